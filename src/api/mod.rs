@@ -2,7 +2,7 @@ use base64::Engine;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use serde::{Deserialize, Serialize};
-use crate::{BlockDBHandle, BlockDBError, AuthManager, AuthContext, Permission};
+use crate::{BlockDBHandle, BlockDBError, AuthManager, AuthContext, Permission, WriteBatch, WriteReceipt};
 
 // pub mod http;
 // pub mod websocket;
@@ -14,7 +14,6 @@ pub struct ApiConfig {
     pub max_connections: usize,
     pub request_timeout: u64,
     pub enable_cors: bool,
-    pub enable_compression: bool,
     pub auth_enabled: bool,
     pub require_auth_for_reads: bool,
     pub session_duration_hours: u64,
@@ -28,7 +27,6 @@ impl Default for ApiConfig {
             max_connections: 1000,
             request_timeout: 30,
             enable_cors: true,
-            enable_compression: true,
             auth_enabled: true,
             require_auth_for_reads: false,
             session_duration_hours: 24,
@@ -57,6 +55,26 @@ pub struct WriteResponse {
     pub message: String,
     pub timestamp: u64,
     pub sequence_number: Option<u64>,
+    /// Hex SHA-256 of the stored record
+    pub record_hash: Option<String>,
+    /// Blockchain block the record is sealed into
+    pub block_index: Option<u64>,
+}
+
+impl WriteResponse {
+    fn stored(receipt: WriteReceipt) -> Self {
+        WriteResponse {
+            success: true,
+            message: "Data written successfully".to_string(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            sequence_number: Some(receipt.sequence_number),
+            record_hash: Some(receipt.hash_hex()),
+            block_index: Some(receipt.block_index),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -94,6 +112,7 @@ pub struct StatsResponse {
     pub total_reads: u64,
     pub cache_hits: u64,
     pub cache_misses: u64,
+    pub write_stall: crate::storage::WriteStallStats,
     pub blockchain_blocks: u64,
     pub storage_size: u64,
 }
@@ -147,8 +166,6 @@ pub struct BlockDBServer {
 pub struct Stats {
     pub total_writes: u64,
     pub total_reads: u64,
-    pub cache_hits: u64,
-    pub cache_misses: u64,
     pub start_time: Option<std::time::SystemTime>,
 }
 
@@ -298,6 +315,20 @@ impl BlockDBServer {
     }
 
     pub async fn write(&self, request: WriteRequest) -> Result<WriteResponse, BlockDBError> {
+        let (key, value) = self.decode_write(request).await?;
+
+        let receipt = self.db.put(&key, &value).await?;
+        
+        {
+            let mut stats = self.stats.write().await;
+            stats.total_writes += 1;
+        }
+
+        Ok(WriteResponse::stored(receipt))
+    }
+
+    /// Authenticate a write request and decode its key and value
+    async fn decode_write(&self, request: WriteRequest) -> Result<(Vec<u8>, Vec<u8>), BlockDBError> {
         // Authenticate if auth is enabled
         if self.config.auth_enabled {
             self.authenticate_request(request.auth_token, Permission::Write).await?;
@@ -314,22 +345,7 @@ impl BlockDBServer {
             request.value.into_bytes()
         };
 
-        self.db.put(&key, &value).await?;
-        
-        {
-            let mut stats = self.stats.write().await;
-            stats.total_writes += 1;
-        }
-
-        Ok(WriteResponse {
-            success: true,
-            message: "Data written successfully".to_string(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            sequence_number: None,
-        })
+        Ok((key, value))
     }
 
     pub async fn read(&self, request: ReadRequest) -> Result<ReadResponse, BlockDBError> {
@@ -348,11 +364,6 @@ impl BlockDBServer {
         {
             let mut stats = self.stats.write().await;
             stats.total_reads += 1;
-            if result.is_some() {
-                stats.cache_hits += 1;
-            } else {
-                stats.cache_misses += 1;
-            }
         }
 
         let data = if let Some(value) = result {
@@ -376,34 +387,49 @@ impl BlockDBServer {
         })
     }
 
+    /// Apply all operations atomically: either every write succeeds or,
+    /// if any operation is invalid or its key exists, none is stored
     pub async fn batch_write(&self, request: BatchWriteRequest) -> Result<BatchWriteResponse, BlockDBError> {
-        let mut results = Vec::new();
-        let mut processed = 0;
-
-        for op in request.operations {
-            match self.write(op).await {
-                Ok(response) => {
-                    results.push(response);
-                    processed += 1;
-                }
-                Err(e) => {
-                    results.push(WriteResponse {
+        let count = request.operations.len();
+
+        let result = async {
+            let mut batch = WriteBatch::new();
+            for op in request.operations {
+                let (key, value) = self.decode_write(op).await?;
+                batch.put(&key, &value);
+            }
+            self.db.write_batch(batch).await
+        }.await;
+
+        let results: Vec<WriteResponse> = match result {
+            Ok(receipts) => {
+                let mut stats = self.stats.write().await;
+                stats.total_writes += count as u64;
+                receipts.into_iter().map(WriteResponse::stored).collect()
+            }
+            Err(e) => {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                (0..count)
+                    .map(|_| WriteResponse {
                         success: false,
-                        message: e.to_string(),
-                        timestamp: std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
+                        message: format!("Batch rejected: {}", e),
+                        timestamp,
                         sequence_number: None,
-                    });
-                }
+                        record_hash: None,
+                        block_index: None,
+                    })
+                    .collect()
             }
-        }
+        };
+        let success = results.iter().all(|response| response.success);
 
         Ok(BatchWriteResponse {
-            success: processed > 0,
+            success,
             results,
-            total_processed: processed,
+            total_processed: if success { count } else { 0 },
         })
     }
 
@@ -431,12 +457,14 @@ impl BlockDBServer {
 
     pub async fn stats(&self) -> Result<StatsResponse, BlockDBError> {
         let stats = self.stats.read().await;
+        let cache = self.db.cache_stats().await;
         
         Ok(StatsResponse {
             total_writes: stats.total_writes,
             total_reads: stats.total_reads,
-            cache_hits: stats.cache_hits,
-            cache_misses: stats.cache_misses,
+            cache_hits: cache.hits,
+            cache_misses: cache.misses,
+            write_stall: self.db.write_stall_stats().await,
             blockchain_blocks: 0, // Would need to implement
             storage_size: 0, // Would need to implement
         })
@@ -2,6 +2,7 @@ use crate::auth::{AuthConfig, AuthContext, AuthManager, Permission, PermissionSe
 use crate::distributed::{DistributedBlockDB, DistributedBlockDBConfig};
 use crate::transaction::Operation;
 use crate::error::BlockDBError;
+use crate::storage::WriteReceipt;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -247,7 +248,7 @@ impl AuthenticatedDistributedBlockDB {
         key: &[u8],
         value: &[u8],
         auth_context: &AuthContext,
-    ) -> Result<(), BlockDBError> {
+    ) -> Result<WriteReceipt, BlockDBError> {
         if let Some(auth_manager) = &self.auth_manager {
             // Check write permission
             auth_manager.check_permission(&auth_context.user_id, &Permission::Write)
@@ -428,7 +429,7 @@ impl AuthenticatedDistributedBlockDB {
     }
 
     // Delegate non-authenticated methods to inner
-    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), BlockDBError> {
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<WriteReceipt, BlockDBError> {
         self.inner.put(key, value).await
     }
 
@@ -449,7 +450,7 @@ pub struct AuthenticatedTransactionContext<'a> {
 }
 
 impl<'a> AuthenticatedTransactionContext<'a> {
-    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), BlockDBError> {
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<WriteReceipt, BlockDBError> {
         // Check write permission
         self.auth_manager.check_permission(&self.auth_context.user_id, &Permission::Write)
             .map_err(BlockDBError::AuthError)?;
@@ -1,4 +1,4 @@
-use blockdb::{BlockDBConfig, BlockDBHandle};
+use blockdb::{BlockDBConfig, BlockDBHandle, WriteReceipt};
 use blockdb::storage::collection::{CollectionManager, CollectionSchema, CollectionSettings, IndexDefinition};
 use clap::{Parser, Subcommand};
 use std::io::{self, Write};
@@ -117,8 +117,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 value.into_bytes()
             };
 
-            db.put(&key_bytes, &value_bytes).await?;
+            let receipt = db.put(&key_bytes, &value_bytes).await?;
             println!("Successfully stored key-value pair");
+            print_receipt(&receipt);
         }
         Commands::Get { key, base64 } => {
             let key_bytes = if base64 {
@@ -203,7 +204,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             let value = parts[2..].join(" ").as_bytes().to_vec();
                             
                             match db.put(key, &value).await {
-                                Ok(_) => println!("OK"),
+                                Ok(receipt) => println!("OK (sequence {}, block {})", receipt.sequence_number, receipt.block_index),
                                 Err(e) => println!("Error: {}", e),
                             }
                         } else {
@@ -279,6 +280,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn print_receipt(receipt: &WriteReceipt) {
+    println!("  Sequence:  {}", receipt.sequence_number);
+    println!("  Timestamp: {}", receipt.timestamp);
+    println!("  Hash:      {}", receipt.hash_hex());
+    println!("  Block:     {}", receipt.block_index);
+}
+
 async fn handle_collection_action(action: CollectionAction, collection_manager: &CollectionManager) -> Result<(), Box<dyn std::error::Error>> {
     match action {
         CollectionAction::Create { name, description } => {
@@ -337,8 +345,9 @@ async fn handle_collection_action(action: CollectionAction, collection_manager:
                 value.into_bytes()
             };
 
-            collection_manager.put(&collection_id, &key_bytes, &value_bytes)?;
+            let receipt = collection_manager.put(&collection_id, &key_bytes, &value_bytes)?;
             println!("✅ Successfully stored document in collection");
+            print_receipt(&receipt);
         }
         CollectionAction::Get { collection_id, key, base64 } => {
             let key_bytes = if base64 {
@@ -474,7 +483,7 @@ async fn handle_interactive_collection_command(parts: &[&str], collection_manage
                 let value = parts[3..].join(" ").as_bytes().to_vec();
                 
                 match collection_manager.put(collection_id, key, &value) {
-                    Ok(_) => println!("OK"),
+                    Ok(receipt) => println!("OK (sequence {}, block {})", receipt.sequence_number, receipt.block_index),
                     Err(e) => println!("Error: {}", e),
                 }
             } else {
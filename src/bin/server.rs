@@ -1,4 +1,5 @@
 use blockdb::{BlockDBConfig, BlockDBHandle, BlockDBServer, ApiConfig};
+use blockdb::storage::{Compression, MasterKeySource};
 use clap::Parser;
 use std::path::Path;
 use tokio;
@@ -36,8 +37,17 @@ struct Args {
     #[arg(long)]
     disable_cors: bool,
 
+    /// Store SSTable blocks and WAL frames uncompressed instead of with LZ4
     #[arg(long)]
     disable_compression: bool,
+
+    /// File holding the master key for encryption at rest
+    #[arg(long)]
+    key_file: Option<String>,
+
+    /// Environment variable holding the master key
+    #[arg(long, conflicts_with = "key_file")]
+    key_env: Option<String>,
 }
 
 #[tokio::main]
@@ -49,9 +59,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let db_config = BlockDBConfig {
         data_dir: args.data_dir,
         memtable_size_limit: args.memtable_size,
-        wal_sync_interval: args.wal_sync_interval,
+        wal_sync_interval_ms: args.wal_sync_interval,
         compaction_threshold: args.compaction_threshold,
         blockchain_batch_size: args.blockchain_batch_size,
+        compression: if args.disable_compression { Compression::None } else { Compression::Lz4 },
+        master_key: args.key_file.map(MasterKeySource::KeyFile).or(args.key_env.map(MasterKeySource::Env)),
+        ..Default::default()
     };
 
     let api_config = ApiConfig {
@@ -60,12 +73,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         max_connections: args.max_connections,
         request_timeout: args.request_timeout,
         enable_cors: !args.disable_cors,
-        enable_compression: !args.disable_compression,
     };
 
     println!("Starting BlockDB server with config:");
     println!("  Data directory: {}", db_config.data_dir);
     println!("  Memtable size: {} MB", db_config.memtable_size_limit / 1024 / 1024);
+    println!("  Compression: {:?}", db_config.compression);
+    println!("  Encryption at rest: {}", if db_config.master_key.is_some() { "enabled" } else { "disabled" });
     println!("  Server address: {}:{}", api_config.host, api_config.port);
     println!("  Max connections: {}", api_config.max_connections);
 
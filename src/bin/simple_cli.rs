@@ -1,5 +1,6 @@
-use blockdb::{BlockDBConfig, BlockDBHandle, AuthManager, Permission};
+use blockdb::{BlockDB, BlockDBConfig, BlockDBHandle, AuthManager, Permission, WriteReceipt};
 use blockdb::storage::collection::{CollectionManager, IndexDefinition};
+use blockdb::storage::MasterKeySource;
 use clap::{Parser, Subcommand};
 use std::io::{self, Write};
 use base64::Engine;
@@ -10,6 +11,14 @@ struct Args {
     #[arg(short, long, default_value = "./blockdb_data")]
     data_dir: String,
 
+    /// File holding the master key (64 hex digits) for encrypted data
+    #[arg(long, global = true)]
+    key_file: Option<String>,
+
+    /// Environment variable holding the master key
+    #[arg(long, global = true, conflicts_with = "key_file")]
+    key_env: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -42,6 +51,15 @@ enum Commands {
         action: AuthAction,
     },
     Interactive,
+    /// Re-encrypt all data under a new master key and data keys. Run while
+    /// no other process has the database open; the current master key, if
+    /// any, is given with --key-file or --key-env.
+    RotateKey {
+        #[arg(long, required_unless_present = "new_key_env")]
+        new_key_file: Option<String>,
+        #[arg(long, conflicts_with = "new_key_file")]
+        new_key_env: Option<String>,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -133,9 +151,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let config = BlockDBConfig {
         data_dir: args.data_dir,
+        master_key: master_key_source(args.key_file, args.key_env),
         ..Default::default()
     };
 
+    // Rotation rewrites files in place, so it must run before anything
+    // opens the database
+    if let Commands::RotateKey { new_key_file, new_key_env } = args.command {
+        let new_master_key = master_key_source(new_key_file, new_key_env)
+            .ok_or("--new-key-file or --new-key-env is required")?;
+        BlockDB::rotate_encryption_key(&config, &new_master_key)?;
+        CollectionManager::rotate_encryption_key(&config, &new_master_key)?;
+        println!("✅ Master key rotated");
+        return Ok(());
+    }
+
     let db = BlockDBHandle::new(config.clone())?;
     let collection_manager = CollectionManager::new(config.clone())?;
     let mut auth_manager = AuthManager::new(config.clone())?;
@@ -154,8 +184,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 value.into_bytes()
             };
 
-            db.put(&key_bytes, &value_bytes).await?;
+            let receipt = db.put(&key_bytes, &value_bytes).await?;
             println!("Successfully stored key-value pair");
+            print_receipt(&receipt);
         }
         Commands::Get { key, base64 } => {
             let key_bytes = if base64 {
@@ -243,7 +274,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             let value = parts[2..].join(" ").as_bytes().to_vec();
                             
                             match db.put(key, &value).await {
-                                Ok(_) => println!("OK"),
+                                Ok(receipt) => println!("OK (sequence {}, block {})", receipt.sequence_number, receipt.block_index),
                                 Err(e) => println!("Error: {}", e),
                             }
                         } else {
@@ -321,11 +352,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
+        Commands::RotateKey { .. } => unreachable!("handled before the database is opened"),
     }
 
     Ok(())
 }
 
+fn master_key_source(key_file: Option<String>, key_env: Option<String>) -> Option<MasterKeySource> {
+    key_file
+        .map(MasterKeySource::KeyFile)
+        .or(key_env.map(MasterKeySource::Env))
+}
+
+fn print_receipt(receipt: &WriteReceipt) {
+    println!("  Sequence:  {}", receipt.sequence_number);
+    println!("  Timestamp: {}", receipt.timestamp);
+    println!("  Hash:      {}", receipt.hash_hex());
+    println!("  Block:     {}", receipt.block_index);
+}
+
 async fn handle_collection_action(action: CollectionAction, collection_manager: &CollectionManager) -> Result<(), Box<dyn std::error::Error>> {
     match action {
         CollectionAction::Create { name, description } => {
@@ -384,8 +429,9 @@ async fn handle_collection_action(action: CollectionAction, collection_manager:
                 value.into_bytes()
             };
 
-            collection_manager.put(&collection_id, &key_bytes, &value_bytes)?;
+            let receipt = collection_manager.put(&collection_id, &key_bytes, &value_bytes)?;
             println!("✅ Successfully stored document in collection");
+            print_receipt(&receipt);
         }
         CollectionAction::Get { collection_id, key, base64 } => {
             let key_bytes = if base64 {
@@ -521,7 +567,7 @@ async fn handle_interactive_collection_command(parts: &[&str], collection_manage
                 let value = parts[3..].join(" ").as_bytes().to_vec();
                 
                 match collection_manager.put(collection_id, key, &value) {
-                    Ok(_) => println!("OK"),
+                    Ok(receipt) => println!("OK (sequence {}, block {})", receipt.sequence_number, receipt.block_index),
                     Err(e) => println!("Error: {}", e),
                 }
             } else {
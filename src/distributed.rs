@@ -8,7 +8,7 @@ use uuid::Uuid;
 use crate::consensus::{NodeId, NodeAddress, ClusterConfig, ConsensusEngine};
 use crate::consensus::raft::RaftNode;
 use crate::error::BlockDBError;
-use crate::storage::{BlockDB, BlockDBConfig};
+use crate::storage::{BlockDB, BlockDBConfig, WriteReceipt};
 use crate::transaction::{TransactionManager, TransactionId, Operation, TwoPhaseCommitCoordinator};
 
 pub struct DistributedBlockDBConfig {
@@ -105,7 +105,7 @@ impl DistributedBlockDB {
     // ACID-compliant operations
     
     /// Single put operation (goes through consensus)
-    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), BlockDBError> {
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<WriteReceipt, BlockDBError> {
         let operation = Operation::Put {
             key: key.to_vec(),
             value: value.to_vec(),
@@ -114,8 +114,12 @@ impl DistributedBlockDB {
         // Use consensus to ensure all nodes agree
         self.consensus.propose(operation).await?;
         
-        // If we're the leader, the operation will be applied to storage through log application
-        // If we're a follower, we wait for the leader to replicate the change
+        // Apply the agreed write to local storage, which assigns the
+        // sequence number and block the receipt reports
+        let receipt = {
+            let storage = self.storage.read();
+            storage.put(key, value)?
+        };
         
         // Update read cache for better performance
         self.read_cache.insert(
@@ -123,7 +127,7 @@ impl DistributedBlockDB {
             (value.to_vec(), self.current_timestamp()),
         );
         
-        Ok(())
+        Ok(receipt)
     }
     
     /// Single get operation (can be served locally for better performance)
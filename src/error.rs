@@ -9,9 +9,11 @@ pub enum BlockDBError {
     StorageError(String),
     ApiError(String),
     DuplicateKey(String),
+    KeyNotFound(String),
     AuthError(crate::auth::AuthError),
     ConsensusError(String),
     TransactionError(String),
+    Corruption(String),
 }
 
 impl fmt::Display for BlockDBError {
@@ -24,9 +26,11 @@ impl fmt::Display for BlockDBError {
             BlockDBError::StorageError(msg) => write!(f, "Storage Error: {}", msg),
             BlockDBError::ApiError(msg) => write!(f, "API Error: {}", msg),
             BlockDBError::DuplicateKey(msg) => write!(f, "Duplicate Key Error: {}", msg),
+            BlockDBError::KeyNotFound(msg) => write!(f, "Key Not Found: {}", msg),
             BlockDBError::AuthError(e) => write!(f, "Authentication Error: {}", e),
             BlockDBError::ConsensusError(msg) => write!(f, "Consensus Error: {}", msg),
             BlockDBError::TransactionError(msg) => write!(f, "Transaction Error: {}", msg),
+            BlockDBError::Corruption(msg) => write!(f, "Data Corruption: {}", msg),
         }
     }
 }
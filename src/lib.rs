@@ -6,7 +6,7 @@ pub mod transaction;
 pub mod distributed;
 pub mod auth;
 
-pub use storage::{BlockDB, BlockDBConfig, Record};
+pub use storage::{BlockDB, BlockDBConfig, Record, WriteBatch, WriteReceipt};
 pub use api::{BlockDBServer, ApiConfig};
 pub use error::BlockDBError;
 pub use distributed::{DistributedBlockDB, DistributedBlockDBConfig};
@@ -30,10 +30,27 @@ impl BlockDBHandle {
         })
     }
 
-    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), BlockDBError> {
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<WriteReceipt, BlockDBError> {
         let db = self.db.read().await;
-        db.put(key, value)?;
-        Ok(())
+        db.put(key, value).map_err(BlockDBError::from)
+    }
+
+    /// Write every entry of `batch`, or none of them if any is rejected
+    pub async fn write_batch(&self, batch: WriteBatch) -> Result<Vec<WriteReceipt>, BlockDBError> {
+        let db = self.db.read().await;
+        db.write_batch(batch).map_err(BlockDBError::from)
+    }
+
+    /// Hide `key` from reads behind a tombstone
+    pub async fn delete(&self, key: &[u8]) -> Result<WriteReceipt, BlockDBError> {
+        let db = self.db.read().await;
+        db.delete(key).map_err(BlockDBError::from)
+    }
+
+    /// Delete `key` and destroy its encryption key; needs `crypto_shredding`
+    pub async fn erase(&self, key: &[u8]) -> Result<(), BlockDBError> {
+        let db = self.db.read().await;
+        db.erase(key).map_err(BlockDBError::from)
     }
 
     pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BlockDBError> {
@@ -41,11 +58,48 @@ impl BlockDBHandle {
         db.get(key).map_err(BlockDBError::from)
     }
 
+    pub async fn get_version(&self, key: &[u8], sequence: u64) -> Result<Option<Record>, BlockDBError> {
+        let db = self.db.read().await;
+        db.get_version(key, sequence).map_err(BlockDBError::from)
+    }
+
+    /// Every stored version of `key`, oldest first
+    pub async fn history(&self, key: &[u8]) -> Result<Vec<Record>, BlockDBError> {
+        let db = self.db.read().await;
+        db.history(key).map_err(BlockDBError::from)
+    }
+
+    /// Hits and misses of the SSTable block cache
+    pub async fn cache_stats(&self) -> storage::CacheStats {
+        let db = self.db.read().await;
+        db.cache_stats()
+    }
+
+    /// Flush and compaction backlog, and whether writes are being slowed
+    pub async fn write_stall_stats(&self) -> storage::WriteStallStats {
+        let db = self.db.read().await;
+        db.write_stall_stats()
+    }
+
     pub async fn verify_integrity(&self) -> Result<bool, BlockDBError> {
         let db = self.db.read().await;
         db.verify_integrity().map_err(BlockDBError::from)
     }
 
+    /// Proof that the newest record of `key` is in the chain, checkable
+    /// with [`storage::verify_proof`]
+    pub async fn prove(&self, key: &[u8]) -> Result<Option<storage::InclusionProof>, BlockDBError> {
+        let db = self.db.read().await;
+        db.prove(key).map_err(BlockDBError::from)
+    }
+
+    /// Recompute every record hash and check the stored data against the
+    /// chain, reporting the first tampered block, record and file
+    pub async fn verify_deep(&self) -> Result<storage::VerifyReport, BlockDBError> {
+        let db = self.db.read().await;
+        db.verify_deep().map_err(BlockDBError::from)
+    }
+
     pub async fn force_flush(&self) -> Result<(), BlockDBError> {
         let db = self.db.write().await;
         db.force_flush_memtable().map_err(BlockDBError::from)
@@ -0,0 +1,619 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use sha2::{Sha256, Digest};
+use serde::{Serialize, Deserialize};
+use crate::storage::{Record, RecordKind};
+use crate::storage::compression::Compression;
+use crate::storage::encryption::{self, Keyring, PLAINTEXT_KEY_ID};
+use crate::storage::sstable::read_at;
+use crate::storage::wal::WalDurability;
+
+/// "BDBB" followed by the format version and the id of the data key
+/// entries are sealed with
+const BLOB_MAGIC: [u8; 4] = *b"BDBB";
+const BLOB_VERSION: u32 = 1;
+const BLOB_HEADER_SIZE: u64 = 12;
+/// Entry header: payload length followed by its CRC32C
+const ENTRY_HEADER_SIZE: u64 = 8;
+const BLOB_FILE_PREFIX: &str = "blob_";
+const BLOB_FILE_SUFFIX: &str = ".blob";
+/// Suffix of a file being resealed by a key rotation
+const ROTATING_SUFFIX: &str = ".rotating";
+
+/// Where a separated value is stored. It replaces the value of its record,
+/// whose kind becomes `RecordKind::Blob`; the record keeps the hash it had
+/// when it held the [`ValueDigest`] of the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobPointer {
+    pub file_number: u64,
+    /// Offset of the entry header in the file
+    pub offset: u64,
+    /// Length of the stored payload
+    pub size: u32,
+    /// Kind of the value the pointer replaced
+    pub kind: RecordKind,
+}
+
+impl BlobPointer {
+    /// Pointer held by `record`, if its value was separated
+    pub fn of(record: &Record) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if record.kind != RecordKind::Blob {
+            return Ok(None);
+        }
+        Ok(Some(bincode::deserialize(&record.value)?))
+    }
+
+    /// Make `record`, already hashed over its digest, point here instead
+    pub fn store_in(self, record: &mut Record) {
+        // Only fixed-size fields, which always serialize
+        record.value = bincode::serialize(&self).unwrap();
+        record.kind = RecordKind::Blob;
+    }
+
+    /// Bytes the entry takes up in its file
+    pub fn entry_size(&self) -> u64 {
+        ENTRY_HEADER_SIZE + self.size as u64
+    }
+}
+
+/// What the blockchain seals in place of a separated value. It replaces the
+/// value of its record, whose kind becomes `RecordKind::BlobDigest`, and
+/// the record hash covers it instead of the value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueDigest {
+    /// SHA-256 of the value
+    pub digest: Vec<u8>,
+    /// Kind of the value the digest replaced
+    pub kind: RecordKind,
+}
+
+impl ValueDigest {
+    pub fn of(value: &[u8], kind: RecordKind) -> Self {
+        ValueDigest { digest: Sha256::digest(value).to_vec(), kind }
+    }
+
+    /// The value of a `RecordKind::BlobDigest` record
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Only a byte vector and an enum, which always serialize
+        bincode::serialize(self).unwrap()
+    }
+}
+
+/// Append-only files holding values too large to rewrite on every flush
+/// and compaction. Each value is written once, as `len | crc | payload`
+/// with the payload compressed and, when encryption is enabled, sealed and
+/// bound to its file number and offset. The LSM tree and WAL only hold a
+/// [`BlobPointer`], and the blockchain a [`ValueDigest`], so collecting a
+/// file never affects the chain.
+///
+/// Compaction reports the entries it drops as garbage. Once enough of a
+/// file is garbage, compaction moves its live entries to the newest file,
+/// and a file that is all garbage is deleted once no reader that started
+/// before then holds a [`BlobPin`].
+#[derive(Debug)]
+pub struct BlobStore {
+    data_dir: String,
+    /// Codec for new entries
+    compression: Compression,
+    keyring: Option<Arc<Keyring>>,
+    /// A new file is started once the current one would grow past this
+    max_file_size: u64,
+    /// Fraction of a file that must be garbage before compaction moves its
+    /// live entries out
+    gc_ratio: f64,
+    state: Mutex<BlobState>,
+}
+
+#[derive(Debug, Default)]
+struct BlobState {
+    /// File being appended to; opened on the first append
+    writer: Option<BlobWriter>,
+    /// Highest file number in use
+    last_file: u64,
+    /// Bytes of each file no longer referenced by any record
+    garbage: BTreeMap<u64, u64>,
+    /// Files opened for reading, kept until they are deleted
+    readers: HashMap<u64, Arc<BlobReader>>,
+    /// Bumped whenever files die; pins and dead files are stamped with it
+    generation: u64,
+    /// Live pins by the generation they were taken at
+    pins: BTreeMap<u64, usize>,
+    /// Files holding nothing but garbage, by the generation they died in,
+    /// waiting for the readers that may still resolve into them
+    dead: BTreeMap<u64, u64>,
+}
+
+/// Keeps every blob file a reader could still resolve a pointer into.
+/// Taken before a read looks up any record, so files that die while it is
+/// held are only deleted once it is dropped.
+#[derive(Debug)]
+pub struct BlobPin {
+    blobs: Arc<BlobStore>,
+    generation: u64,
+}
+
+impl Deref for BlobPin {
+    type Target = BlobStore;
+
+    fn deref(&self) -> &BlobStore {
+        &self.blobs
+    }
+}
+
+impl Drop for BlobPin {
+    fn drop(&mut self) {
+        let mut state = self.blobs.state.lock().unwrap();
+        if let Some(count) = state.pins.get_mut(&self.generation) {
+            *count -= 1;
+            if *count == 0 {
+                state.pins.remove(&self.generation);
+            }
+        }
+    }
+}
+
+/// An open blob file and the data key its entries are sealed with
+#[derive(Debug)]
+struct BlobReader {
+    file: File,
+    key_id: u32,
+}
+
+#[derive(Debug)]
+struct BlobWriter {
+    file: BufWriter<File>,
+    file_number: u64,
+    offset: u64,
+    /// Data key new entries are sealed with, or `PLAINTEXT_KEY_ID`
+    key_id: u32,
+}
+
+/// Contents of a blob file as far as it is valid
+struct FileScan {
+    key_id: u32,
+    valid_len: u64,
+    file_len: u64,
+}
+
+impl BlobStore {
+    /// Open the blob files in `data_dir`, with `garbage` as recorded in the
+    /// manifest. Appends continue in the newest file, after any torn entry
+    /// left by a crash is truncated.
+    pub fn open(
+        data_dir: &str,
+        compression: Compression,
+        keyring: Option<Arc<Keyring>>,
+        max_file_size: u64,
+        gc_ratio: f64,
+        mut garbage: BTreeMap<u64, u64>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let files = Self::list_files(data_dir)?;
+        garbage.retain(|file_number, _| files.contains(file_number));
+
+        let key_id = keyring.as_ref().map_or(PLAINTEXT_KEY_ID, |keyring| keyring.current_id());
+        let mut state = BlobState {
+            writer: None,
+            last_file: files.last().copied().unwrap_or(0),
+            garbage,
+            readers: HashMap::new(),
+            generation: 0,
+            pins: BTreeMap::new(),
+            dead: BTreeMap::new(),
+        };
+
+        if let Some(&file_number) = files.last() {
+            let path = Self::file_path(data_dir, file_number);
+            match Self::scan(&path, |_, _| Ok(()))? {
+                // Only the header was torn, so nothing in it is referenced
+                None => fs::remove_file(&path)?,
+                Some(scan) => {
+                    if scan.valid_len < scan.file_len {
+                        println!("⚠️ Discarded {} bytes of torn blob tail in {}", scan.file_len - scan.valid_len, path);
+                        OpenOptions::new().write(true).open(&path)?.set_len(scan.valid_len)?;
+                    }
+                    // A file sealed under an older key is left as it is
+                    if scan.key_id == key_id {
+                        state.writer = Some(BlobWriter {
+                            file: BufWriter::new(OpenOptions::new().append(true).open(&path)?),
+                            file_number,
+                            offset: scan.valid_len,
+                            key_id,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Left behind by an interrupted key rotation, which rewrites them
+        // when run again
+        for entry in fs::read_dir(data_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.starts_with(BLOB_FILE_PREFIX) && file_name.ends_with(ROTATING_SUFFIX) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(BlobStore {
+            data_dir: data_dir.to_string(),
+            compression,
+            keyring,
+            max_file_size,
+            gc_ratio,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn file_path(data_dir: &str, file_number: u64) -> String {
+        format!("{}/{}{:06}{}", data_dir, BLOB_FILE_PREFIX, file_number, BLOB_FILE_SUFFIX)
+    }
+
+    /// Numbers of the blob files in `data_dir`, ascending
+    fn list_files(data_dir: &str) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(data_dir)? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(number) = file_name
+                .strip_prefix(BLOB_FILE_PREFIX)
+                .and_then(|rest| rest.strip_suffix(BLOB_FILE_SUFFIX))
+                .and_then(|number| number.parse::<u64>().ok())
+            {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+
+    /// Entries are bound to where they are stored, so one cannot be swapped
+    /// for another
+    fn aad(file_number: u64, offset: u64) -> [u8; 16] {
+        let mut aad = [0u8; 16];
+        aad[..8].copy_from_slice(&file_number.to_be_bytes());
+        aad[8..].copy_from_slice(&offset.to_be_bytes());
+        aad
+    }
+
+    /// Data key id from a file header
+    fn parse_header(header: &[u8; BLOB_HEADER_SIZE as usize], path: &str) -> Result<u32, Box<dyn std::error::Error>> {
+        if header[..4] != BLOB_MAGIC {
+            return Err(Box::new(crate::error::BlockDBError::Corruption(format!("Invalid blob file header in {}", path))));
+        }
+        let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        if version != BLOB_VERSION {
+            return Err(Box::new(crate::error::BlockDBError::InvalidData(
+                format!("Unsupported blob file format version {} in {}", version, path)
+            )));
+        }
+        Ok(u32::from_be_bytes(header[8..12].try_into().unwrap()))
+    }
+
+    /// Read the header and every valid entry of a blob file, passing each
+    /// entry's offset and payload to `visit`. `None` if the header itself
+    /// is incomplete.
+    fn scan(path: &str, mut visit: impl FnMut(u64, Vec<u8>) -> Result<(), Box<dyn std::error::Error>>) -> Result<Option<FileScan>, Box<dyn std::error::Error>> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0u8; BLOB_HEADER_SIZE as usize];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        }
+        let key_id = Self::parse_header(&header, path)?;
+
+        // Stop at the first entry that is incomplete or fails its checksum
+        let mut valid_len = BLOB_HEADER_SIZE;
+        loop {
+            let mut entry_header = [0u8; ENTRY_HEADER_SIZE as usize];
+            if reader.read_exact(&mut entry_header).is_err() {
+                break;
+            }
+            let size = u32::from_be_bytes(entry_header[..4].try_into().unwrap()) as u64;
+            let crc = u32::from_be_bytes(entry_header[4..].try_into().unwrap());
+            if valid_len + ENTRY_HEADER_SIZE + size > file_len {
+                break;
+            }
+            let mut payload = vec![0u8; size as usize];
+            if reader.read_exact(&mut payload).is_err() || crc32c::crc32c(&payload) != crc {
+                break;
+            }
+            visit(valid_len, payload)?;
+            valid_len += ENTRY_HEADER_SIZE + size;
+        }
+
+        Ok(Some(FileScan { key_id, valid_len, file_len }))
+    }
+
+    fn create_file(&self, file_number: u64, key_id: u32) -> Result<BlobWriter, Box<dyn std::error::Error>> {
+        let mut file = BufWriter::new(
+            OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(Self::file_path(&self.data_dir, file_number))?
+        );
+        file.write_all(&BLOB_MAGIC)?;
+        file.write_all(&BLOB_VERSION.to_be_bytes())?;
+        file.write_all(&key_id.to_be_bytes())?;
+
+        Ok(BlobWriter { file, file_number, offset: BLOB_HEADER_SIZE, key_id })
+    }
+
+    /// Append `value`, which replaces a value of `kind`, and return where it
+    /// went. The entry is buffered until [`Self::commit`].
+    pub fn append(&self, value: &[u8], kind: RecordKind) -> Result<BlobPointer, Box<dyn std::error::Error>> {
+        let payload = self.compression.encode(value)?;
+        let key_id = self.keyring.as_ref().map_or(PLAINTEXT_KEY_ID, |keyring| keyring.current_id());
+
+        let mut state = self.state.lock().unwrap();
+        let full = state.writer.as_ref().is_some_and(|writer| {
+            writer.offset > BLOB_HEADER_SIZE && writer.offset + ENTRY_HEADER_SIZE + payload.len() as u64 > self.max_file_size
+        });
+        if full || state.writer.is_none() {
+            // Entries of the finished file must be durable once it is no
+            // longer the one commits sync
+            if let Some(mut writer) = state.writer.take() {
+                writer.file.flush()?;
+                writer.file.get_mut().sync_data()?;
+            }
+            state.last_file += 1;
+            state.writer = Some(self.create_file(state.last_file, key_id)?);
+        }
+        let writer = state.writer.as_mut().unwrap();
+
+        let payload = match self.keyring.as_ref().filter(|_| writer.key_id != PLAINTEXT_KEY_ID) {
+            Some(keyring) => keyring.seal(&payload, &Self::aad(writer.file_number, writer.offset))?,
+            None => payload,
+        };
+        writer.file.write_all(&(payload.len() as u32).to_be_bytes())?;
+        writer.file.write_all(&crc32c::crc32c(&payload).to_be_bytes())?;
+        writer.file.write_all(&payload)?;
+
+        let pointer = BlobPointer {
+            file_number: writer.file_number,
+            offset: writer.offset,
+            size: payload.len() as u32,
+            kind,
+        };
+        writer.offset += pointer.entry_size();
+        Ok(pointer)
+    }
+
+    /// Make appended entries readable and as durable as `durability`
+    /// requires. Entries are always handed to the OS, since reads open the
+    /// file separately.
+    pub fn commit(&self, durability: WalDurability) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(writer) = self.state.lock().unwrap().writer.as_mut() {
+            writer.file.flush()?;
+            if durability == WalDurability::Fsync {
+                writer.file.get_mut().sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fsync the file being appended to
+    pub fn sync(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.commit(WalDurability::Fsync)
+    }
+
+    /// Open file `file_number` for reading, or reuse the handle opened
+    /// before
+    fn reader(&self, file_number: u64) -> Result<Arc<BlobReader>, Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(reader) = state.readers.get(&file_number) {
+            return Ok(reader.clone());
+        }
+
+        let path = Self::file_path(&self.data_dir, file_number);
+        let file = File::open(&path)?;
+        let mut header = [0u8; BLOB_HEADER_SIZE as usize];
+        read_at(&file, 0, &mut header)?;
+        let key_id = Self::parse_header(&header, &path)?;
+
+        let reader = Arc::new(BlobReader { file, key_id });
+        state.readers.insert(file_number, reader.clone());
+        Ok(reader)
+    }
+
+    /// Read the value `pointer` refers to
+    pub fn read(&self, pointer: &BlobPointer) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let path = Self::file_path(&self.data_dir, pointer.file_number);
+        let BlobReader { file, key_id } = &*self.reader(pointer.file_number)?;
+
+        let mut entry = vec![0u8; pointer.entry_size() as usize];
+        read_at(file, pointer.offset, &mut entry)?;
+        let (entry_header, payload) = entry.split_at(ENTRY_HEADER_SIZE as usize);
+        let size = u32::from_be_bytes(entry_header[..4].try_into().unwrap());
+        let crc = u32::from_be_bytes(entry_header[4..].try_into().unwrap());
+        if size != pointer.size || crc32c::crc32c(payload) != crc {
+            return Err(Box::new(crate::error::BlockDBError::Corruption(
+                format!("Blob at offset {} of {} failed its checksum", pointer.offset, path)
+            )));
+        }
+
+        let payload = encryption::unseal(self.keyring.as_deref(), *key_id, payload.to_vec(), &Self::aad(pointer.file_number, pointer.offset))?;
+        Compression::decode(&payload)
+    }
+
+    /// Replace a separated value with the value it points to
+    pub fn resolve(&self, record: &mut Record) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(pointer) = BlobPointer::of(record)? {
+            record.value = self.read(&pointer)?;
+            record.kind = pointer.kind;
+        }
+        Ok(())
+    }
+
+    /// `record` as the blockchain seals it, which is what its hash covers: a
+    /// separated value is replaced by its digest. Records separated before
+    /// digests were sealed have the value itself hashed, and keep it.
+    pub fn sealed(&self, record: &Record) -> Result<Record, Box<dyn std::error::Error>> {
+        let Some(pointer) = BlobPointer::of(record)? else {
+            return Ok(record.clone());
+        };
+        let value = self.read(&pointer)?;
+
+        let mut sealed = Record {
+            value: ValueDigest::of(&value, pointer.kind).to_bytes(),
+            kind: RecordKind::BlobDigest,
+            ..record.clone()
+        };
+        if sealed.compute_hash() != record.hash {
+            sealed.value = value;
+            sealed.kind = pointer.kind;
+        }
+        Ok(sealed)
+    }
+
+    /// Keep the files a read starting now may resolve pointers into
+    pub fn pin(self: &Arc<Self>) -> BlobPin {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        *state.pins.entry(generation).or_default() += 1;
+        BlobPin { blobs: self.clone(), generation }
+    }
+
+    /// Files, other than the one being appended to, with enough garbage
+    /// that compaction should move their live entries out, counting the
+    /// `pending` garbage of a merge not yet installed
+    pub fn files_to_collect(&self, pending: &BTreeMap<u64, u64>) -> Result<BTreeSet<u64>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        let active = state.writer.as_ref().map(|writer| writer.file_number);
+
+        let mut garbage = state.garbage.clone();
+        for (&file_number, &bytes) in pending {
+            *garbage.entry(file_number).or_default() += bytes;
+        }
+
+        let mut files = BTreeSet::new();
+        for (file_number, garbage) in garbage {
+            if Some(file_number) == active || state.dead.contains_key(&file_number) {
+                continue;
+            }
+            let len = fs::metadata(Self::file_path(&self.data_dir, file_number))?.len();
+            if garbage as f64 >= self.gc_ratio * len.saturating_sub(BLOB_HEADER_SIZE) as f64 {
+                files.insert(file_number);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Copy the entry a separated record points to into the newest file and
+    /// point the record at the copy. Returns the old pointer, whose entry is
+    /// now garbage.
+    pub fn relocate(&self, record: &mut Record) -> Result<Option<BlobPointer>, Box<dyn std::error::Error>> {
+        let Some(pointer) = BlobPointer::of(record)? else {
+            return Ok(None);
+        };
+        self.append(&self.read(&pointer)?, pointer.kind)?.store_in(record);
+        Ok(Some(pointer))
+    }
+
+    /// Count bytes of entries no longer referenced, by file
+    pub fn add_garbage(&self, garbage: &BTreeMap<u64, u64>) {
+        let mut state = self.state.lock().unwrap();
+        for (&file_number, &bytes) in garbage {
+            *state.garbage.entry(file_number).or_default() += bytes;
+        }
+    }
+
+    /// Unreferenced bytes of each file, as recorded in the manifest
+    pub fn garbage(&self) -> BTreeMap<u64, u64> {
+        self.state.lock().unwrap().garbage.clone()
+    }
+
+    /// Delete every file, other than the one being appended to, that holds
+    /// nothing but garbage and that no pinned reader started before it
+    /// died. Files a reader still pins are deleted by a later call. Returns
+    /// whether any was deleted.
+    pub fn remove_dead_files(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap();
+        let active = state.writer.as_ref().map(|writer| writer.file_number);
+
+        let mut died = Vec::new();
+        for (&file_number, &garbage) in &state.garbage {
+            let path = Self::file_path(&self.data_dir, file_number);
+            if Some(file_number) != active
+                && !state.dead.contains_key(&file_number)
+                && garbage >= fs::metadata(&path)?.len().saturating_sub(BLOB_HEADER_SIZE)
+            {
+                died.push(file_number);
+            }
+        }
+        if !died.is_empty() {
+            // Readers pinned from now on never see a pointer into them
+            let generation = state.generation;
+            state.dead.extend(died.into_iter().map(|file_number| (file_number, generation)));
+            state.generation += 1;
+        }
+
+        let oldest_pin = state.pins.keys().next().copied().unwrap_or(state.generation);
+        let removable: Vec<u64> = state.dead
+            .iter()
+            .filter(|&(_, &generation)| generation < oldest_pin)
+            .map(|(&file_number, _)| file_number)
+            .collect();
+        for file_number in &removable {
+            fs::remove_file(Self::file_path(&self.data_dir, *file_number))?;
+            state.dead.remove(file_number);
+            state.garbage.remove(file_number);
+            state.readers.remove(file_number);
+        }
+        Ok(!removable.is_empty())
+    }
+
+    /// Delete every blob file
+    pub fn clear(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap();
+        state.writer = None;
+        for file_number in Self::list_files(&self.data_dir)? {
+            fs::remove_file(Self::file_path(&self.data_dir, file_number))?;
+        }
+        *state = BlobState::default();
+        Ok(())
+    }
+
+    /// Reseal every blob file in `data_dir` sealed under an older key with
+    /// the current key of `keyring`. Sealing does not change an entry's
+    /// length, so pointers to it stay valid. Plaintext files are left as
+    /// they are.
+    pub fn reseal_files(data_dir: &str, keyring: &Keyring) -> Result<(), Box<dyn std::error::Error>> {
+        let key_id = keyring.current_id();
+        for file_number in Self::list_files(data_dir)? {
+            let path = Self::file_path(data_dir, file_number);
+            let mut header = [0u8; BLOB_HEADER_SIZE as usize];
+            if File::open(&path)?.read_exact(&mut header).is_err() {
+                continue;
+            }
+            let file_key_id = Self::parse_header(&header, &path)?;
+            if file_key_id == PLAINTEXT_KEY_ID || file_key_id == key_id {
+                continue;
+            }
+
+            let tmp_path = format!("{}{}", path, ROTATING_SUFFIX);
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(&BLOB_MAGIC)?;
+            writer.write_all(&BLOB_VERSION.to_be_bytes())?;
+            writer.write_all(&key_id.to_be_bytes())?;
+            Self::scan(&path, |offset, payload| {
+                let aad = Self::aad(file_number, offset);
+                let payload = keyring.seal(&keyring.open_sealed(file_key_id, &payload, &aad)?, &aad)?;
+                writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+                writer.write_all(&crc32c::crc32c(&payload).to_be_bytes())?;
+                writer.write_all(&payload)?;
+                Ok(())
+            })?;
+            writer.flush()?;
+            writer.get_mut().sync_all()?;
+            drop(writer);
+            fs::rename(&tmp_path, &path)?;
+        }
+        Ok(())
+    }
+}
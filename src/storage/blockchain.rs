@@ -1,10 +1,46 @@
-use std::collections::VecDeque;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write, Seek, SeekFrom};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use sha2::{Sha256, Digest};
 use serde::{Serialize, Deserialize};
-use crate::storage::Record;
+use crate::storage::{Record, RecordKind, RecordV1};
+use crate::storage::encryption::{self, Keyring, PLAINTEXT_KEY_ID};
+use crate::storage::sstable::read_at;
+use crate::storage::proof::{ProofStep, Side};
+use crate::storage::record_index::{self, RecordIndex};
+
+/// Chain segment header: "BDBL", the format version and the id of the data
+/// key frames are sealed with
+const LOG_MAGIC: [u8; 4] = *b"BDBL";
+const LOG_VERSION: u32 = 1;
+const LOG_HEADER_SIZE: u64 = 12;
+/// Frame header: payload length followed by its CRC32C
+const FRAME_HEADER_SIZE: u64 = 8;
+/// A new segment is started once the current one reaches this size
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const SEGMENT_PREFIX: &str = "chain_";
+const SEGMENT_SUFFIX: &str = ".log";
+/// Suffix of a segment being resealed by a key rotation
+const REWRITE_SUFFIX: &str = ".tmp";
+/// Offset index: one `segment | offset | size` entry per block
+const INDEX_FILE: &str = "chain.idx";
+const INDEX_ENTRY_SIZE: usize = 20;
+
+/// Single file holding the whole chain, written before the block log.
+/// "BDBC" followed by the format version; files without it hold blocks of
+/// [`RecordV1`]s.
+const LEGACY_CHAIN_FILE: &str = "blockchain.dat";
+const CHAIN_MAGIC: [u8; 4] = *b"BDBC";
+/// Version 2: the serialized blocks.
+/// Version 3: a data key id, then the blocks, sealed under that key unless
+/// it is `PLAINTEXT_KEY_ID`.
+const CHAIN_VERSION_V2: u32 = 2;
+const CHAIN_VERSION_V3: u32 = 3;
+const CHAIN_HEADER_SIZE: usize = 8;
+const CHAIN_HEADER_SIZE_V3: usize = CHAIN_HEADER_SIZE + 4;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -17,6 +53,36 @@ pub struct Block {
     pub nonce: u64,
 }
 
+/// Block a record was sealed in, and the Merkle path from the record to
+/// the block's root
+pub type RecordProof = (Block, Vec<ProofStep>);
+
+/// Block layout of unversioned blockchain files
+#[derive(Debug, Deserialize)]
+struct BlockV1 {
+    index: u64,
+    timestamp: u64,
+    previous_hash: Vec<u8>,
+    merkle_root: Vec<u8>,
+    records: Vec<RecordV1>,
+    hash: Vec<u8>,
+    nonce: u64,
+}
+
+impl From<BlockV1> for Block {
+    fn from(block: BlockV1) -> Self {
+        Block {
+            index: block.index,
+            timestamp: block.timestamp,
+            previous_hash: block.previous_hash,
+            merkle_root: block.merkle_root,
+            records: block.records.into_iter().map(Record::from).collect(),
+            hash: block.hash,
+            nonce: block.nonce,
+        }
+    }
+}
+
 impl Block {
     pub fn new(index: u64, previous_hash: Vec<u8>, records: Vec<Record>) -> Self {
         let timestamp = std::time::SystemTime::now()
@@ -40,18 +106,14 @@ impl Block {
     }
 
     fn calculate_hash(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(&self.index.to_be_bytes());
-        hasher.update(&self.timestamp.to_be_bytes());
-        hasher.update(&self.previous_hash);
-        hasher.update(&self.merkle_root);
-        hasher.update(&self.nonce.to_be_bytes());
-        
-        for record in &self.records {
-            hasher.update(&record.hash);
-        }
-        
-        hasher.finalize().to_vec()
+        block_hash(
+            self.index,
+            self.timestamp,
+            &self.previous_hash,
+            &self.merkle_root,
+            self.nonce,
+            self.records.iter().map(|record| record.hash.as_slice()),
+        )
     }
 
     fn calculate_merkle_root(records: &[Record]) -> Vec<u8> {
@@ -65,14 +127,8 @@ impl Block {
             let mut next_level = Vec::new();
             
             for chunk in hashes.chunks(2) {
-                let mut hasher = Sha256::new();
-                hasher.update(&chunk[0]);
-                if chunk.len() > 1 {
-                    hasher.update(&chunk[1]);
-                } else {
-                    hasher.update(&chunk[0]);
-                }
-                next_level.push(hasher.finalize().to_vec());
+                // An odd node out is paired with itself
+                next_level.push(merkle_parent(&chunk[0], chunk.get(1).unwrap_or(&chunk[0])));
             }
             
             hashes = next_level;
@@ -89,186 +145,747 @@ impl Block {
     }
 }
 
+/// Hash of a block over its header fields and the hashes of its records
+pub(crate) fn block_hash<'a>(
+    index: u64,
+    timestamp: u64,
+    previous_hash: &[u8],
+    merkle_root: &[u8],
+    nonce: u64,
+    record_hashes: impl IntoIterator<Item = &'a [u8]>,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(index.to_be_bytes());
+    hasher.update(timestamp.to_be_bytes());
+    hasher.update(previous_hash);
+    hasher.update(merkle_root);
+    hasher.update(nonce.to_be_bytes());
+    
+    for hash in record_hashes {
+        hasher.update(hash);
+    }
+    
+    hasher.finalize().to_vec()
+}
+
+/// Node of a block's Merkle tree over its two children
+pub(crate) fn merkle_parent(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+/// When queued records are sealed into a block. Each write is sealed
+/// whole, so a block can run past the record and byte limits by one write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealingPolicy {
+    /// Seal once this many records are queued
+    pub max_records: usize,
+    /// Seal once the queued records' keys, values and hashes reach this size
+    pub max_bytes: usize,
+    /// Seal a partial block once its oldest record has waited this long;
+    /// `None` waits for the other limits
+    pub max_age: Option<Duration>,
+}
+
+impl SealingPolicy {
+    pub fn from_config(config: &crate::storage::BlockDBConfig) -> Self {
+        SealingPolicy {
+            max_records: config.blockchain_batch_size.max(1),
+            max_bytes: config.blockchain_max_block_bytes,
+            max_age: match config.blockchain_max_block_age_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+        }
+    }
+}
+
+impl Default for SealingPolicy {
+    fn default() -> Self {
+        SealingPolicy::from_config(&crate::storage::BlockDBConfig::default())
+    }
+}
+
+/// Where a block's frame is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockLocation {
+    segment: u64,
+    /// Offset of the frame header
+    offset: u64,
+    /// Length of the stored payload
+    size: u32,
+}
+
+impl BlockLocation {
+    fn encode(&self) -> [u8; INDEX_ENTRY_SIZE] {
+        let mut entry = [0u8; INDEX_ENTRY_SIZE];
+        entry[..8].copy_from_slice(&self.segment.to_be_bytes());
+        entry[8..16].copy_from_slice(&self.offset.to_be_bytes());
+        entry[16..].copy_from_slice(&self.size.to_be_bytes());
+        entry
+    }
+
+    fn decode(entry: &[u8]) -> Self {
+        BlockLocation {
+            segment: u64::from_be_bytes(entry[..8].try_into().unwrap()),
+            offset: u64::from_be_bytes(entry[8..16].try_into().unwrap()),
+            size: u32::from_be_bytes(entry[16..20].try_into().unwrap()),
+        }
+    }
+
+    /// Offset just past the frame
+    fn end(&self) -> u64 {
+        self.offset + FRAME_HEADER_SIZE + self.size as u64
+    }
+}
+
+/// Segment being appended to
+#[derive(Debug)]
+struct SegmentWriter {
+    file: File,
+    segment: u64,
+    offset: u64,
+    /// Data key new frames are sealed with, or `PLAINTEXT_KEY_ID`
+    key_id: u32,
+}
+
+/// Read side of a [`BlockChain`]. A clone is a snapshot of the blocks
+/// sealed so far, which can be read without holding the chain.
+#[derive(Debug, Clone)]
+pub struct ChainReader {
+    data_dir: String,
+    /// Where each block is stored, by index
+    locations: Vec<BlockLocation>,
+    /// Data key of each segment
+    segment_keys: BTreeMap<u64, u32>,
+    /// Opens sealed segments, and seals new ones under its current key, when set
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl ChainReader {
+    fn segment_path(&self, segment: u64) -> String {
+        format!("{}/{}{:06}{}", self.data_dir, SEGMENT_PREFIX, segment, SEGMENT_SUFFIX)
+    }
+
+    /// Read and decode the block stored at `location` of the open `file`
+    fn read_block(&self, file: &File, location: &BlockLocation) -> Result<Block, Box<dyn std::error::Error>> {
+        let mut frame = vec![0u8; (FRAME_HEADER_SIZE + location.size as u64) as usize];
+        read_at(file, location.offset, &mut frame)?;
+        let (frame_header, payload) = frame.split_at(FRAME_HEADER_SIZE as usize);
+        let size = u32::from_be_bytes(frame_header[..4].try_into().unwrap());
+        let crc = u32::from_be_bytes(frame_header[4..].try_into().unwrap());
+        if size != location.size || crc32c::crc32c(payload) != crc {
+            return Err(Box::new(crate::error::BlockDBError::Corruption(format!(
+                "Block frame at offset {} of {} failed its checksum", location.offset, self.segment_path(location.segment)
+            ))));
+        }
+
+        let key_id = self.segment_keys.get(&location.segment).copied().unwrap_or(PLAINTEXT_KEY_ID);
+        let payload = encryption::unseal(self.keyring.as_deref(), key_id, payload.to_vec(), &BlockChain::aad(location.segment, location.offset))?;
+        Ok(bincode::deserialize(&payload)?)
+    }
+
+    /// Every block in order, read from disk one at a time
+    pub fn blocks(&self) -> impl Iterator<Item = Result<Block, Box<dyn std::error::Error>>> + '_ {
+        let mut open: Option<(u64, File)> = None;
+        self.locations.iter().map(move |location| {
+            if open.as_ref().is_none_or(|(segment, _)| *segment != location.segment) {
+                open = Some((location.segment, File::open(self.segment_path(location.segment))?));
+            }
+            self.read_block(&open.as_ref().unwrap().1, location)
+        })
+    }
+
+    /// Block `index`, read from disk
+    pub fn get_block(&self, index: u64) -> Result<Option<Block>, Box<dyn std::error::Error>> {
+        match self.locations.get(index as usize) {
+            Some(location) => Ok(Some(self.read_block(&File::open(self.segment_path(location.segment))?, location)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Name of the segment file block `index` is stored in
+    pub fn segment_file(&self, index: u64) -> Option<String> {
+        self.locations.get(index as usize)
+            .map(|location| format!("{}{:06}{}", SEGMENT_PREFIX, location.segment, SEGMENT_SUFFIX))
+    }
+}
+
+/// The chain is an append-only log of numbered segments, one frame per
+/// block: `len | crc | payload`, with the payload sealed and bound to its
+/// segment and offset when encryption is enabled. Creating a block appends
+/// one frame and one entry to the offset index, and only the newest block
+/// is kept in memory; others are read from disk when asked for.
+///
+/// The index is rebuilt from the segments for any blocks it is missing, so
+/// it needs no sync of its own. A crash can only tear the last frame, which
+/// is dropped on open.
 #[derive(Debug)]
 pub struct BlockChain {
-    blocks: Vec<Block>,
+    reader: ChainReader,
+    /// Newest block, which the next one is chained to
+    head: Block,
+    /// Highest sequence number of a write sealed into a block
+    sealed_sequence: u64,
     pending_records: VecDeque<Record>,
-    batch_size: usize,
-    file_path: String,
+    /// Size of the queued records, as counted by [`SealingPolicy::max_bytes`]
+    pending_bytes: usize,
+    /// When the oldest queued record was added
+    pending_since: Option<Instant>,
+    policy: SealingPolicy,
+    writer: Option<SegmentWriter>,
+    index_file: File,
+    /// Block each sealed record hash is in
+    record_index: RecordIndex,
 }
 
 impl BlockChain {
-    pub fn new(data_dir: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let file_path = format!("{}/blockchain.dat", data_dir);
+    /// Open the chain in `data_dir`, migrating a `blockchain.dat` written
+    /// before the block log, or starting a new chain with a genesis block
+    pub fn new(data_dir: &str, keyring: Option<Arc<Keyring>>, policy: SealingPolicy) -> Result<Self, Box<dyn std::error::Error>> {
+        let legacy_path = format!("{}/{}", data_dir, LEGACY_CHAIN_FILE);
+        let legacy_blocks = match Path::new(&legacy_path).exists() {
+            true => Some(Self::load_legacy(&legacy_path, keyring.as_deref())?),
+            false => None,
+        };
+
+        for entry in fs::read_dir(data_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let segment = file_name.starts_with(SEGMENT_PREFIX) && file_name.ends_with(SEGMENT_SUFFIX);
+            let rewrite = file_name.starts_with(SEGMENT_PREFIX) && file_name.ends_with(REWRITE_SUFFIX);
+            // The legacy file is only removed once migrated, so a log
+            // next to it is a partial migration to start over
+            let index = file_name == INDEX_FILE || record_index::is_index_file(&file_name);
+            if rewrite || ((segment || index) && legacy_blocks.is_some()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        let index_file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(format!("{}/{}", data_dir, INDEX_FILE))?;
+        let record_index = RecordIndex::open(data_dir)?;
         let mut blockchain = BlockChain {
-            blocks: Vec::new(),
+            reader: ChainReader {
+                data_dir: data_dir.to_string(),
+                locations: Vec::new(),
+                segment_keys: BTreeMap::new(),
+                keyring,
+            },
+            head: Block::new(0, vec![0u8; 32], Vec::new()),
+            sealed_sequence: 0,
             pending_records: VecDeque::new(),
-            batch_size: 1000,
-            file_path,
+            pending_bytes: 0,
+            pending_since: None,
+            policy,
+            writer: None,
+            index_file,
+            record_index,
         };
-        
-        blockchain.load_from_disk()?;
-        
-        if blockchain.blocks.is_empty() {
-            let genesis_block = Block::new(0, vec![0u8; 32], Vec::new());
-            blockchain.blocks.push(genesis_block);
-            blockchain.save_to_disk()?;
+        blockchain.load_index()?;
+        blockchain.load_record_index()?;
+
+        match legacy_blocks {
+            Some(blocks) => {
+                for block in blocks {
+                    blockchain.append(block)?;
+                }
+                if blockchain.reader.locations.is_empty() {
+                    blockchain.append(Block::new(0, vec![0u8; 32], Vec::new()))?;
+                }
+                fs::remove_file(&legacy_path)?;
+                File::open(data_dir)?.sync_all()?;
+                println!("✅ Migrated {} blocks of {} to the block log", blockchain.reader.locations.len(), legacy_path);
+            }
+            None if blockchain.reader.locations.is_empty() => {
+                let genesis = blockchain.head.clone();
+                blockchain.append(genesis)?;
+            }
+            None => {
+                let last = *blockchain.reader.locations.last().unwrap();
+                blockchain.head = blockchain.reader.read_block(&File::open(blockchain.reader.segment_path(last.segment))?, &last)?;
+
+                // Writes are sealed in sequence order, so the newest block
+                // holding any has the highest
+                for index in (0..blockchain.reader.locations.len() as u64).rev() {
+                    let block = blockchain.get_block(index)?.unwrap();
+                    if let Some(sequence) = Self::max_write_sequence(&block) {
+                        blockchain.sealed_sequence = sequence;
+                        break;
+                    }
+                }
+            }
         }
-        
+
         Ok(blockchain)
     }
 
+    /// Numbers of the chain segments in `data_dir`, ascending
+    fn list_segments(data_dir: &str) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(data_dir)? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(number) = file_name
+                .strip_prefix(SEGMENT_PREFIX)
+                .and_then(|rest| rest.strip_suffix(SEGMENT_SUFFIX))
+                .and_then(|number| number.parse::<u64>().ok())
+            {
+                segments.push(number);
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Frames are bound to where they are stored, so one cannot be swapped
+    /// for another
+    fn aad(segment: u64, offset: u64) -> [u8; 16] {
+        let mut aad = [0u8; 16];
+        aad[..8].copy_from_slice(&segment.to_be_bytes());
+        aad[8..].copy_from_slice(&offset.to_be_bytes());
+        aad
+    }
+
+    /// Data key id from a segment header, or `None` if the header is torn
+    fn read_header(path: &str) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+        let mut header = [0u8; LOG_HEADER_SIZE as usize];
+        match File::open(path)?.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        }
+        if header[..4] != LOG_MAGIC {
+            return Err(Box::new(crate::error::BlockDBError::Corruption(format!("Invalid chain segment header in {}", path))));
+        }
+        let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        if version != LOG_VERSION {
+            return Err(Box::new(crate::error::BlockDBError::InvalidData(
+                format!("Unsupported chain segment format version {} in {}", version, path)
+            )));
+        }
+        Ok(Some(u32::from_be_bytes(header[8..12].try_into().unwrap())))
+    }
+
+    /// Pass the offset and payload of each valid frame from `from` on to
+    /// `visit`, stopping at the first frame that is incomplete or fails its
+    /// checksum. Returns the end of the last valid frame and the file length.
+    fn scan_segment(path: &str, from: u64, mut visit: impl FnMut(u64, Vec<u8>) -> Result<(), Box<dyn std::error::Error>>) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(from))?;
+        let mut reader = BufReader::new(file);
+
+        let mut valid_len = from;
+        loop {
+            let mut frame_header = [0u8; FRAME_HEADER_SIZE as usize];
+            if reader.read_exact(&mut frame_header).is_err() {
+                break;
+            }
+            let size = u32::from_be_bytes(frame_header[..4].try_into().unwrap()) as u64;
+            let crc = u32::from_be_bytes(frame_header[4..].try_into().unwrap());
+            if valid_len + FRAME_HEADER_SIZE + size > file_len {
+                break;
+            }
+            let mut payload = vec![0u8; size as usize];
+            if reader.read_exact(&mut payload).is_err() || crc32c::crc32c(&payload) != crc {
+                break;
+            }
+            visit(valid_len, payload)?;
+            valid_len += FRAME_HEADER_SIZE + size;
+        }
+
+        Ok((valid_len, file_len))
+    }
+
+    /// Load the offset index, drop entries the segments do not back, and
+    /// index any frames appended after its last entry. A torn frame at the
+    /// end of the last segment is truncated.
+    fn load_index(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut segments = Self::list_segments(&self.reader.data_dir)?;
+        for &segment in &segments {
+            match Self::read_header(&self.reader.segment_path(segment))? {
+                Some(key_id) => {
+                    self.reader.segment_keys.insert(segment, key_id);
+                }
+                // Only the newest segment can be torn while it is created
+                None if Some(&segment) == segments.last() => fs::remove_file(self.reader.segment_path(segment))?,
+                None => {
+                    return Err(Box::new(crate::error::BlockDBError::Corruption(
+                        format!("Torn header in chain segment {}", self.reader.segment_path(segment))
+                    )));
+                }
+            }
+        }
+        segments.retain(|segment| self.reader.segment_keys.contains_key(segment));
+
+        let mut data = Vec::new();
+        self.index_file.read_to_end(&mut data)?;
+        self.reader.locations = data.chunks_exact(INDEX_ENTRY_SIZE).map(BlockLocation::decode).collect();
+        while let Some(last) = self.reader.locations.last() {
+            let backed = self.reader.segment_keys.contains_key(&last.segment)
+                && fs::metadata(self.reader.segment_path(last.segment))?.len() >= last.end();
+            if backed {
+                break;
+            }
+            self.reader.locations.pop();
+        }
+        if data.len() != self.reader.locations.len() * INDEX_ENTRY_SIZE {
+            self.index_file.set_len((self.reader.locations.len() * INDEX_ENTRY_SIZE) as u64)?;
+        }
+
+        let (first_segment, first_offset) = match self.reader.locations.last() {
+            Some(last) => (last.segment, last.end()),
+            None => (segments.first().copied().unwrap_or(0), LOG_HEADER_SIZE),
+        };
+        for &segment in segments.iter().filter(|&&segment| segment >= first_segment) {
+            let path = self.reader.segment_path(segment);
+            let from = if segment == first_segment { first_offset } else { LOG_HEADER_SIZE };
+            let mut found = Vec::new();
+            let (valid_len, file_len) = Self::scan_segment(&path, from, |offset, payload| {
+                found.push(BlockLocation { segment, offset, size: payload.len() as u32 });
+                Ok(())
+            })?;
+            for location in found {
+                self.index_file.write_all(&location.encode())?;
+                self.reader.locations.push(location);
+            }
+
+            if valid_len < file_len {
+                if Some(&segment) != segments.last() {
+                    return Err(Box::new(crate::error::BlockDBError::Corruption(
+                        format!("Invalid frame at offset {} of chain segment {}", valid_len, path)
+                    )));
+                }
+                println!("⚠️ Discarded {} bytes of torn chain tail in {}", file_len - valid_len, path);
+                OpenOptions::new().write(true).open(&path)?.set_len(valid_len)?;
+            }
+        }
+
+        // Keep appending to the newest segment unless it is full or sealed
+        // under an older key
+        let key_id = self.current_key_id();
+        if let Some(&segment) = segments.last() {
+            let path = self.reader.segment_path(segment);
+            let offset = fs::metadata(&path)?.len();
+            if self.reader.segment_keys[&segment] == key_id && offset < SEGMENT_SIZE {
+                let file = OpenOptions::new().append(true).open(&path)?;
+                self.writer = Some(SegmentWriter { file, segment, offset, key_id });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load the record index and index again the blocks it may be missing
+    fn load_record_index(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let chain_length = self.reader.locations.len() as u64;
+        for index in self.record_index.load(chain_length)?..chain_length {
+            let block = self.get_block(index)?.unwrap();
+            self.record_index.add(&block)?;
+        }
+        Ok(())
+    }
+
+    fn current_key_id(&self) -> u32 {
+        self.reader.keyring.as_ref().map_or(PLAINTEXT_KEY_ID, |keyring| keyring.current_id())
+    }
+
+    fn create_segment(&mut self) -> Result<SegmentWriter, Box<dyn std::error::Error>> {
+        let segment = self.reader.segment_keys.keys().last().copied().unwrap_or(0) + 1;
+        let key_id = self.current_key_id();
+
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(self.reader.segment_path(segment))?;
+        let mut header = LOG_MAGIC.to_vec();
+        header.extend_from_slice(&LOG_VERSION.to_be_bytes());
+        header.extend_from_slice(&key_id.to_be_bytes());
+        file.write_all(&header)?;
+        file.sync_all()?;
+        File::open(&self.reader.data_dir)?.sync_all()?;
+
+        self.reader.segment_keys.insert(segment, key_id);
+        Ok(SegmentWriter { file, segment, offset: LOG_HEADER_SIZE, key_id })
+    }
+
+    /// Append `block` as one synced frame, index it and make it the head
+    fn append(&mut self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
+        if self.writer.as_ref().is_none_or(|writer| writer.offset >= SEGMENT_SIZE) {
+            self.writer = Some(self.create_segment()?);
+        }
+        let writer = self.writer.as_mut().unwrap();
+
+        let mut payload = bincode::serialize(&block)?;
+        if let Some(keyring) = self.reader.keyring.as_ref().filter(|_| writer.key_id != PLAINTEXT_KEY_ID) {
+            payload = keyring.seal(&payload, &Self::aad(writer.segment, writer.offset))?;
+        }
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE as usize + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&crc32c::crc32c(&payload).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        writer.file.write_all(&frame)?;
+        writer.file.sync_data()?;
+
+        let location = BlockLocation { segment: writer.segment, offset: writer.offset, size: payload.len() as u32 };
+        writer.offset = location.end();
+        self.index_file.write_all(&location.encode())?;
+        self.reader.locations.push(location);
+        self.record_index.add(&block)?;
+        self.sealed_sequence = self.sealed_sequence.max(Self::max_write_sequence(&block).unwrap_or(0));
+        self.head = block;
+
+        Ok(())
+    }
+
+    /// Highest sequence number of the writes in `block`. Expiry markers
+    /// carry the sequence number of an older record, so they are skipped.
+    fn max_write_sequence(block: &Block) -> Option<u64> {
+        block.records.iter()
+            .filter(|record| record.kind != RecordKind::Expired)
+            .map(|record| record.sequence_number)
+            .max()
+    }
+
+    /// Highest sequence number of a write sealed into a block; later
+    /// writes are pending or were lost from the queue by a crash
+    pub fn sealed_sequence(&self) -> u64 {
+        self.sealed_sequence
+    }
+
     pub fn add_record(&mut self, record: Record) -> Result<(), Box<dyn std::error::Error>> {
-        self.pending_records.push_back(record);
+        self.add_records(vec![record])?;
+        Ok(())
+    }
+
+    /// Queue the records of one write together, so they are never split
+    /// across blocks. Returns the index of the block they go into.
+    pub fn add_records(&mut self, records: Vec<Record>) -> Result<u64, Box<dyn std::error::Error>> {
+        let block_index = self.reader.locations.len() as u64;
+        self.pending_since.get_or_insert_with(Instant::now);
+        self.pending_bytes += records.iter().map(Self::record_size).sum::<usize>();
+        self.pending_records.extend(records);
         
-        if self.pending_records.len() >= self.batch_size {
+        if self.pending_records.len() >= self.policy.max_records || self.pending_bytes >= self.policy.max_bytes {
             self.create_block()?;
         }
         
-        Ok(())
+        Ok(block_index)
+    }
+
+    fn record_size(record: &Record) -> usize {
+        record.key.len() + record.value.len() + record.hash.len()
+    }
+
+    /// Seal the queued records if the oldest has waited out the policy's
+    /// `max_age`. Returns how long until the queue is next due, or `None`
+    /// if nothing is queued or there is no age limit.
+    pub fn seal_if_due(&mut self) -> Result<Option<Duration>, Box<dyn std::error::Error>> {
+        let (Some(max_age), Some(since)) = (self.policy.max_age, self.pending_since) else {
+            return Ok(None);
+        };
+        match max_age.checked_sub(since.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => Ok(Some(remaining)),
+            _ => {
+                self.create_block()?;
+                Ok(None)
+            }
+        }
     }
 
     fn create_block(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.pending_bytes = 0;
+        self.pending_since = None;
         if self.pending_records.is_empty() {
             return Ok(());
         }
         
         let records: Vec<Record> = self.pending_records.drain(..).collect();
-        let previous_hash = self.blocks.last().unwrap().hash.clone();
-        let index = self.blocks.len() as u64;
+        let previous_hash = self.head.hash.clone();
+        let index = self.reader.locations.len() as u64;
         
-        let block = Block::new(index, previous_hash, records);
-        self.blocks.push(block);
-        
-        self.save_to_disk()?;
-        
-        Ok(())
+        self.append(Block::new(index, previous_hash, records))
     }
 
     pub fn verify_chain(&self) -> Result<bool, Box<dyn std::error::Error>> {
-        if self.blocks.is_empty() {
-            return Ok(true);
-        }
-        
-        for i in 1..self.blocks.len() {
-            let current_block = &self.blocks[i];
-            let previous_block = &self.blocks[i - 1];
-            
-            if !current_block.verify_integrity() {
-                return Ok(false);
-            }
-            
-            if current_block.previous_hash != previous_block.hash {
-                return Ok(false);
-            }
-            
-            if current_block.index != previous_block.index + 1 {
-                return Ok(false);
+        let mut previous_block: Option<Block> = None;
+
+        for block in self.blocks() {
+            let current_block = block?;
+            if let Some(previous_block) = &previous_block {
+                if !current_block.verify_integrity() {
+                    return Ok(false);
+                }
+
+                if current_block.previous_hash != previous_block.hash {
+                    return Ok(false);
+                }
+
+                if current_block.index != previous_block.index + 1 {
+                    return Ok(false);
+                }
             }
+            previous_block = Some(current_block);
         }
         
         Ok(true)
     }
 
-    pub fn get_block(&self, index: u64) -> Option<&Block> {
-        self.blocks.get(index as usize)
+    /// Every block in order, read from disk one at a time
+    pub fn blocks(&self) -> impl Iterator<Item = Result<Block, Box<dyn std::error::Error>>> + '_ {
+        self.reader.blocks()
+    }
+
+    /// Block `index`, read from disk
+    pub fn get_block(&self, index: u64) -> Result<Option<Block>, Box<dyn std::error::Error>> {
+        self.reader.get_block(index)
+    }
+
+    /// Snapshot of the blocks sealed so far
+    pub fn reader(&self) -> ChainReader {
+        self.reader.clone()
     }
 
     pub fn get_latest_block(&self) -> Option<&Block> {
-        self.blocks.last()
+        Some(&self.head)
     }
 
     pub fn get_chain_length(&self) -> usize {
-        self.blocks.len()
+        self.reader.locations.len()
     }
 
     pub fn force_create_block(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.create_block()
     }
 
-    fn save_to_disk(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&self.file_path)?;
-        
-        let serialized = bincode::serialize(&self.blocks)?;
-        file.write_all(&serialized)?;
-        file.flush()?;
-        
+    /// Reseal every segment under the current key of the keyring. Frames
+    /// can change size, so the index is rebuilt on the next open.
+    pub fn rewrite_segments(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.writer = None;
+        let key_id = self.current_key_id();
+
+        for (&segment, &old_key_id) in &self.reader.segment_keys {
+            let path = self.reader.segment_path(segment);
+            let tmp_path = format!("{}{}", path, REWRITE_SUFFIX);
+
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(&LOG_MAGIC)?;
+            writer.write_all(&LOG_VERSION.to_be_bytes())?;
+            writer.write_all(&key_id.to_be_bytes())?;
+            let mut offset = LOG_HEADER_SIZE;
+            Self::scan_segment(&path, LOG_HEADER_SIZE, |frame_offset, payload| {
+                let mut payload = encryption::unseal(self.reader.keyring.as_deref(), old_key_id, payload, &Self::aad(segment, frame_offset))?;
+                if let Some(keyring) = self.reader.keyring.as_ref().filter(|_| key_id != PLAINTEXT_KEY_ID) {
+                    payload = keyring.seal(&payload, &Self::aad(segment, offset))?;
+                }
+                writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+                writer.write_all(&crc32c::crc32c(&payload).to_be_bytes())?;
+                writer.write_all(&payload)?;
+                offset += FRAME_HEADER_SIZE + payload.len() as u64;
+                Ok(())
+            })?;
+            writer.flush()?;
+            writer.get_mut().sync_all()?;
+            drop(writer);
+            fs::rename(&tmp_path, &path)?;
+        }
+
+        fs::remove_file(format!("{}/{}", self.reader.data_dir, INDEX_FILE))?;
+        File::open(&self.reader.data_dir)?.sync_all()?;
         Ok(())
     }
 
-    fn load_from_disk(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        if !Path::new(&self.file_path).exists() {
-            return Ok(());
-        }
+    /// Read every block of a `blockchain.dat` written before the block log
+    fn load_legacy(path: &str, keyring: Option<&Keyring>) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
+        let buffer = fs::read(path)?;
         
-        let mut file = File::open(&self.file_path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        
-        if !buffer.is_empty() {
-            self.blocks = bincode::deserialize(&buffer)?;
+        if buffer.starts_with(&CHAIN_MAGIC) && buffer.len() >= CHAIN_HEADER_SIZE {
+            let version = u32::from_be_bytes(buffer[4..CHAIN_HEADER_SIZE].try_into().unwrap());
+            match version {
+                CHAIN_VERSION_V2 => Ok(bincode::deserialize(&buffer[CHAIN_HEADER_SIZE..])?),
+                CHAIN_VERSION_V3 if buffer.len() >= CHAIN_HEADER_SIZE_V3 => {
+                    let (header, payload) = buffer.split_at(CHAIN_HEADER_SIZE_V3);
+                    let key_id = u32::from_be_bytes(header[CHAIN_HEADER_SIZE..].try_into().unwrap());
+                    encryption::check_key(keyring, key_id, path)?;
+                    let payload = encryption::unseal(keyring, key_id, payload.to_vec(), header)?;
+                    Ok(bincode::deserialize(&payload)?)
+                }
+                _ => Err(Box::new(crate::error::BlockDBError::InvalidData(
+                    format!("Unsupported blockchain format version {} in {}", version, path)
+                ))),
+            }
+        } else if !buffer.is_empty() {
+            let blocks: Vec<BlockV1> = bincode::deserialize(&buffer)?;
+            Ok(blocks.into_iter().map(Block::from).collect())
+        } else {
+            Ok(Vec::new())
         }
-        
-        Ok(())
     }
 
-    pub fn get_record_proof(&self, record_hash: &[u8]) -> Option<Vec<Vec<u8>>> {
-        for block in &self.blocks {
-            if let Some(index) = block.records.iter().position(|r| r.hash == record_hash) {
-                return Some(self.generate_merkle_proof(&block.records, index));
+    /// Block the record with `record_hash` was sealed in
+    pub fn find_record(&self, record_hash: &[u8]) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        self.record_index.find(record_hash)
+    }
+
+    /// Block the record with `record_hash` was sealed in, and its Merkle path
+    pub fn get_record_proof(&self, record_hash: &[u8]) -> Result<Option<RecordProof>, Box<dyn std::error::Error>> {
+        let Some(block) = self.find_record(record_hash)?.map(|index| self.get_block(index)).transpose()?.flatten() else {
+            return Ok(None);
+        };
+        match block.records.iter().position(|record| record.hash == record_hash) {
+            Some(position) => {
+                let path = Self::merkle_path(&block.records, position);
+                Ok(Some((block, path)))
             }
+            None => Err(Box::new(crate::error::BlockDBError::Corruption(
+                format!("Record index points at block {}, which does not hold the record", block.index)
+            ))),
         }
-        None
     }
 
-    fn generate_merkle_proof(&self, records: &[Record], target_index: usize) -> Vec<Vec<u8>> {
-        let mut proof = Vec::new();
+    fn merkle_path(records: &[Record], target_index: usize) -> Vec<ProofStep> {
+        let mut path = Vec::new();
         let mut hashes: Vec<Vec<u8>> = records.iter().map(|r| r.hash.clone()).collect();
         let mut index = target_index;
         
         while hashes.len() > 1 {
-            if index % 2 == 0 && index + 1 < hashes.len() {
-                proof.push(hashes[index + 1].clone());
-            } else if index % 2 == 1 {
-                proof.push(hashes[index - 1].clone());
-            }
-            
-            let mut next_level = Vec::new();
-            for chunk in hashes.chunks(2) {
-                let mut hasher = Sha256::new();
-                hasher.update(&chunk[0]);
-                if chunk.len() > 1 {
-                    hasher.update(&chunk[1]);
-                } else {
-                    hasher.update(&chunk[0]);
-                }
-                next_level.push(hasher.finalize().to_vec());
-            }
+            path.push(match index % 2 {
+                0 => ProofStep { sibling: hashes.get(index + 1).unwrap_or(&hashes[index]).clone(), side: Side::Right },
+                _ => ProofStep { sibling: hashes[index - 1].clone(), side: Side::Left },
+            });
             
-            hashes = next_level;
+            hashes = hashes.chunks(2)
+                .map(|chunk| merkle_parent(&chunk[0], chunk.get(1).unwrap_or(&chunk[0])))
+                .collect();
             index /= 2;
         }
         
-        proof
+        path
     }
 
     /// Clear all blockchain data and reset to genesis block
     pub fn clear(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // Clear all blocks and pending records
-        self.blocks.clear();
+        self.writer = None;
+        for segment in Self::list_segments(&self.reader.data_dir)? {
+            fs::remove_file(self.reader.segment_path(segment))?;
+        }
+        self.index_file.set_len(0)?;
+        self.record_index.clear()?;
+        self.reader.locations.clear();
+        self.reader.segment_keys.clear();
         self.pending_records.clear();
+        self.pending_bytes = 0;
+        self.pending_since = None;
+        self.sealed_sequence = 0;
         
         // Create new genesis block
-        let genesis_block = Block::new(0, vec![0u8; 32], Vec::new());
-        self.blocks.push(genesis_block);
-        
-        // Save to disk
-        self.save_to_disk()?;
+        self.append(Block::new(0, vec![0u8; 32], Vec::new()))?;
         
         Ok(())
     }
-}
\ No newline at end of file
+}
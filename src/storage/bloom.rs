@@ -0,0 +1,81 @@
+use serde::{Serialize, Deserialize};
+
+/// Bloom filter over SSTable keys. A negative answer is definite, so a
+/// lookup can skip the file without touching its index or data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Build a filter from key hashes produced by [`hash_key`]
+    pub fn from_hashes(hashes: &[u64], bits_per_key: usize) -> Self {
+        // k = bits_per_key * ln(2) minimises the false positive rate
+        let num_hashes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        // Very small filters have a high false positive rate regardless of k
+        let num_bits = (hashes.len() * bits_per_key).max(64);
+
+        let mut filter = BloomFilter {
+            bits: vec![0u8; num_bits.div_ceil(8)],
+            num_hashes,
+        };
+
+        for &hash in hashes {
+            filter.insert_hash(hash);
+        }
+
+        filter
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = self.num_bits();
+        if num_bits == 0 {
+            return true;
+        }
+
+        self.probe_positions(hash_key(key), num_bits)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.bits.len()
+    }
+
+    fn insert_hash(&mut self, hash: u64) {
+        let num_bits = self.num_bits();
+        let positions: Vec<usize> = self.probe_positions(hash, num_bits).collect();
+        for bit in positions {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    fn num_bits(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Double hashing: derive all probe positions from one 64-bit hash
+    fn probe_positions(&self, hash: u64, num_bits: usize) -> impl Iterator<Item = usize> {
+        let delta = hash.rotate_right(17) | 1;
+        (0..self.num_hashes as u64)
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % num_bits as u64) as usize)
+    }
+}
+
+/// Stable 64-bit key hash. Filters are persisted, so this must never change
+/// (std's `DefaultHasher` is not guaranteed stable across releases).
+pub fn hash_key(key: &[u8]) -> u64 {
+    // FNV-1a followed by a 64-bit finalizer to spread the low bits
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    hash
+}
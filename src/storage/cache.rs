@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, Deserialize};
+
+/// A block is identified by its table's cache id and its offset in the file
+type BlockKey = (u64, u64);
+
+/// Counters of a [`BlockCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes of decoded blocks currently cached
+    pub size: usize,
+    pub capacity: usize,
+}
+
+/// Decoded SSTable data blocks, shared by every table of a database and
+/// bounded by their total size. The least recently used blocks are evicted
+/// first.
+///
+/// Blocks are cached after checksum verification, decryption and
+/// decompression, so a hit costs neither I/O nor CPU.
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    next_table_id: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct LruState {
+    /// Each block with the tick of its last use
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    /// Blocks by last use, oldest first
+    recency: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    size: usize,
+}
+
+impl BlockCache {
+    /// A cache holding up to `capacity` bytes of blocks; 0 caches nothing
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            state: Mutex::new(LruState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            next_table_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Id to cache a newly opened table's blocks under. Ids are never
+    /// reused, so blocks of a deleted table are simply aged out.
+    pub fn new_table_id(&self) -> u64 {
+        self.next_table_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn get(&self, table_id: u64, offset: u64) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.tick += 1;
+
+        match state.blocks.get_mut(&(table_id, offset)) {
+            Some((block, last_use)) => {
+                state.recency.remove(last_use);
+                state.recency.insert(state.tick, (table_id, offset));
+                *last_use = state.tick;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(block.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Cache a block, evicting the least recently used ones to make room.
+    /// A block larger than the whole cache is not kept.
+    pub fn insert(&self, table_id: u64, offset: u64, block: Arc<Vec<u8>>) {
+        if block.len() > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        state.size += block.len();
+        if let Some((old, last_use)) = state.blocks.insert((table_id, offset), (block, tick)) {
+            state.size -= old.len();
+            state.recency.remove(&last_use);
+        }
+        state.recency.insert(tick, (table_id, offset));
+
+        while state.size > self.capacity {
+            let Some((_, key)) = state.recency.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = state.blocks.remove(&key) {
+                state.size -= evicted.len();
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.state.lock().unwrap().size,
+            capacity: self.capacity,
+        }
+    }
+
+    /// Drop every block; the counters keep running
+    pub fn clear(&self) {
+        *self.state.lock().unwrap() = LruState::default();
+    }
+}
@@ -5,7 +5,8 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 use crate::error::BlockDBError;
-use super::{BlockDB, BlockDBConfig, Record};
+use super::{BlockDB, BlockDBConfig, Compression, KeyRange, MasterKeySource, Record, ScanOptions, WriteReceipt};
+use super::encryption::Keyring;
 
 pub type CollectionId = String;
 
@@ -72,6 +73,13 @@ pub struct CollectionSettings {
     pub max_document_size: Option<usize>,
     pub ttl_seconds: Option<u64>,
     pub compression_enabled: bool,
+    /// Codec used when `compression_enabled` is set
+    #[serde(default = "default_compression_codec")]
+    pub compression_codec: Compression,
+    /// Documents at least this large are stored in blob files; `None` uses
+    /// the database's `blob_threshold`
+    #[serde(default)]
+    pub blob_threshold: Option<usize>,
     pub encryption_enabled: bool,
     pub replication_factor: u32,
     pub read_concern: ReadConcern,
@@ -108,6 +116,8 @@ impl Default for CollectionSettings {
             max_document_size: Some(16 * 1024 * 1024), // 16MB
             ttl_seconds: None,
             compression_enabled: false,
+            compression_codec: default_compression_codec(),
+            blob_threshold: None,
             encryption_enabled: false,
             replication_factor: 3,
             read_concern: ReadConcern::Local,
@@ -116,6 +126,10 @@ impl Default for CollectionSettings {
     }
 }
 
+fn default_compression_codec() -> Compression {
+    Compression::Lz4
+}
+
 impl Default for CollectionStats {
     fn default() -> Self {
         Self {
@@ -179,6 +193,25 @@ impl Collection {
         // Create storage with collection-specific data directory
         let mut collection_config = config.clone();
         collection_config.data_dir = format!("{}/collections/{}", config.data_dir, metadata.id);
+        if metadata.settings.ttl_seconds.is_some() {
+            collection_config.ttl_seconds = metadata.settings.ttl_seconds;
+        }
+        if metadata.settings.compression_enabled {
+            collection_config.compression = metadata.settings.compression_codec;
+        } else {
+            collection_config.compression = Compression::None;
+        }
+        if metadata.settings.blob_threshold.is_some() {
+            collection_config.blob_threshold = metadata.settings.blob_threshold;
+        }
+        if !metadata.settings.encryption_enabled {
+            collection_config.master_key = None;
+        } else if collection_config.master_key.is_none() {
+            return Err(BlockDBError::InvalidData(format!(
+                "Collection '{}' is encrypted but no master key is configured",
+                metadata.name
+            )));
+        }
 
         let storage = BlockDB::new(collection_config)?;
 
@@ -189,13 +222,13 @@ impl Collection {
         })
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), BlockDBError> {
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<WriteReceipt, BlockDBError> {
         // Validate against schema if present
         self.validate_document(value)?;
 
         // Store in underlying BlockDB
         let mut storage = self.storage.write().unwrap();
-        storage.put(key, value)?;
+        let receipt = storage.put(key, value)?;
 
         // Update statistics
         self.update_stats(key, value, true)?;
@@ -203,7 +236,7 @@ impl Collection {
         // Update indexes
         self.update_indexes(key, value)?;
 
-        Ok(())
+        Ok(receipt)
     }
 
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BlockDBError> {
@@ -211,21 +244,65 @@ impl Collection {
         storage.get(key).map_err(BlockDBError::from)
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<(), BlockDBError> {
-        // Note: In append-only architecture, this marks as deleted
-        // For now, return error as delete is not supported in append-only
-        Err(BlockDBError::ApiError(
-            "Delete operations not supported in append-only database".to_string(),
-        ))
+    /// Hide `key` behind a tombstone; its history and the blockchain keep
+    /// the earlier records
+    pub fn delete(&self, key: &[u8]) -> Result<WriteReceipt, BlockDBError> {
+        let storage = self.storage.write().unwrap();
+        let receipt = storage.delete(key)?;
+
+        self.remove_document(key);
+
+        Ok(receipt)
+    }
+
+    /// Delete `key` and destroy its encryption key so no version of its
+    /// value can be recovered. Requires `crypto_shredding`.
+    pub fn erase(&self, key: &[u8]) -> Result<(), BlockDBError> {
+        let storage = self.storage.write().unwrap();
+        let was_live = storage.get(key)?.is_some();
+        storage.erase(key)?;
+
+        if was_live {
+            self.remove_document(key);
+        }
+
+        Ok(())
     }
 
     pub fn list_keys(&self, prefix: Option<&[u8]>, limit: Option<usize>) -> Result<Vec<Vec<u8>>, BlockDBError> {
-        // Implementation would scan through keys with optional prefix filter
         let storage = self.storage.read().unwrap();
-        
-        // For now, return empty list as this requires memtable/sstable scanning
-        // In full implementation, this would scan through all keys
-        Ok(Vec::new())
+
+        let range = match prefix {
+            Some(prefix) => KeyRange::prefix(prefix),
+            None => KeyRange::all(),
+        };
+        let options = ScanOptions {
+            limit,
+            ..Default::default()
+        };
+
+        storage
+            .scan_with(range, &options)?
+            .map(|record| record.map(|record| record.key).map_err(BlockDBError::from))
+            .collect()
+    }
+
+    /// Newest record for `key` written at or before `timestamp` (ms).
+    /// Requires `versioned`.
+    pub fn get_as_of(&self, key: &[u8], timestamp: u64) -> Result<Option<Record>, BlockDBError> {
+        let storage = self.storage.read().unwrap();
+        storage.get_as_of(key, timestamp).map_err(BlockDBError::from)
+    }
+
+    /// Records in `range` as they stood at `timestamp` (ms). Requires
+    /// `versioned`.
+    pub fn scan_as_of(&self, range: KeyRange, timestamp: u64) -> Result<Vec<Record>, BlockDBError> {
+        let storage = self.storage.read().unwrap();
+
+        storage
+            .scan_as_of(range, timestamp)?
+            .map(|record| record.map_err(BlockDBError::from))
+            .collect()
     }
 
     pub fn count_documents(&self) -> Result<u64, BlockDBError> {
@@ -313,6 +390,22 @@ impl Collection {
         Ok(())
     }
 
+    fn remove_document(&self, key: &[u8]) {
+        {
+            let mut metadata = self.metadata.write().unwrap();
+            metadata.stats.document_count = metadata.stats.document_count.saturating_sub(1);
+            metadata.stats.operations_count += 1;
+            metadata.stats.last_updated = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+        }
+
+        for index_keys in self.indexes.write().unwrap().values_mut() {
+            index_keys.retain(|indexed| indexed != key);
+        }
+    }
+
     fn update_indexes(&self, key: &[u8], _value: &[u8]) -> Result<(), BlockDBError> {
         // In a full implementation, this would extract index fields from the document
         // and update the appropriate indexes
@@ -508,7 +601,7 @@ impl CollectionManager {
         Ok(None)
     }
 
-    pub fn put(&self, collection_id: &str, key: &[u8], value: &[u8]) -> Result<(), BlockDBError> {
+    pub fn put(&self, collection_id: &str, key: &[u8], value: &[u8]) -> Result<WriteReceipt, BlockDBError> {
         let collections = self.collections.read().unwrap();
         match collections.get(collection_id) {
             Some(collection) => collection.put(key, value),
@@ -530,7 +623,7 @@ impl CollectionManager {
         }
     }
 
-    pub fn delete(&self, collection_id: &str, key: &[u8]) -> Result<(), BlockDBError> {
+    pub fn delete(&self, collection_id: &str, key: &[u8]) -> Result<WriteReceipt, BlockDBError> {
         let collections = self.collections.read().unwrap();
         match collections.get(collection_id) {
             Some(collection) => collection.delete(key),
@@ -541,6 +634,17 @@ impl CollectionManager {
         }
     }
 
+    pub fn erase(&self, collection_id: &str, key: &[u8]) -> Result<(), BlockDBError> {
+        let collections = self.collections.read().unwrap();
+        match collections.get(collection_id) {
+            Some(collection) => collection.erase(key),
+            None => Err(BlockDBError::ApiError(format!(
+                "Collection '{}' not found",
+                collection_id
+            ))),
+        }
+    }
+
     pub fn list_keys(&self, collection_id: &str, prefix: Option<&[u8]>, limit: Option<usize>) -> Result<Vec<Vec<u8>>, BlockDBError> {
         let collections = self.collections.read().unwrap();
         match collections.get(collection_id) {
@@ -619,6 +723,28 @@ impl CollectionManager {
         Ok((total_collections, total_documents, total_size_bytes))
     }
 
+    /// Rotate the encryption key of every encrypted collection under
+    /// `config.data_dir`. Like [`BlockDB::rotate_encryption_key`], this
+    /// runs while no manager is open.
+    pub fn rotate_encryption_key(config: &BlockDBConfig, new_master_key: &MasterKeySource) -> Result<(), BlockDBError> {
+        let collections_dir = format!("{}/collections", config.data_dir);
+        if !std::path::Path::new(&collections_dir).exists() {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(&collections_dir)? {
+            let data_dir = entry?.path().to_string_lossy().to_string();
+            if Keyring::exists(&data_dir) {
+                let collection_config = BlockDBConfig {
+                    data_dir,
+                    ..config.clone()
+                };
+                BlockDB::rotate_encryption_key(&collection_config, new_master_key)?;
+            }
+        }
+        Ok(())
+    }
+
     fn load_existing_collections(&self) -> Result<(), BlockDBError> {
         let collections_dir = format!("{}/collections", self.config.data_dir);
         
@@ -757,6 +883,7 @@ mod tests {
             wal_sync_interval_ms: 1000,
             compaction_threshold: 4,
             blockchain_batch_size: 100,
+            ..Default::default()
         };
 
         let collection = Collection::new(metadata, config).unwrap();
@@ -772,6 +899,127 @@ mod tests {
         assert!(stats.total_size_bytes > 0);
     }
 
+    #[test]
+    fn test_collection_compression_setting() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = BlockDBConfig {
+            data_dir: temp_dir.path().to_string_lossy().to_string(),
+            compression: Compression::Lz4,
+            ..Default::default()
+        };
+        let value = format!(r#"{{"status":"{}"}}"#, "shipped ".repeat(32));
+
+        // Codec tags: 0 = none, 1 = LZ4
+        for (compression_enabled, codec_tag) in [(false, 0u8), (true, 1u8)] {
+            let mut metadata = CollectionMetadata::new(format!("compressed_{}", compression_enabled), None);
+            metadata.settings.compression_enabled = compression_enabled;
+            let data_dir = format!("{}/collections/{}", config.data_dir, metadata.id);
+
+            let collection = Collection::new(metadata, config.clone()).unwrap();
+            collection.put(b"order_1", value.as_bytes()).unwrap();
+            collection.storage.read().unwrap().force_flush_memtable().unwrap();
+            collection.put(b"order_2", value.as_bytes()).unwrap();
+
+            let mut files: Vec<String> = std::fs::read_dir(&data_dir).unwrap()
+                .map(|entry| entry.unwrap().path().to_string_lossy().to_string())
+                .collect();
+            files.sort();
+
+            // The first data block starts the SSTable
+            let sstable = files.iter().find(|path| path.ends_with(".sst")).unwrap();
+            assert_eq!(std::fs::read(sstable).unwrap()[0], codec_tag);
+
+            // The frame payload follows the 12-byte WAL header and 8-byte frame header
+            let wal = files.iter().rev().find(|path| path.ends_with(".log")).unwrap();
+            assert_eq!(std::fs::read(wal).unwrap()[20], codec_tag);
+        }
+    }
+
+    #[test]
+    fn test_collection_time_travel() {
+        let temp_dir = TempDir::new().unwrap();
+        let metadata = CollectionMetadata::new("audited_collection".to_string(), None);
+        
+        let config = BlockDBConfig {
+            data_dir: temp_dir.path().to_string_lossy().to_string(),
+            versioned: true,
+            ..Default::default()
+        };
+
+        let collection = Collection::new(metadata, config).unwrap();
+        
+        let first = collection.put(b"account", b"open").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let second = collection.put(b"account", b"frozen").unwrap();
+        collection.put(b"audit_log", b"entry").unwrap();
+        
+        // Test reads as of each write
+        assert!(collection.get_as_of(b"account", first.timestamp - 1).unwrap().is_none());
+        let record = collection.get_as_of(b"account", first.timestamp).unwrap().unwrap();
+        assert_eq!(record.value, b"open".to_vec());
+        let record = collection.get_as_of(b"account", second.timestamp).unwrap().unwrap();
+        assert_eq!(record.value, b"frozen".to_vec());
+        
+        // Test scans only see records written by then
+        let records = collection.scan_as_of(KeyRange::all(), first.timestamp).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].value, b"open".to_vec());
+    }
+
+    #[test]
+    fn test_collection_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let metadata = CollectionMetadata::new("gdpr_collection".to_string(), None);
+
+        let config = BlockDBConfig {
+            data_dir: temp_dir.path().to_string_lossy().to_string(),
+            crypto_shredding: true,
+            ..Default::default()
+        };
+
+        let collection = Collection::new(metadata, config).unwrap();
+
+        collection.put(b"user_1", b"alice").unwrap();
+        collection.put(b"user_2", b"bob").unwrap();
+
+        // Test delete hides the key
+        collection.delete(b"user_1").unwrap();
+        assert_eq!(collection.get(b"user_1").unwrap(), None);
+        assert!(collection.delete(b"user_1").is_err());
+
+        // Test erase leaves the chain intact
+        collection.erase(b"user_2").unwrap();
+        assert_eq!(collection.get(b"user_2").unwrap(), None);
+        assert!(collection.verify_integrity().unwrap());
+        assert_eq!(collection.get_stats().unwrap().document_count, 0);
+    }
+
+    #[test]
+    fn test_collection_ttl() {
+        let temp_dir = TempDir::new().unwrap();
+        let settings = CollectionSettings {
+            ttl_seconds: Some(1),
+            ..Default::default()
+        };
+        let metadata = CollectionMetadata::new("sessions".to_string(), None).with_settings(settings);
+
+        let config = BlockDBConfig {
+            data_dir: temp_dir.path().to_string_lossy().to_string(),
+            ..Default::default()
+        };
+
+        let collection = Collection::new(metadata, config).unwrap();
+
+        collection.put(b"session_1", b"token").unwrap();
+        assert_eq!(collection.get(b"session_1").unwrap(), Some(b"token".to_vec()));
+
+        // Test records past the TTL are hidden
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(collection.get(b"session_1").unwrap(), None);
+        assert!(collection.list_keys(None, None).unwrap().is_empty());
+        assert!(collection.verify_integrity().unwrap());
+    }
+
     #[test]
     fn test_index_operations() {
         let temp_dir = TempDir::new().unwrap();
@@ -783,6 +1031,7 @@ mod tests {
             wal_sync_interval_ms: 1000,
             compaction_threshold: 4,
             blockchain_batch_size: 100,
+            ..Default::default()
         };
 
         let collection = Collection::new(metadata, config).unwrap();
@@ -802,8 +1051,10 @@ mod tests {
         collection.put(b"user2", b"{'email': 'bob@example.com'}").unwrap();
         
         // Verify index exists
-        let indexes = collection.indexes.read().unwrap();
-        assert!(indexes.contains_key("email_index"));
+        {
+            let indexes = collection.indexes.read().unwrap();
+            assert!(indexes.contains_key("email_index"));
+        }
         
         // Drop index
         collection.drop_index("email_index").unwrap();
@@ -856,6 +1107,7 @@ mod tests {
             wal_sync_interval_ms: 1000,
             compaction_threshold: 4,
             blockchain_batch_size: 100,
+            ..Default::default()
         };
 
         let manager = CollectionManager::new(config).unwrap();
@@ -880,6 +1132,7 @@ mod tests {
             wal_sync_interval_ms: 1000,
             compaction_threshold: 4,
             blockchain_batch_size: 100,
+            ..Default::default()
         };
 
         let manager = CollectionManager::new(config).unwrap();
@@ -917,6 +1170,12 @@ mod tests {
         assert_eq!(manager.get(&users_id, b"order1").unwrap(), None);
         assert_eq!(manager.get(&orders_id, b"user1").unwrap(), None);
         
+        // List keys
+        assert_eq!(manager.list_keys(&users_id, None, None).unwrap(), vec![b"user1".to_vec(), b"user2".to_vec()]);
+        assert_eq!(manager.list_keys(&users_id, Some(b"user2"), None).unwrap(), vec![b"user2".to_vec()]);
+        assert_eq!(manager.list_keys(&users_id, None, Some(1)).unwrap(), vec![b"user1".to_vec()]);
+        assert_eq!(manager.list_keys(&orders_id, Some(b"user"), None).unwrap(), Vec::<Vec<u8>>::new());
+        
         // List collections
         let collections = manager.list_collections().unwrap();
         assert_eq!(collections.len(), 2);
@@ -954,6 +1213,7 @@ mod tests {
             wal_sync_interval_ms: 1000,
             compaction_threshold: 4,
             blockchain_batch_size: 100,
+            ..Default::default()
         };
 
         let manager = CollectionManager::new(config).unwrap();
@@ -993,6 +1253,7 @@ mod tests {
             wal_sync_interval_ms: 1000,
             compaction_threshold: 4,
             blockchain_batch_size: 100,
+            ..Default::default()
         };
 
         let manager = CollectionManager::new(config).unwrap();
@@ -1029,6 +1290,7 @@ mod tests {
             wal_sync_interval_ms: 1000,
             compaction_threshold: 4,
             blockchain_batch_size: 100,
+            ..Default::default()
         };
 
         let manager = CollectionManager::new(config).unwrap();
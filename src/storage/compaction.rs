@@ -1,136 +1,541 @@
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
 use std::fs;
 use std::path::Path;
-use crate::storage::{Record, sstable::SSTable};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use crate::storage::Record;
+use crate::storage::blob::{BlobPointer, BlobStore};
+use crate::storage::sstable::{SSTable, SSTableIter, SSTableOptions, SSTableWriter};
 
+/// Distinguishes compaction outputs created within the same nanosecond
+static NEXT_OUTPUT: AtomicU64 = AtomicU64::new(0);
+
+/// A set of SSTables at one level chosen to be merged into the next level,
+/// together with the tables of the next level whose keys overlap them
+#[derive(Debug, Clone)]
+pub struct CompactionJob {
+    pub level: usize,
+    /// Input file names, oldest first
+    pub inputs: Vec<String>,
+    /// An output is closed at the first key boundary past this many bytes
+    pub max_output_size: u64,
+}
+
+/// Smallest and largest key of an SSTable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySpan {
+    pub first: Vec<u8>,
+    pub last: Vec<u8>,
+}
+
+impl KeySpan {
+    /// Span of `sstable`, or `None` if it holds no record
+    pub fn of(sstable: &SSTable) -> Option<Self> {
+        Some(KeySpan {
+            first: sstable.first_key()?.clone(),
+            last: sstable.last_key()?.clone(),
+        })
+    }
+
+    fn overlaps(&self, other: &KeySpan) -> bool {
+        self.first <= other.last && other.first <= self.last
+    }
+
+    fn union(&self, other: &KeySpan) -> KeySpan {
+        KeySpan {
+            first: self.first.clone().min(other.first.clone()),
+            last: self.last.clone().max(other.last.clone()),
+        }
+    }
+}
+
+/// An SSTable tracked at some level
+#[derive(Debug, Clone)]
+struct LevelFile {
+    file_name: String,
+    span: Option<KeySpan>,
+    /// File size in bytes
+    size: u64,
+}
+
+/// Which older versions of a key a compaction keeps besides the newest
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Sequence numbers of live snapshots, ascending
+    pub snapshots: Vec<u64>,
+    /// Keep every version, as versioned databases serve history reads
+    pub keep_history: bool,
+    /// Records written before this time, in milliseconds since the Unix
+    /// epoch, have outlived their TTL and are dropped
+    pub expire_before: Option<u64>,
+}
+
+/// An SSTable written by a compaction
+#[derive(Debug, Clone)]
+pub struct OutputFile {
+    pub file_name: String,
+    /// Keys the table covers
+    pub span: KeySpan,
+}
+
+/// Result of [`Compactor::merge`]
+#[derive(Debug, Default)]
+pub struct MergeOutput {
+    /// The new SSTables in key order, with disjoint spans; empty if no
+    /// record was kept
+    pub files: Vec<OutputFile>,
+    /// Records that would have been kept but had expired
+    pub expired: Vec<Record>,
+    /// Bytes of blob entries no longer referenced by the output, by blob file
+    pub blob_garbage: BTreeMap<u64, u64>,
+}
+
+/// Next record of one merge input. The heap pops the smallest key first
+/// and, within a key, the newest version.
+struct MergeHead {
+    record: Record,
+    source: usize,
+}
+
+impl Ord for MergeHead {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.record.key.cmp(&self.record.key)
+            .then(self.record.sequence_number.cmp(&other.record.sequence_number))
+    }
+}
+
+impl PartialOrd for MergeHead {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead {}
+
+/// Streams the records of several SSTables in key order, one key's
+/// versions at a time, reading each table one block ahead
+struct MergingIter {
+    sources: Vec<SSTableIter>,
+    heads: BinaryHeap<MergeHead>,
+}
+
+impl MergingIter {
+    fn new(mut sources: Vec<SSTableIter>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut heads = BinaryHeap::with_capacity(sources.len());
+        for (source, iter) in sources.iter_mut().enumerate() {
+            if let Some(record) = iter.next().transpose()? {
+                heads.push(MergeHead { record, source });
+            }
+        }
+        Ok(MergingIter { sources, heads })
+    }
+
+    /// Every version of the next key, newest first
+    fn next_key(&mut self) -> Result<Option<Vec<Record>>, Box<dyn std::error::Error>> {
+        let Some(first) = self.heads.peek() else {
+            return Ok(None);
+        };
+        let key = first.record.key.clone();
+
+        let mut versions = Vec::new();
+        while self.heads.peek().is_some_and(|head| head.record.key == key) {
+            let MergeHead { record, source } = self.heads.pop().unwrap();
+            versions.push(record);
+            if let Some(record) = self.sources[source].next().transpose()? {
+                self.heads.push(MergeHead { record, source });
+            }
+        }
+        Ok(Some(versions))
+    }
+}
+
+/// A finished compaction output and the blob files it references
+type FinishedOutput = (OutputFile, BTreeSet<u64>);
+
+/// A compaction output being written, created on its first record
+struct MergeWriter<'a> {
+    data_dir: &'a str,
+    file_name: String,
+    options: &'a SSTableOptions,
+    writer: Option<SSTableWriter>,
+    span: Option<KeySpan>,
+    /// Blob files the output references
+    referenced: BTreeSet<u64>,
+}
+
+impl<'a> MergeWriter<'a> {
+    fn new(data_dir: &'a str, job: &CompactionJob, options: &'a SSTableOptions) -> Self {
+        MergeWriter {
+            data_dir,
+            file_name: Compactor::output_name(job),
+            options,
+            writer: None,
+            span: None,
+            referenced: BTreeSet::new(),
+        }
+    }
+
+    fn add(&mut self, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self.writer.insert(SSTableWriter::create(&format!("{}/{}", self.data_dir, self.file_name), self.options)?),
+        };
+        writer.add(record)?;
+        if let Some(pointer) = BlobPointer::of(record)? {
+            self.referenced.insert(pointer.file_number);
+        }
+        match &mut self.span {
+            Some(span) => span.last = record.key.clone(),
+            None => self.span = Some(KeySpan { first: record.key.clone(), last: record.key.clone() }),
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far
+    fn size(&self) -> u64 {
+        self.writer.as_ref().map_or(0, |writer| writer.size())
+    }
+
+    /// Finish the file, or return `None` if nothing was written
+    fn finish(self) -> Result<Option<FinishedOutput>, Box<dyn std::error::Error>> {
+        match (self.writer, self.span) {
+            (Some(writer), Some(span)) => {
+                writer.finish()?;
+                Ok(Some((OutputFile { file_name: self.file_name, span }, self.referenced)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Compactor {
     data_dir: String,
-    levels: Vec<Vec<String>>,
-    max_level_size: Vec<usize>,
+    levels: Vec<Vec<LevelFile>>,
+    /// Level 0 is merged once it holds this many files
+    level0_threshold: usize,
+    /// Deeper levels are merged once their files exceed this many bytes
+    max_level_bytes: Vec<u64>,
+    target_file_size: u64,
+    /// Largest key of the last file merged out of each level; the next
+    /// merge starts after it so every part of the level takes its turn
+    compact_pointer: Vec<Option<Vec<u8>>>,
 }
 
 impl Compactor {
-    pub fn new(data_dir: &str) -> Self {
+    /// Create a compactor whose level 0 is merged once it holds
+    /// `level0_threshold` files. Compactions write files of about
+    /// `target_file_size` bytes; level 1 holds ten of them and each deeper
+    /// level ten times more.
+    pub fn new(data_dir: &str, level0_threshold: usize, target_file_size: u64) -> Self {
+        let target_file_size = target_file_size.max(1);
+        let max_level_bytes = (0..7u32)
+            .map(|level| target_file_size.saturating_mul(10u64.saturating_pow(level)))
+            .collect();
+
         Compactor {
             data_dir: data_dir.to_string(),
             levels: vec![Vec::new(); 7],
-            max_level_size: vec![10, 100, 1000, 10000, 100000, 1000000, 10000000],
+            level0_threshold: level0_threshold.max(2),
+            max_level_bytes,
+            target_file_size,
+            compact_pointer: vec![None; 7],
         }
     }
 
-    pub fn add_sstable(&mut self, sstable_path: String, level: usize) {
+    pub fn add_sstable(&mut self, file_name: String, level: usize, span: Option<KeySpan>) {
         if level < self.levels.len() {
-            self.levels[level].push(sstable_path);
+            let size = fs::metadata(format!("{}/{}", self.data_dir, file_name)).map_or(0, |metadata| metadata.len());
+            self.levels[level].push(LevelFile { file_name, span, size });
         }
     }
 
     pub fn needs_compaction(&self, level: usize) -> bool {
-        if level >= self.levels.len() {
+        if level >= self.levels.len() - 1 {
             return false;
         }
-        
-        self.levels[level].len() > self.max_level_size[level]
+
+        match level {
+            0 => self.levels[0].len() >= self.level0_threshold,
+            _ => self.levels[level].iter().map(|file| file.size).sum::<u64>() > self.max_level_bytes[level],
+        }
     }
 
-    pub fn compact_level(&mut self, level: usize) -> Result<(), Box<dyn std::error::Error>> {
-        if level >= self.levels.len() - 1 {
-            return Ok(());
+    /// Pick the shallowest level that is over its limit and one run of it:
+    /// the oldest level 0 file with every level 0 file overlapping it, or
+    /// the next file of a deeper level in key order. Tables of the next
+    /// level whose keys overlap the run are merged too, so that level's
+    /// tables stay disjoint and the output replaces every older version.
+    pub fn pick_compaction(&self) -> Option<CompactionJob> {
+        let level = (0..self.levels.len()).find(|&level| self.needs_compaction(level))?;
+
+        let run = match level {
+            0 => Self::level0_run(&self.levels[0]),
+            _ => self.next_file(level),
+        };
+        let span = run
+            .iter()
+            .filter_map(|file| file.span.as_ref())
+            .fold(None, |span: Option<KeySpan>, file| Some(span.map_or_else(|| file.clone(), |span| span.union(file))));
+        let overlapping = self.levels[level + 1]
+            .iter()
+            .filter(|file| file.span.as_ref().zip(span.as_ref()).is_some_and(|(file, span)| file.overlaps(span)));
+
+        // The next level holds older data
+        Some(CompactionJob {
+            level,
+            inputs: overlapping
+                .chain(run)
+                .map(|file| file.file_name.clone())
+                .collect(),
+            max_output_size: self.target_file_size,
+        })
+    }
+
+    /// The oldest level 0 file and, until none is added, every file
+    /// overlapping those taken, oldest first. Files left behind share no
+    /// key with the run, so it can move down ahead of them.
+    fn level0_run(files: &[LevelFile]) -> Vec<&LevelFile> {
+        let mut taken = vec![false; files.len()];
+        let mut span: Option<KeySpan> = None;
+        if let Some(first) = taken.first_mut() {
+            *first = true;
+            span = files[0].span.clone();
         }
 
-        let mut records = BTreeMap::new();
-        let mut files_to_remove = Vec::new();
+        let mut grown = true;
+        while grown {
+            grown = false;
+            for (file, taken) in files.iter().zip(taken.iter_mut()) {
+                if *taken {
+                    continue;
+                }
+                // Files without a span hold nothing to order against
+                let overlaps = match (&file.span, &span) {
+                    (Some(file), Some(span)) => file.overlaps(span),
+                    _ => true,
+                };
+                if overlaps {
+                    *taken = true;
+                    grown = true;
+                    if let Some(file) = &file.span {
+                        span = Some(span.map_or_else(|| file.clone(), |span| span.union(file)));
+                    }
+                }
+            }
+        }
+
+        files.iter().zip(taken).filter(|(_, taken)| *taken).map(|(file, _)| file).collect()
+    }
+
+    /// The file of `level` after the compact pointer in key order,
+    /// wrapping around to the first
+    fn next_file(&self, level: usize) -> Vec<&LevelFile> {
+        let mut files: Vec<&LevelFile> = self.levels[level].iter().collect();
+        files.sort_by(|a, b| a.span.as_ref().map(|span| &span.first).cmp(&b.span.as_ref().map(|span| &span.first)));
 
-        for sstable_path in &self.levels[level] {
-            let mut sstable = SSTable::open(sstable_path)?;
-            
-            let keys: Vec<_> = sstable.iter().cloned().collect();
-            for key in keys {
-                if let Some(record) = sstable.get(&key)? {
-                    records.insert(key, record);
+        let after_pointer = self.compact_pointer[level].as_ref().and_then(|pointer| {
+            files.iter().position(|file| file.span.as_ref().is_some_and(|span| &span.first > pointer))
+        });
+        files.get(after_pointer.unwrap_or(0)).copied().into_iter().collect()
+    }
+
+    /// Merge the job's inputs into SSTables for the next level, streaming
+    /// them in key order so only one key's versions are held at a time.
+    /// A new output is started at the first key boundary once the current
+    /// one reaches the job's maximum size, so a merge only ever rewrites
+    /// the part of the next level its inputs overlap. Older versions of a
+    /// key are dropped unless `retention` keeps them, and expired records
+    /// are dropped and returned.
+    ///
+    /// Blob entries of dropped records are counted as garbage. With `blobs`,
+    /// kept entries in files that are mostly garbage are moved to the newest
+    /// blob file so the old one can be deleted.
+    ///
+    /// This only reads the inputs and writes the new files; nothing is
+    /// deleted, so callers must install the result before removing inputs.
+    /// It takes no `&self` so it can run without holding the compactor lock.
+    pub fn merge(
+        data_dir: &str,
+        job: &CompactionJob,
+        options: &SSTableOptions,
+        retention: &Retention,
+        blobs: Option<&BlobStore>,
+    ) -> Result<MergeOutput, Box<dyn std::error::Error>> {
+        let sstables = job.inputs
+            .iter()
+            .map(|file_name| SSTable::open_with(&format!("{}/{}", data_dir, file_name), options))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut merging = MergingIter::new(
+            sstables.iter().map(|sstable| sstable.scan_uncached()).collect::<Result<Vec<_>, _>>()?,
+        )?;
+
+        let mut outputs = Vec::new();
+        let mut output = MergeWriter::new(data_dir, job, options);
+        let mut expired = Vec::new();
+        let mut blob_garbage = BTreeMap::new();
+        while let Some(versions) = merging.next_key()? {
+            let (retained, dropped) = Self::retain_versions(versions, retention);
+            for record in &dropped {
+                Self::count_blob_garbage(&mut blob_garbage, record)?;
+            }
+            for record in retained {
+                if retention.expire_before.is_some_and(|expire_before| record.timestamp < expire_before) {
+                    Self::count_blob_garbage(&mut blob_garbage, &record)?;
+                    expired.push(record);
+                } else {
+                    output.add(&record)?;
                 }
             }
-            
-            files_to_remove.push(sstable_path.clone());
+
+            // Every version of a key stays in one file, so the outputs'
+            // spans are disjoint
+            if output.size() >= job.max_output_size {
+                let full = std::mem::replace(&mut output, MergeWriter::new(data_dir, job, options));
+                outputs.extend(full.finish()?);
+            }
         }
+        outputs.extend(output.finish()?);
 
-        if !records.is_empty() {
-            let new_sstable_path = format!("{}/compacted_{}_{}.sst", 
-                self.data_dir, 
-                level + 1,
-                std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos()
-            );
+        // Whether a blob file is worth collecting depends on the garbage this
+        // merge found, so outputs referencing one are rewritten in a second
+        // pass
+        let mut files = Vec::with_capacity(outputs.len());
+        let collect = match blobs {
+            Some(blobs) => blobs.files_to_collect(&blob_garbage)?,
+            None => BTreeSet::new(),
+        };
+        for (file, referenced) in outputs {
+            let Some(blobs) = blobs.filter(|_| !collect.is_disjoint(&referenced)) else {
+                files.push(file);
+                continue;
+            };
 
-            self.create_sstable_from_records(&new_sstable_path, records)?;
-            self.levels[level + 1].push(new_sstable_path);
+            let merged = format!("{}/{}", data_dir, file.file_name);
+            let mut relocated = MergeWriter::new(data_dir, job, options);
+            for record in SSTable::open_with(&merged, options)?.scan_uncached()? {
+                let mut record = record?;
+                if BlobPointer::of(&record)?.is_some_and(|pointer| collect.contains(&pointer.file_number)) {
+                    Self::count_blob_garbage(&mut blob_garbage, &record)?;
+                    blobs.relocate(&mut record)?;
+                }
+                relocated.add(&record)?;
+            }
+            // The output must not reference entries that could be lost
+            blobs.sync()?;
+            fs::remove_file(merged)?;
+            files.extend(relocated.finish()?.map(|(file, _)| file));
+        }
+
+        Ok(MergeOutput { files, expired, blob_garbage })
+    }
+
+    fn output_name(job: &CompactionJob) -> String {
+        format!("compacted_{}_{}_{}.sst",
+            job.level + 1,
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+            NEXT_OUTPUT.fetch_add(1, AtomicOrdering::Relaxed)
+        )
+    }
+
+    fn count_blob_garbage(garbage: &mut BTreeMap<u64, u64>, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(pointer) = BlobPointer::of(record)? {
+            *garbage.entry(pointer.file_number).or_default() += pointer.entry_size();
         }
+        Ok(())
+    }
 
-        for file_path in files_to_remove {
-            fs::remove_file(file_path)?;
+    /// Split `versions` (sorted newest first) into those to keep and those
+    /// to drop. The newest is kept, plus each older one that is the newest
+    /// visible to some snapshot, or all of them when keeping history.
+    fn retain_versions(versions: Vec<Record>, retention: &Retention) -> (Vec<Record>, Vec<Record>) {
+        if retention.keep_history {
+            return (versions, Vec::new());
         }
-        
-        self.levels[level].clear();
 
-        if self.needs_compaction(level + 1) {
-            self.compact_level(level + 1)?;
+        let snapshots = &retention.snapshots;
+        let mut retained = Vec::new();
+        let mut dropped = Vec::new();
+        let mut newer_sequence = None;
+
+        for record in versions {
+            let keep = match newer_sequence {
+                None => true,
+                // A snapshot in [this, newer) reads this version
+                Some(newer) => {
+                    let first = snapshots.partition_point(|&snapshot| snapshot < record.sequence_number);
+                    snapshots.get(first).is_some_and(|&snapshot| snapshot < newer)
+                }
+            };
+            newer_sequence = Some(record.sequence_number);
+            if keep {
+                retained.push(record);
+            } else {
+                dropped.push(record);
+            }
         }
 
-        Ok(())
+        (retained, dropped)
+    }
+
+    /// Record that `job` was installed: its inputs leave their levels and
+    /// the outputs join the next one
+    pub fn complete(&mut self, job: &CompactionJob, outputs: &[OutputFile]) {
+        if job.level > 0 {
+            let last = self.levels[job.level]
+                .iter()
+                .filter(|file| job.inputs.contains(&file.file_name))
+                .filter_map(|file| file.span.as_ref())
+                .map(|span| span.last.clone())
+                .max();
+            if last.is_some() {
+                self.compact_pointer[job.level] = last;
+            }
+        }
+
+        for level in &mut self.levels[job.level..=job.level + 1] {
+            level.retain(|file| !job.inputs.contains(&file.file_name));
+        }
+        for output in outputs {
+            self.add_sstable(output.file_name.clone(), job.level + 1, Some(output.span.clone()));
+        }
     }
 
-    fn create_sstable_from_records(
-        &self,
-        path: &str,
-        records: BTreeMap<Vec<u8>, Record>
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        use std::fs::OpenOptions;
-        use std::io::Write;
-        
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-
-        let mut index = BTreeMap::new();
-        let mut offset = 0u64;
-
-        for (key, record) in records {
-            let serialized = bincode::serialize(&record)?;
-            let size = serialized.len() as u32;
-            
-            file.write_all(&size.to_be_bytes())?;
-            file.write_all(&serialized)?;
-            
-            index.insert(key.clone(), crate::storage::sstable::IndexEntry {
-                key: key.clone(),
-                offset,
-                size,
-            });
-            
-            offset += 4 + serialized.len() as u64;
-        }
-
-        let index_offset = offset;
-        let index_data = bincode::serialize(&index)?;
-        file.write_all(&index_data)?;
-        file.write_all(&index_offset.to_be_bytes())?;
-        file.write_all(&(index_data.len() as u64).to_be_bytes())?;
-        
-        file.flush()?;
-        
+    /// Delete the input files of an installed job
+    pub fn remove_inputs(data_dir: &str, job: &CompactionJob) -> Result<(), Box<dyn std::error::Error>> {
+        for file_name in &job.inputs {
+            fs::remove_file(format!("{}/{}", data_dir, file_name))?;
+        }
         Ok(())
     }
 
+    /// Number of SSTables at `level`
+    pub fn level_size(&self, level: usize) -> usize {
+        self.levels.get(level).map_or(0, |files| files.len())
+    }
+
     pub fn get_level_info(&self) -> Vec<usize> {
         self.levels.iter().map(|level| level.len()).collect()
     }
 
+    pub fn clear(&mut self) {
+        for level in &mut self.levels {
+            level.clear();
+        }
+    }
+
     pub fn cleanup_empty_levels(&mut self) {
         for level in &mut self.levels {
-            level.retain(|path| Path::new(path).exists());
+            level.retain(|file| Path::new(&format!("{}/{}", self.data_dir, file.file_name)).exists());
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,74 @@
+use serde::{Serialize, Deserialize};
+
+/// Zstd level used when writing; favours ratio while staying fast enough
+/// for flushes and compactions
+const ZSTD_LEVEL: i32 = 9;
+
+/// Codec for SSTable data blocks and WAL frames. Each compressed unit is
+/// stored as `codec | payload`, so files written with different codecs can
+/// be read side by side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Fast compression for write-heavy data
+    Lz4,
+    /// Higher ratio at more CPU per block
+    Zstd,
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, Box<dyn std::error::Error>> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(Box::new(crate::error::BlockDBError::Corruption(
+                format!("Unknown compression codec {}", tag)
+            ))),
+        }
+    }
+
+    /// Compress `data` into `codec | payload`. Data that does not shrink is
+    /// stored uncompressed.
+    pub fn encode(self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let compressed = match self {
+            Compression::None => None,
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd => Some(zstd::encode_all(data, ZSTD_LEVEL)?),
+        };
+
+        let (codec, payload) = match &compressed {
+            Some(compressed) if compressed.len() < data.len() => (self, compressed.as_slice()),
+            _ => (Compression::None, data),
+        };
+
+        let mut encoded = Vec::with_capacity(payload.len() + 1);
+        encoded.push(codec.tag());
+        encoded.extend_from_slice(payload);
+        Ok(encoded)
+    }
+
+    /// Reverse [`Self::encode`], whatever codec it used
+    pub fn decode(encoded: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let (&tag, payload) = encoded.split_first().ok_or_else(|| {
+            crate::error::BlockDBError::Corruption("Empty compressed block".to_string())
+        })?;
+
+        match Self::from_tag(tag)? {
+            Compression::None => Ok(payload.to_vec()),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(payload).map_err(|e| {
+                Box::new(crate::error::BlockDBError::Corruption(format!("LZ4 decompression failed: {}", e))) as Box<dyn std::error::Error>
+            }),
+            Compression::Zstd => Ok(zstd::decode_all(payload)?),
+        }
+    }
+}
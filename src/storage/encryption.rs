@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+/// "BDBK" followed by the format version
+const KEYRING_MAGIC: [u8; 4] = *b"BDBK";
+const KEYRING_VERSION: u32 = 1;
+const KEYRING_HEADER_SIZE: usize = 8;
+const KEYRING_FILE: &str = "KEYRING";
+const KEYRING_TMP_FILE: &str = "KEYRING.tmp";
+const NONCE_SIZE: usize = 12;
+/// Key id recorded by files written in plaintext
+pub const PLAINTEXT_KEY_ID: u32 = 0;
+
+/// Where the master key comes from. Either way it is 32 bytes written as
+/// 64 hex digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MasterKeySource {
+    /// Path of a file holding the key
+    KeyFile(String),
+    /// Name of an environment variable holding the key
+    Env(String),
+}
+
+impl MasterKeySource {
+    pub fn load(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let (hex, origin) = match self {
+            MasterKeySource::KeyFile(path) => (fs::read_to_string(path)?, format!("key file {}", path)),
+            MasterKeySource::Env(name) => (
+                std::env::var(name).map_err(|_| invalid(format!("Environment variable {} is not set", name)))?,
+                format!("environment variable {}", name),
+            ),
+        };
+
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(Box::new(invalid(format!("Master key in {} must be 64 hex digits", origin))));
+        }
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| invalid(format!("Master key in {} must be 64 hex digits", origin)))?;
+        }
+        Ok(key)
+    }
+}
+
+/// Data keys of one database directory. Files record the id of the key
+/// they were sealed with, so data written before a rotation stays
+/// readable until it is rewritten.
+///
+/// The keyring is kept in `KEYRING`, sealed under the master key, which
+/// never touches the disk itself.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    current: u32,
+    keys: BTreeMap<u32, [u8; 32]>,
+}
+
+impl std::fmt::Debug for Keyring {
+    // Never print key material
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Keyring of `data_dir`, created with a fresh data key on first use.
+    /// `None` if the directory is not encrypted; an encrypted directory
+    /// opened without a master key is an error.
+    pub fn open(data_dir: &str, master: Option<&MasterKeySource>) -> Result<Option<Arc<Keyring>>, Box<dyn std::error::Error>> {
+        match (master, Self::load(data_dir, master)?) {
+            (_, Some(keyring)) => Ok(Some(Arc::new(keyring))),
+            (None, None) => Ok(None),
+            (Some(master), None) => {
+                let mut keyring = Keyring::default();
+                keyring.add_key();
+                keyring.save(data_dir, master)?;
+                Ok(Some(Arc::new(keyring)))
+            }
+        }
+    }
+
+    /// Whether `data_dir` has been encrypted
+    pub fn exists(data_dir: &str) -> bool {
+        Path::new(&format!("{}/{}", data_dir, KEYRING_FILE)).exists()
+    }
+
+    /// Read the keyring of `data_dir`, if it has one
+    pub fn load(data_dir: &str, master: Option<&MasterKeySource>) -> Result<Option<Keyring>, Box<dyn std::error::Error>> {
+        if !Self::exists(data_dir) {
+            return Ok(None);
+        }
+        let path = format!("{}/{}", data_dir, KEYRING_FILE);
+        let master = master.ok_or_else(|| {
+            invalid(format!("{} is encrypted but no master key is configured", data_dir))
+        })?;
+
+        let data = fs::read(&path)?;
+        if data.len() < KEYRING_HEADER_SIZE + NONCE_SIZE || data[..4] != KEYRING_MAGIC {
+            return Err(Box::new(crate::error::BlockDBError::Corruption(format!("Invalid keyring {}", path))));
+        }
+        let version = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        if version != KEYRING_VERSION {
+            return Err(Box::new(invalid(format!("Unsupported keyring format version {} in {}", version, path))));
+        }
+
+        let plaintext = open_with_key(&master.load()?, &data[KEYRING_HEADER_SIZE..], &data[..KEYRING_HEADER_SIZE])
+            .ok_or_else(|| invalid(format!("Master key does not unlock the keyring in {}", data_dir)))?;
+        let (current, keys): (u32, BTreeMap<u32, [u8; 32]>) = bincode::deserialize(&plaintext)?;
+
+        Ok(Some(Keyring { current, keys }))
+    }
+
+    /// Seal the keyring under `master` and atomically replace `KEYRING`
+    pub fn save(&self, data_dir: &str, master: &MasterKeySource) -> Result<(), Box<dyn std::error::Error>> {
+        let mut header = KEYRING_MAGIC.to_vec();
+        header.extend_from_slice(&KEYRING_VERSION.to_be_bytes());
+        let sealed = seal_with_key(&master.load()?, &bincode::serialize(&(self.current, &self.keys))?, &header)?;
+
+        let tmp_path = format!("{}/{}", data_dir, KEYRING_TMP_FILE);
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&header)?;
+            file.write_all(&sealed)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, format!("{}/{}", data_dir, KEYRING_FILE))?;
+        File::open(data_dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Generate a data key and make it the one new files are sealed with
+    pub fn add_key(&mut self) -> u32 {
+        let id = self.keys.keys().last().copied().unwrap_or(PLAINTEXT_KEY_ID) + 1;
+        self.keys.insert(id, generate_key());
+        self.current = id;
+        id
+    }
+
+    /// Forget every key but the current one
+    pub fn retain_current(&mut self) {
+        let current = self.current;
+        self.keys.retain(|&id, _| id == current);
+    }
+
+    /// Id of the key new files are sealed with
+    pub fn current_id(&self) -> u32 {
+        self.current
+    }
+
+    pub fn contains(&self, key_id: u32) -> bool {
+        self.keys.contains_key(&key_id)
+    }
+
+    /// Seal `data` under the current key, bound to `aad`. Returns
+    /// `nonce | ciphertext`.
+    pub fn seal(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        seal_with_key(&self.keys[&self.current], data, aad)
+    }
+
+    /// Open `nonce | ciphertext` sealed under `key_id`; a wrong key, `aad`
+    /// or any modified byte fails as corruption
+    pub fn open_sealed(&self, key_id: u32, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let key = self.keys.get(&key_id).ok_or_else(|| invalid(format!("Data key {} is not in the keyring", key_id)))?;
+        open_with_key(key, sealed, aad).ok_or_else(|| {
+            Box::new(crate::error::BlockDBError::Corruption("Failed to decrypt sealed data".to_string())) as Box<dyn std::error::Error>
+        })
+    }
+}
+
+/// Fail unless data sealed under `key_id` can be opened with `keyring`
+pub fn check_key(keyring: Option<&Keyring>, key_id: u32, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if key_id == PLAINTEXT_KEY_ID || keyring.is_some_and(|keyring| keyring.contains(key_id)) {
+        return Ok(());
+    }
+    Err(Box::new(invalid(format!("{} is encrypted with data key {}, which is not available", path, key_id))))
+}
+
+/// Open `data` sealed under `key_id`, or return it as is if it is plaintext
+pub fn unseal(keyring: Option<&Keyring>, key_id: u32, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match keyring {
+        _ if key_id == PLAINTEXT_KEY_ID => Ok(data),
+        Some(keyring) => keyring.open_sealed(key_id, &data, aad),
+        None => Err(Box::new(invalid(format!("Data key {} is not available", key_id)))),
+    }
+}
+
+/// A new random AES-256 key
+pub(crate) fn generate_key() -> [u8; 32] {
+    Aes256Gcm::generate_key(&mut OsRng).into()
+}
+
+/// Seal `data` under `key`, bound to `aad`. Returns `nonce | ciphertext`.
+pub(crate) fn seal_with_key(key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cipher = Aes256Gcm::new(&Key::<Aes256Gcm>::from(*key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| crate::error::BlockDBError::StorageError("Failed to encrypt data".to_string()))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Open `nonce | ciphertext` sealed under `key`, or `None` if it was not
+/// sealed under `key` and `aad` or has been modified
+pub(crate) fn open_with_key(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE {
+        return None;
+    }
+    let cipher = Aes256Gcm::new(&Key::<Aes256Gcm>::from(*key));
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let nonce = Nonce::from(<[u8; NONCE_SIZE]>::try_from(nonce).unwrap());
+    cipher.decrypt(&nonce, Payload { msg: ciphertext, aad }).ok()
+}
+
+fn invalid(message: String) -> crate::error::BlockDBError {
+    crate::error::BlockDBError::InvalidData(message)
+}
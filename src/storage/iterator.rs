@@ -0,0 +1,261 @@
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+use crate::storage::blob::BlobPin;
+use crate::storage::keystore::KeyStore;
+use crate::storage::Record;
+
+/// Source of records in key order for a [`DBIterator`]
+pub type RecordSource = Box<dyn Iterator<Item = Result<Record, Box<dyn std::error::Error>>> + Send>;
+
+/// Range of keys to scan
+#[derive(Debug, Clone)]
+pub struct KeyRange {
+    pub start: Bound<Vec<u8>>,
+    pub end: Bound<Vec<u8>>,
+}
+
+impl KeyRange {
+    /// Keys in `[start, end)`
+    pub fn new(start: &[u8], end: &[u8]) -> Self {
+        KeyRange {
+            start: Bound::Included(start.to_vec()),
+            end: Bound::Excluded(end.to_vec()),
+        }
+    }
+
+    /// Just `key`
+    pub fn key(key: &[u8]) -> Self {
+        KeyRange {
+            start: Bound::Included(key.to_vec()),
+            end: Bound::Included(key.to_vec()),
+        }
+    }
+
+    pub fn all() -> Self {
+        KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// Keys starting with `prefix`
+    pub fn prefix(prefix: &[u8]) -> Self {
+        KeyRange {
+            start: Bound::Included(prefix.to_vec()),
+            end: match prefix_successor(prefix) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            },
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        !self.is_before_start(key) && !self.is_past_end(key)
+    }
+
+    pub fn is_before_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key < start.as_slice(),
+            Bound::Excluded(start) => key <= start.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    pub fn is_past_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end.as_slice(),
+            Bound::Excluded(end) => key >= end.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// True if no key can fall in the range. `BTreeMap::range` panics on
+    /// such ranges, so check this first.
+    pub fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    pub fn bounds(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        (self.start.clone(), self.end.clone())
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, or `None`
+/// if there is none (the prefix is empty or all `0xff`)
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Options for [`crate::storage::BlockDB::scan_with`]
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Iterate from the largest key down
+    pub reverse: bool,
+    /// Stop after this many records
+    pub limit: Option<usize>,
+    /// Ignore records with a higher sequence number
+    pub snapshot: Option<u64>,
+    /// Ignore records written after this time, in milliseconds since the
+    /// Unix epoch. Only a versioned database accepts it.
+    pub as_of: Option<u64>,
+}
+
+/// Merges sorted record sources into one ordered stream. When several
+/// records share a key, the one with the highest sequence number visible at
+/// the snapshot and `as_of` time wins; keys with no visible record, or
+/// whose winner is a tombstone, expired or shredded, are skipped. Each source
+/// is read lazily, one record ahead.
+pub struct DBIterator {
+    sources: Vec<RecordSource>,
+    /// Next record of each source, `None` once it is exhausted
+    heads: Vec<Option<Record>>,
+    primed: bool,
+    reverse: bool,
+    remaining: Option<usize>,
+    snapshot: Option<u64>,
+    as_of: Option<u64>,
+    /// Decrypts encrypted values
+    keys: Option<Arc<RwLock<KeyStore>>>,
+    /// Reads values separated into blob files
+    blobs: Option<BlobPin>,
+    /// Records written before this time have outlived their TTL
+    expire_before: Option<u64>,
+    done: bool,
+}
+
+impl DBIterator {
+    pub fn new(sources: Vec<RecordSource>, options: &ScanOptions) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        DBIterator {
+            sources,
+            heads,
+            primed: false,
+            reverse: options.reverse,
+            remaining: options.limit,
+            snapshot: options.snapshot,
+            as_of: options.as_of,
+            keys: None,
+            blobs: None,
+            expire_before: None,
+            done: false,
+        }
+    }
+
+    pub(crate) fn with_keys(mut self, keys: Arc<RwLock<KeyStore>>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    pub(crate) fn with_blobs(mut self, blobs: BlobPin) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+
+    pub(crate) fn expiring(mut self, expire_before: Option<u64>) -> Self {
+        self.expire_before = expire_before;
+        self
+    }
+
+    fn advance(&mut self, source: usize) -> Result<(), Box<dyn std::error::Error>> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+        if !self.primed {
+            for source in 0..self.sources.len() {
+                self.advance(source)?;
+            }
+            self.primed = true;
+        }
+
+        loop {
+            // There are only a handful of sources (memtables plus SSTables),
+            // so a linear pass is cheaper than maintaining a heap
+            let mut key: Option<&Vec<u8>> = None;
+            for record in self.heads.iter().flatten() {
+                let better = match key {
+                    None => true,
+                    Some(current) if self.reverse => record.key > *current,
+                    Some(current) => record.key < *current,
+                };
+                if better {
+                    key = Some(&record.key);
+                }
+            }
+
+            let Some(key) = key.cloned() else { return Ok(None) };
+
+            // Drain every record with this key, which may be several per
+            // source, and keep the newest one the snapshot can see
+            let mut visible: Option<Record> = None;
+            for source in 0..self.heads.len() {
+                while self.heads[source].as_ref().is_some_and(|head| head.key == key) {
+                    let record = self.heads[source].take().unwrap();
+                    self.advance(source)?;
+
+                    let in_snapshot = self.snapshot.is_none_or(|snapshot| record.sequence_number <= snapshot)
+                        && self.as_of.is_none_or(|as_of| record.timestamp <= as_of);
+                    let newer = visible.as_ref().is_none_or(|best| record.sequence_number > best.sequence_number);
+                    if in_snapshot && newer {
+                        visible = Some(record);
+                    }
+                }
+            }
+
+            let Some(mut record) = visible else { continue };
+            if record.is_tombstone() || self.expire_before.is_some_and(|expire_before| record.timestamp < expire_before) {
+                continue;
+            }
+            if let Some(blobs) = &self.blobs {
+                blobs.resolve(&mut record)?;
+            }
+            if let Some(keys) = &self.keys {
+                if !keys.read().unwrap().decrypt_record(&mut record) {
+                    continue;
+                }
+            }
+            return Ok(Some(record));
+        }
+    }
+}
+
+impl Iterator for DBIterator {
+    type Item = Result<Record, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.remaining == Some(0) {
+            return None;
+        }
+
+        match self.next_record() {
+            Ok(Some(record)) => {
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining -= 1;
+                }
+                Some(Ok(record))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
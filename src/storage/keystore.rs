@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use crate::storage::{Record, RecordKind};
+use crate::storage::encryption::{self, Keyring, PLAINTEXT_KEY_ID};
+
+const KEYSTORE_FILE: &str = "keys.log";
+const KEYSTORE_TMP_FILE: &str = "keys.log.tmp";
+/// File header: "BDBS", the format version and the id of the data key that
+/// sealed the frames. Files without it hold plaintext frames.
+const KEYSTORE_MAGIC: [u8; 4] = *b"BDBS";
+const KEYSTORE_VERSION: u32 = 1;
+const KEYSTORE_HEADER_SIZE: usize = 12;
+/// Frame header: payload length followed by its CRC32C
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// Per-key data encryption keys for crypto-shredding. Values are stored
+/// encrypted under their key's DEK, so destroying the DEK makes every
+/// version unreadable while the stored ciphertext, and every hash over it,
+/// stays as it was.
+///
+/// DEKs are appended to `keys.log` as `len | crc | (key, dek)` frames when
+/// first used; destroying one rewrites the file without it. With a keyring
+/// each frame is sealed under the data key named in the file header, bound
+/// to its offset, and the file is rewritten when that key is not current.
+pub struct KeyStore {
+    data_dir: String,
+    keys: HashMap<Vec<u8>, [u8; 32]>,
+    keyring: Option<Arc<Keyring>>,
+    file: Option<File>,
+    /// Length of the file, where the next frame goes
+    len: u64,
+}
+
+impl std::fmt::Debug for KeyStore {
+    // Never print key material
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyStore")
+            .field("data_dir", &self.data_dir)
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+impl KeyStore {
+    /// Load the DEKs in `data_dir`. The file is only created once the first
+    /// DEK is. A torn last frame is a DEK whose record never reached the
+    /// WAL, and is dropped. A file in plaintext or sealed under an older
+    /// key is resealed under the current one.
+    pub fn open(data_dir: &str, keyring: Option<Arc<Keyring>>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = format!("{}/{}", data_dir, KEYSTORE_FILE);
+        let mut store = KeyStore {
+            data_dir: data_dir.to_string(),
+            keys: HashMap::new(),
+            keyring,
+            file: None,
+            len: 0,
+        };
+        if !Path::new(&path).exists() {
+            return Ok(store);
+        }
+
+        let data = fs::read(&path)?;
+        let legacy = !data.starts_with(&KEYSTORE_MAGIC);
+        let (key_id, mut offset) = if !legacy {
+            let header = data.get(..KEYSTORE_HEADER_SIZE).ok_or_else(|| {
+                crate::error::BlockDBError::Corruption(format!("Torn header in {}", path))
+            })?;
+            let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            if version != KEYSTORE_VERSION {
+                return Err(Box::new(crate::error::BlockDBError::InvalidData(format!(
+                    "Unsupported key store version {} in {}", version, path
+                ))));
+            }
+            (u32::from_be_bytes(header[8..12].try_into().unwrap()), KEYSTORE_HEADER_SIZE)
+        } else {
+            (PLAINTEXT_KEY_ID, 0)
+        };
+        encryption::check_key(store.keyring.as_deref(), key_id, &path)?;
+
+        while offset + FRAME_HEADER_SIZE <= data.len() {
+            let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            let checksum = u32::from_be_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+            let start = offset + FRAME_HEADER_SIZE;
+            if start + size > data.len() || crc32c::crc32c(&data[start..start + size]) != checksum {
+                break;
+            }
+            let payload = encryption::unseal(store.keyring.as_deref(), key_id, data[start..start + size].to_vec(), &(offset as u64).to_be_bytes())?;
+            let (key, dek): (Vec<u8>, [u8; 32]) = bincode::deserialize(&payload)?;
+            store.keys.insert(key, dek);
+            offset = start + size;
+        }
+        if offset < data.len() {
+            OpenOptions::new().write(true).open(&path)?.set_len(offset as u64)?;
+        }
+        store.len = offset as u64;
+
+        if legacy || key_id != store.key_id() {
+            store.rewrite()?;
+        }
+        Ok(store)
+    }
+
+    /// Id of the data key new frames are sealed with
+    fn key_id(&self) -> u32 {
+        self.keyring.as_ref().map_or(PLAINTEXT_KEY_ID, |keyring| keyring.current_id())
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    /// Encrypt `value` under the DEK of `key`, creating and persisting the
+    /// DEK first if needed. Returns `nonce | ciphertext`.
+    pub fn encrypt(&mut self, key: &[u8], value: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let dek = match self.keys.get(key) {
+            Some(dek) => *dek,
+            None => self.create(key)?,
+        };
+
+        // The key is bound in as associated data so a ciphertext cannot be
+        // passed off as another key's value
+        encryption::seal_with_key(&dek, value, key)
+    }
+
+    /// Plaintext of a `nonce | ciphertext` value, or `None` if the DEK of
+    /// `key` was destroyed
+    pub fn decrypt(&self, key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let dek = self.keys.get(key)?;
+        // A DEK created after an erase cannot open older versions either
+        encryption::open_with_key(dek, sealed, key)
+    }
+
+    /// Replace the value of an encrypted record with its plaintext. Returns
+    /// false, leaving the record as stored, if it has been shredded.
+    pub fn decrypt_record(&self, record: &mut Record) -> bool {
+        if record.kind != RecordKind::Encrypted {
+            return true;
+        }
+        match self.decrypt(&record.key, &record.value) {
+            Some(value) => {
+                record.value = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Destroy the DEK of `key`, returning whether there was one. The file
+    /// is rewritten without it and synced before this returns.
+    pub fn destroy(&mut self, key: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.keys.remove(key).is_none() {
+            return Ok(false);
+        }
+
+        self.rewrite()?;
+
+        Ok(true)
+    }
+
+    fn create(&mut self, key: &[u8]) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let dek = encryption::generate_key();
+
+        if self.file.is_none() {
+            let path = format!("{}/{}", self.data_dir, KEYSTORE_FILE);
+            if !Path::new(&path).exists() {
+                self.rewrite()?;
+            }
+            self.file = Some(OpenOptions::new().append(true).open(&path)?);
+        }
+
+        // Durable before any record encrypted under it reaches the WAL
+        let file = self.file.as_mut().unwrap();
+        self.len += Self::write_frame(file, self.keyring.as_deref(), self.len, key, &dek)?;
+        file.sync_data()?;
+
+        self.keys.insert(key.to_vec(), dek);
+        Ok(dek)
+    }
+
+    /// Write every DEK to a new file sealed under the current data key and
+    /// swap it in, synced before this returns
+    fn rewrite(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_path = format!("{}/{}", self.data_dir, KEYSTORE_TMP_FILE);
+        let path = format!("{}/{}", self.data_dir, KEYSTORE_FILE);
+        let keyring = self.keyring.as_deref();
+        let mut offset = KEYSTORE_HEADER_SIZE as u64;
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(&KEYSTORE_MAGIC)?;
+            writer.write_all(&KEYSTORE_VERSION.to_be_bytes())?;
+            writer.write_all(&self.key_id().to_be_bytes())?;
+            for (key, dek) in &self.keys {
+                offset += Self::write_frame(&mut writer, keyring, offset, key, dek)?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+
+        self.file = None;
+        fs::rename(&tmp_path, &path)?;
+        File::open(&self.data_dir)?.sync_all()?;
+        self.len = offset;
+
+        Ok(())
+    }
+
+    /// Write the frame at `offset`, sealed if there is a keyring. Returns
+    /// its length.
+    fn write_frame(writer: &mut impl Write, keyring: Option<&Keyring>, offset: u64, key: &[u8], dek: &[u8; 32]) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut payload = bincode::serialize(&(key, dek))?;
+        if let Some(keyring) = keyring {
+            payload = keyring.seal(&payload, &offset.to_be_bytes())?;
+        }
+        writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+        writer.write_all(&crc32c::crc32c(&payload).to_be_bytes())?;
+        writer.write_all(&payload)?;
+        Ok((FRAME_HEADER_SIZE + payload.len()) as u64)
+    }
+
+    /// Destroy every DEK
+    pub fn clear(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.keys.clear();
+        self.file = None;
+        self.len = 0;
+        let path = format!("{}/{}", self.data_dir, KEYSTORE_FILE);
+        if Path::new(&path).exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+
+/// A live SSTable as recorded in the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SSTableEntry {
+    /// File name relative to the data directory
+    pub file_name: String,
+    pub level: usize,
+}
+
+/// Bytes of a blob file that no record references any more
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobGarbage {
+    pub file_number: u64,
+    pub bytes: u64,
+}
+
+/// Durable record of which SSTables make up the database and how much of the
+/// WAL they already cover. Rewritten atomically on every change.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Live SSTables, oldest first
+    #[serde(default)]
+    pub sstables: Vec<SSTableEntry>,
+    /// Highest sequence number persisted in an SSTable. WAL records at or
+    /// below this are already durable and are skipped on replay.
+    #[serde(default)]
+    pub last_flushed_sequence: u64,
+    /// Oldest WAL segment still needed; older segments only hold records
+    /// that are in SSTables
+    #[serde(default)]
+    pub log_number: u64,
+    /// Garbage in each blob file, as counted by compactions
+    #[serde(default)]
+    pub blob_garbage: Vec<BlobGarbage>,
+}
+
+impl Manifest {
+    /// Load the manifest from `data_dir`, returning `None` if none exists yet
+    pub fn load(data_dir: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let path = format!("{}/{}", data_dir, MANIFEST_FILE);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let manifest: Manifest = toml::from_str(&content)
+            .map_err(|e| crate::error::BlockDBError::InvalidData(format!("Failed to parse manifest: {}", e)))?;
+
+        Ok(Some(manifest))
+    }
+
+    /// Persist the manifest by writing a temporary file and renaming it over
+    /// the old one, so a crash leaves either the old or the new version
+    pub fn save(&self, data_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_path = format!("{}/{}", data_dir, MANIFEST_TMP_FILE);
+        let path = format!("{}/{}", data_dir, MANIFEST_FILE);
+
+        let content = toml::to_string(self)
+            .map_err(|e| crate::error::BlockDBError::InvalidData(format!("Failed to serialize manifest: {}", e)))?;
+
+        {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, &path)?;
+
+        // Make the rename itself durable
+        File::open(data_dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Blob garbage by file number
+    pub fn blob_garbage(&self) -> BTreeMap<u64, u64> {
+        self.blob_garbage.iter().map(|entry| (entry.file_number, entry.bytes)).collect()
+    }
+
+    pub fn set_blob_garbage(&mut self, garbage: &BTreeMap<u64, u64>) {
+        self.blob_garbage = garbage
+            .iter()
+            .map(|(&file_number, &bytes)| BlobGarbage { file_number, bytes })
+            .collect();
+    }
+
+    pub fn add_sstable(&mut self, file_name: String, level: usize) {
+        self.sstables.push(SSTableEntry { file_name, level });
+    }
+
+    pub fn contains(&self, file_name: &str) -> bool {
+        self.sstables.iter().any(|entry| entry.file_name == file_name)
+    }
+
+    /// Position of a file in the oldest-first ordering
+    pub fn position(&self, file_name: &str) -> Option<usize> {
+        self.sstables.iter().position(|entry| entry.file_name == file_name)
+    }
+
+    /// Replace compacted inputs with their merged outputs, given in key
+    /// order. Deeper levels hold older data, so the outputs go after every
+    /// file at their level or below.
+    pub fn apply_compaction(&mut self, inputs: &[String], outputs: &[String], level: usize) {
+        self.sstables.retain(|entry| !inputs.contains(&entry.file_name));
+
+        let position = self.sstables
+            .iter()
+            .rposition(|entry| entry.level >= level)
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        self.sstables.splice(
+            position..position,
+            outputs.iter().map(|file_name| SSTableEntry { file_name: file_name.clone(), level }),
+        );
+    }
+}
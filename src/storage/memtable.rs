@@ -1,10 +1,28 @@
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
 use crate::storage::Record;
+use crate::storage::iterator::KeyRange;
+
+/// Key and sequence number of one version. Versions of a key sort newest
+/// first, the order SSTables store them in.
+type VersionKey = (Vec<u8>, Reverse<u64>);
 
 #[derive(Debug)]
 pub struct MemTable {
-    data: BTreeMap<Vec<u8>, Record>,
+    data: BTreeMap<VersionKey, Record>,
     size: usize,
+    max_sequence: u64,
+    /// First WAL segment holding none of this memtable's records; set when
+    /// it is swapped out
+    next_log_number: u64,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemTable {
@@ -12,12 +30,15 @@ impl MemTable {
         MemTable {
             data: BTreeMap::new(),
             size: 0,
+            max_sequence: 0,
+            next_log_number: 0,
         }
     }
 
     pub fn insert(&mut self, record: Record) {
-        let key = record.key.clone();
+        let key = (record.key.clone(), Reverse(record.sequence_number));
         let record_size = self.calculate_record_size(&record);
+        self.max_sequence = self.max_sequence.max(record.sequence_number);
         
         if let Some(old_record) = self.data.insert(key, record) {
             self.size -= self.calculate_record_size(&old_record);
@@ -26,8 +47,24 @@ impl MemTable {
         self.size += record_size;
     }
 
+    /// Newest version of `key`
     pub fn get(&self, key: &[u8]) -> Option<&Record> {
-        self.data.get(key)
+        self.get_at(key, u64::MAX)
+    }
+
+    /// Newest version of `key` with a sequence number at or below `sequence`
+    pub fn get_at(&self, key: &[u8], sequence: u64) -> Option<&Record> {
+        self.data
+            .range((key.to_vec(), Reverse(sequence))..=(key.to_vec(), Reverse(0)))
+            .next()
+            .map(|(_, record)| record)
+    }
+
+    /// Every version of `key`, newest first
+    pub fn versions(&self, key: &[u8]) -> impl Iterator<Item = &Record> {
+        self.data
+            .range((key.to_vec(), Reverse(u64::MAX))..=(key.to_vec(), Reverse(0)))
+            .map(|(_, record)| record)
     }
 
     pub fn size(&self) -> usize {
@@ -38,18 +75,35 @@ impl MemTable {
         self.data.is_empty()
     }
 
+    /// Every version in key order, newest first within a key
     pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Record)> {
-        self.data.iter()
+        self.data.iter().map(|((key, _), record)| (key, record))
     }
 
+    /// Distinct keys in order
     pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
-        self.data.keys()
+        let mut previous: Option<&Vec<u8>> = None;
+        self.data.keys().map(|(key, _)| key).filter(move |key| {
+            let distinct = previous != Some(*key);
+            previous = Some(*key);
+            distinct
+        })
     }
 
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
+    /// Highest sequence number inserted so far
+    pub fn max_sequence(&self) -> u64 {
+        self.max_sequence
+    }
+
+    /// WAL segments before this one can go once the memtable is flushed
+    pub fn next_log_number(&self) -> u64 {
+        self.next_log_number
+    }
+
     fn calculate_record_size(&self, record: &Record) -> usize {
         record.key.len() + 
         record.value.len() + 
@@ -62,17 +116,143 @@ impl MemTable {
     pub fn clear(&mut self) {
         self.data.clear();
         self.size = 0;
+        self.max_sequence = 0;
     }
 
     pub fn range(&self, start: &[u8], end: &[u8]) -> impl Iterator<Item = (&Vec<u8>, &Record)> {
-        self.data.range(start.to_vec()..end.to_vec())
+        self.data
+            .range((start.to_vec(), Reverse(u64::MAX))..(end.to_vec(), Reverse(u64::MAX)))
+            .map(|((key, _), record)| (key, record))
+    }
+
+    /// First version in `range` that comes after `after` (the key and
+    /// sequence number of the previous one returned), or the last one
+    /// before it when `reverse` is set
+    pub fn next_in(&self, range: &KeyRange, after: Option<(&[u8], u64)>, reverse: bool) -> Option<&Record> {
+        if range.is_empty() {
+            return None;
+        }
+
+        // Extend each key bound over all versions of that key
+        let mut start = match &range.start {
+            Bound::Included(key) => Bound::Included((key.clone(), Reverse(u64::MAX))),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), Reverse(0))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut end = match &range.end {
+            Bound::Included(key) => Bound::Included((key.clone(), Reverse(0))),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), Reverse(u64::MAX))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        if let Some((key, sequence)) = after {
+            let position = Bound::Excluded((key.to_vec(), Reverse(sequence)));
+            if reverse {
+                end = position;
+            } else {
+                start = position;
+            }
+        }
+
+        let empty = match (&start, &end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+        if empty {
+            return None;
+        }
+
+        let mut records = self.data.range((start, end)).map(|(_, record)| record);
+        if reverse {
+            records.next_back()
+        } else {
+            records.next()
+        }
     }
 
+    /// Newest version of the greatest key starting with `prefix`
     pub fn get_latest_by_prefix(&self, prefix: &[u8]) -> Option<&Record> {
-        self.data
-            .range(prefix.to_vec()..)
-            .take_while(|(key, _)| key.starts_with(prefix))
-            .last()
-            .map(|(_, record)| record)
+        let ((key, _), _) = self.data
+            .range((prefix.to_vec(), Reverse(u64::MAX))..)
+            .take_while(|((key, _), _)| key.starts_with(prefix))
+            .last()?;
+        self.get(key)
     }
-}
\ No newline at end of file
+}
+
+/// Memtables whose records are not in an installed SSTable yet: the active
+/// one taking writes plus the swapped out ones queued for the background
+/// flush
+#[derive(Debug)]
+pub struct MemTableSet {
+    pub active: Arc<RwLock<MemTable>>,
+    /// Oldest first, the order they are flushed in
+    pub immutable: Vec<Arc<RwLock<MemTable>>>,
+}
+
+impl MemTableSet {
+    pub fn new() -> Self {
+        MemTableSet {
+            active: Arc::new(RwLock::new(MemTable::new())),
+            immutable: Vec::new(),
+        }
+    }
+
+    /// Replace the active memtable with an empty one and return the old
+    /// one, which stays readable until [`Self::remove_immutable`]. Records
+    /// written from now on go to WAL segment `next_log_number` onwards.
+    pub fn swap(&mut self, next_log_number: u64) -> Arc<RwLock<MemTable>> {
+        let old = std::mem::replace(&mut self.active, Arc::new(RwLock::new(MemTable::new())));
+        old.write().unwrap().next_log_number = next_log_number;
+        self.immutable.push(old.clone());
+        old
+    }
+
+    /// Drop a swapped out memtable once its SSTable is installed
+    pub fn remove_immutable(&mut self, memtable: &Arc<RwLock<MemTable>>) {
+        self.immutable.retain(|candidate| !Arc::ptr_eq(candidate, memtable));
+    }
+
+    /// Every memtable, newest first
+    pub fn newest_first(&self) -> Vec<Arc<RwLock<MemTable>>> {
+        std::iter::once(self.active.clone())
+            .chain(self.immutable.iter().rev().cloned())
+            .collect()
+    }
+}
+
+impl Default for MemTableSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cursor over a memtable in key order, yielding every version. The lock
+/// is only held while fetching each record, so writers are never blocked
+/// by a scan; records inserted ahead of the cursor are picked up as it
+/// reaches them.
+pub struct MemTableIter {
+    memtable: Arc<RwLock<MemTable>>,
+    range: KeyRange,
+    reverse: bool,
+    /// Key and sequence number of the last record returned
+    position: Option<(Vec<u8>, u64)>,
+}
+
+impl MemTableIter {
+    pub fn new(memtable: Arc<RwLock<MemTable>>, range: KeyRange, reverse: bool) -> Self {
+        MemTableIter { memtable, range, reverse, position: None }
+    }
+}
+
+impl Iterator for MemTableIter {
+    type Item = Result<Record, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let after = self.position.as_ref().map(|(key, sequence)| (key.as_slice(), *sequence));
+        let record = self.memtable.read().unwrap().next_in(&self.range, after, self.reverse)?.clone();
+
+        self.position = Some((record.key.clone(), record.sequence_number));
+        Some(Ok(record))
+    }
+}
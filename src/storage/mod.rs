@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Seek, SeekFrom};
 use std::path::Path;
@@ -13,6 +13,30 @@ pub mod sstable;
 pub mod blockchain;
 pub mod compaction;
 pub mod collection;
+pub mod manifest;
+pub mod bloom;
+pub mod iterator;
+pub mod snapshot;
+pub mod write_batch;
+pub mod keystore;
+pub mod compression;
+pub mod encryption;
+pub mod cache;
+pub mod blob;
+pub mod write_stall;
+pub mod verify;
+pub mod proof;
+pub mod record_index;
+
+pub use iterator::{DBIterator, KeyRange, ScanOptions};
+pub use snapshot::Snapshot;
+pub use write_batch::WriteBatch;
+pub use compression::Compression;
+pub use encryption::MasterKeySource;
+pub use cache::CacheStats;
+pub use write_stall::WriteStallStats;
+pub use verify::{Tamper, TamperKind, VerifyReport};
+pub use proof::{verify_proof, InclusionProof};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Record {
@@ -21,15 +45,162 @@ pub struct Record {
     pub timestamp: u64,
     pub sequence_number: u64,
     pub hash: Vec<u8>,
+    pub kind: RecordKind,
+}
+
+/// What a record's value holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordKind {
+    /// The value as written
+    #[default]
+    Value,
+    /// `nonce | ciphertext` under the key's data encryption key
+    Encrypted,
+    /// The key was deleted; the value is empty
+    Tombstone,
+    /// Kept only in the blockchain: the record with this key and sequence
+    /// number outlived its TTL and was dropped. The value is its hash.
+    Expired,
+    /// The value was separated into a blob file; the value is its
+    /// [`blob::BlobPointer`]
+    Blob,
+    /// Kept only in the blockchain, in place of a separated value; the
+    /// value is its [`blob::ValueDigest`]
+    BlobDigest,
+}
+
+impl Record {
+    pub fn is_tombstone(&self) -> bool {
+        self.kind == RecordKind::Tombstone
+    }
+
+    /// SHA-256 over the key, value, timestamp and sequence number, and a
+    /// tag for the kind. A blob record must be turned back into its sealed
+    /// form first (see [`blob::BlobStore::sealed`]): it keeps the hash of
+    /// the digest the chain holds.
+    pub fn compute_hash(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.key);
+        hasher.update(&self.value);
+        hasher.update(self.timestamp.to_be_bytes());
+        hasher.update(self.sequence_number.to_be_bytes());
+        // Plain values hash as they did before kinds existed
+        match self.kind {
+            RecordKind::Value => {}
+            RecordKind::Encrypted => hasher.update([1u8]),
+            RecordKind::Tombstone => hasher.update([2u8]),
+            RecordKind::Expired => hasher.update([3u8]),
+            RecordKind::Blob => hasher.update([4u8]),
+            RecordKind::BlobDigest => hasher.update([5u8]),
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
+/// Record layout from before `kind` existed, found in version 3 WAL
+/// segments, version 2 SSTables and older blockchain files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordV1 {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub timestamp: u64,
+    pub sequence_number: u64,
+    pub hash: Vec<u8>,
+}
+
+impl From<RecordV1> for Record {
+    fn from(record: RecordV1) -> Self {
+        Record {
+            key: record.key,
+            value: record.value,
+            timestamp: record.timestamp,
+            sequence_number: record.sequence_number,
+            hash: record.hash,
+            kind: RecordKind::Value,
+        }
+    }
+}
+
+/// Proof of a stored write, returned by `put` and `write_batch`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteReceipt {
+    pub sequence_number: u64,
+    pub timestamp: u64,
+    /// SHA-256 of the record, as kept in the blockchain
+    pub hash: Vec<u8>,
+    /// Blockchain block the record is sealed into; it may still be pending
+    pub block_index: u64,
+}
+
+impl WriteReceipt {
+    pub fn hash_hex(&self) -> String {
+        self.hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct BlockDBConfig {
     pub data_dir: String,
+    /// Size at which the active memtable is swapped out and queued for the
+    /// background flush
     pub memtable_size_limit: usize,
+    /// Writes are slowed down once this many memtables are queued for flushing
+    pub max_immutable_memtables: usize,
+    /// Writes are slowed down once level 0 holds this many SSTables
+    pub level0_slowdown_trigger: usize,
+    /// How long each write is delayed while slowed down; 0 disables throttling
+    pub write_stall_delay_ms: u64,
     pub wal_sync_interval_ms: u64,
     pub compaction_threshold: usize,
+    /// Compaction starts a new output SSTable once the current one reaches
+    /// this many bytes; it also sizes the levels below level 0
+    pub sstable_target_size: u64,
+    /// A block is sealed once this many records are queued for it
     pub blockchain_batch_size: usize,
+    /// A block is sealed once its queued keys, values and hashes reach this
+    /// many bytes
+    pub blockchain_max_block_bytes: usize,
+    /// A partial block is sealed once its oldest record has waited this
+    /// long, bounding how long a write goes unanchored; 0 disables the timer
+    pub blockchain_max_block_age_ms: u64,
+    /// Bloom filter bits per key in new SSTables; 0 disables filters
+    pub bloom_bits_per_key: usize,
+    /// Target size of SSTable data blocks in bytes
+    pub sstable_block_size: usize,
+    /// Bytes of decoded SSTable blocks kept in memory; 0 disables the cache
+    pub block_cache_size: usize,
+    /// Whether a WAL ending in a torn or corrupt record is truncated or refused
+    pub wal_recovery_mode: wal::WalRecoveryMode,
+    /// How far each commit batch is persisted before writers return. With
+    /// anything weaker than `Fsync` the WAL is synced every
+    /// `wal_sync_interval_ms`.
+    pub wal_durability: wal::WalDurability,
+    /// Keep every version of a key: a put on an existing key appends a new
+    /// version instead of failing with `DuplicateKey`. Time-travel reads
+    /// need it, as compaction otherwise drops superseded versions.
+    pub versioned: bool,
+    /// Encrypt each value under a per-key data encryption key so `erase`
+    /// can make a key's values unrecoverable without touching the chain
+    pub crypto_shredding: bool,
+    /// Hide records older than this from reads and drop them in compaction
+    pub ttl_seconds: Option<u64>,
+    /// Codec for new SSTable blocks and WAL frames. Files keep the codec
+    /// they were written with, so it can be changed between opens.
+    pub compression: Compression,
+    /// Values at least this many bytes long are written once to a blob
+    /// file and referenced from the WAL and SSTables by a pointer; the
+    /// blockchain seals their SHA-256 digest. `None` keeps every value
+    /// inline.
+    pub blob_threshold: Option<usize>,
+    /// A new blob file is started once the current one reaches this size
+    pub blob_file_size: u64,
+    /// Fraction of a blob file that must be unreferenced before compaction
+    /// moves its live values out so the file can be deleted
+    pub blob_gc_ratio: f64,
+    /// Master key wrapping the data keys that seal the WAL, SSTables and
+    /// blockchain. `None` writes plaintext; a directory that has been
+    /// encrypted cannot be opened without it.
+    pub master_key: Option<MasterKeySource>,
     pub auth_enabled: bool,
     pub session_duration_hours: u64,
     pub password_min_length: usize,
@@ -42,9 +213,28 @@ impl Default for BlockDBConfig {
         Self {
             data_dir: "./blockdb_data".to_string(),
             memtable_size_limit: 64 * 1024 * 1024, // 64MB
+            max_immutable_memtables: 4,
+            level0_slowdown_trigger: 12,
+            write_stall_delay_ms: 1,
             wal_sync_interval_ms: 1000, // 1 second
             compaction_threshold: 4,
+            sstable_target_size: 2 * 1024 * 1024, // 2MB
             blockchain_batch_size: 1000,
+            blockchain_max_block_bytes: 4 * 1024 * 1024, // 4MB
+            blockchain_max_block_age_ms: 1000, // 1 second
+            bloom_bits_per_key: 10, // ~1% false positives
+            sstable_block_size: 4 * 1024, // 4KB
+            block_cache_size: 8 * 1024 * 1024, // 8MB
+            wal_recovery_mode: wal::WalRecoveryMode::TolerateTornTail,
+            wal_durability: wal::WalDurability::Fsync,
+            versioned: false,
+            crypto_shredding: false,
+            ttl_seconds: None,
+            compression: Compression::None,
+            blob_threshold: None,
+            blob_file_size: 256 * 1024 * 1024, // 256MB
+            blob_gc_ratio: 0.5,
+            master_key: None,
             auth_enabled: true,
             session_duration_hours: 24,
             password_min_length: 8,
@@ -54,76 +244,473 @@ impl Default for BlockDBConfig {
     }
 }
 
+#[derive(Debug)]
 pub struct BlockDB {
     config: BlockDBConfig,
-    memtable: Arc<RwLock<memtable::MemTable>>,
+    memtables: Arc<RwLock<memtable::MemTableSet>>,
     wal: Arc<Mutex<wal::WriteAheadLog>>,
+    group_commit: wal::GroupCommit,
     sstables: Arc<RwLock<Vec<sstable::SSTable>>>,
+    manifest: Arc<Mutex<manifest::Manifest>>,
+    compactor: Arc<Mutex<compaction::Compactor>>,
+    snapshots: Arc<Mutex<snapshot::SnapshotList>>,
     blockchain: Arc<Mutex<blockchain::BlockChain>>,
+    keys: Arc<RwLock<keystore::KeyStore>>,
+    /// Data keys for encryption at rest, if enabled
+    keyring: Option<Arc<encryption::Keyring>>,
+    /// Shared by every SSTable this instance opens
+    block_cache: Arc<cache::BlockCache>,
+    /// Values separated from their records
+    blobs: Arc<blob::BlobStore>,
+    /// Held while writing memtables to SSTables
+    flush_lock: Arc<Mutex<()>>,
+    write_controller: write_stall::WriteController,
     sequence_counter: Arc<Mutex<u64>>,
+    /// Bytes truncated from the WAL tail when this instance was opened
+    wal_discarded_bytes: u64,
+    /// Wakes the flush worker; dropping it shuts the worker down
+    flush_trigger: Option<crossbeam::channel::Sender<()>>,
+    flush_worker: Option<std::thread::JoinHandle<()>>,
+    /// Wakes the compaction worker; dropping it shuts the worker down
+    compaction_trigger: Option<crossbeam::channel::Sender<()>>,
+    compaction_worker: Option<std::thread::JoinHandle<()>>,
+    /// Dropping it stops the periodic WAL sync
+    wal_sync_shutdown: Option<crossbeam::channel::Sender<()>>,
+    wal_sync_worker: Option<std::thread::JoinHandle<()>>,
+    seal_shutdown: Option<crossbeam::channel::Sender<()>>,
+    seal_worker: Option<std::thread::JoinHandle<()>>,
 }
 
+/// Block each sealed record hash is in
+type SealedRecords = HashMap<Vec<u8>, u64>;
+
+/// Key, value and kind of a batch entry, and where its value was separated to
+type BatchEntry = (Vec<u8>, Vec<u8>, RecordKind, Option<blob::BlobPointer>);
+
 impl BlockDB {
     pub fn new(config: BlockDBConfig) -> Result<Self, Box<dyn std::error::Error>> {
         std::fs::create_dir_all(&config.data_dir)?;
         
-        let memtable = Arc::new(RwLock::new(memtable::MemTable::new()));
-        let wal = Arc::new(Mutex::new(wal::WriteAheadLog::new(&config.data_dir)?));
-        let sstables = Arc::new(RwLock::new(Vec::new()));
-        let blockchain = Arc::new(Mutex::new(blockchain::BlockChain::new(&config.data_dir)?));
-        let sequence_counter = Arc::new(Mutex::new(0));
+        let keyring = encryption::Keyring::open(&config.data_dir, config.master_key.as_ref())?;
+        let block_cache = Arc::new(cache::BlockCache::new(config.block_cache_size));
+        let options = sstable::SSTableOptions {
+            keyring: keyring.clone(),
+            block_cache: Some(block_cache.clone()),
+            ..sstable::SSTableOptions::from_config(&config)
+        };
+        let manifest = Self::load_manifest(&config.data_dir)?;
+        let mut live_sstables = Vec::with_capacity(manifest.sstables.len());
+        let mut compactor = compaction::Compactor::new(&config.data_dir, config.compaction_threshold, config.sstable_target_size);
+        for entry in &manifest.sstables {
+            let path = format!("{}/{}", config.data_dir, entry.file_name);
+            let sstable = sstable::SSTable::open_with(&path, &options)?;
+            compactor.add_sstable(entry.file_name.clone(), entry.level, compaction::KeySpan::of(&sstable));
+            live_sstables.push(sstable);
+        }
+
+        // Opened even without a threshold: earlier records may point into it
+        let blobs = Arc::new(blob::BlobStore::open(
+            &config.data_dir,
+            config.compression,
+            keyring.clone(),
+            config.blob_file_size,
+            config.blob_gc_ratio,
+            manifest.blob_garbage(),
+        )?);
 
-        let db = BlockDB {
+        let memtables = Arc::new(RwLock::new(memtable::MemTableSet::new()));
+        let wal = Arc::new(Mutex::new(wal::WriteAheadLog::new(&config.data_dir, config.compression, keyring.clone())?));
+        let sstables = Arc::new(RwLock::new(live_sstables));
+        let blockchain = Arc::new(Mutex::new(blockchain::BlockChain::new(
+            &config.data_dir,
+            keyring.clone(),
+            blockchain::SealingPolicy::from_config(&config),
+        )?));
+        let keys = Arc::new(RwLock::new(keystore::KeyStore::open(&config.data_dir, keyring.clone())?));
+        let sequence_counter = Arc::new(Mutex::new(manifest.last_flushed_sequence));
+        let manifest = Arc::new(Mutex::new(manifest));
+        let compactor = Arc::new(Mutex::new(compactor));
+        let write_controller = write_stall::WriteController::new(
+            config.max_immutable_memtables,
+            config.level0_slowdown_trigger,
+            std::time::Duration::from_millis(config.write_stall_delay_ms),
+        );
+
+        let mut db = BlockDB {
             config,
-            memtable,
+            memtables,
             wal,
+            group_commit: wal::GroupCommit::default(),
             sstables,
+            manifest,
+            compactor,
+            snapshots: Arc::new(Mutex::new(snapshot::SnapshotList::default())),
             blockchain,
+            keys,
+            keyring,
+            block_cache,
+            blobs,
+            flush_lock: Arc::new(Mutex::new(())),
+            write_controller,
             sequence_counter,
+            wal_discarded_bytes: 0,
+            flush_trigger: None,
+            flush_worker: None,
+            compaction_trigger: None,
+            compaction_worker: None,
+            wal_sync_shutdown: None,
+            wal_sync_worker: None,
+            seal_shutdown: None,
+            seal_worker: None,
         };
         
         // Recover from WAL on startup
         db.recover_from_wal()?;
+        db.group_commit.state.lock().unwrap().durable_sequence = *db.sequence_counter.lock().unwrap();
+
+        db.start_compaction_worker()?;
+        db.start_flush_worker()?;
+        db.start_wal_sync_worker()?;
+        db.start_seal_worker()?;
         
         Ok(db)
     }
+
+    /// Periodically sync the WAL when commits do not fsync themselves
+    fn start_wal_sync_worker(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.wal_durability == wal::WalDurability::Fsync {
+            return Ok(());
+        }
+
+        let (shutdown, stopped) = crossbeam::channel::bounded::<()>(0);
+        let interval = std::time::Duration::from_millis(self.config.wal_sync_interval_ms.max(1));
+        let wal = self.wal.clone();
+        let blobs = self.blobs.clone();
+
+        let worker = std::thread::Builder::new()
+            .name("blockdb-wal-sync".to_string())
+            .spawn(move || {
+                while let Err(crossbeam::channel::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // Blobs first, so no synced record points at a lost value
+                    if let Err(e) = blobs.sync().and_then(|_| wal.lock().unwrap().sync()) {
+                        println!("⚠️ WAL sync failed: {}", e);
+                    }
+                }
+            })?;
+
+        self.wal_sync_shutdown = Some(shutdown);
+        self.wal_sync_worker = Some(worker);
+        Ok(())
+    }
+
+    /// Seal partial blocks once their oldest record reaches the maximum
+    /// block age
+    fn start_seal_worker(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let max_age = match self.config.blockchain_max_block_age_ms {
+            0 => return Ok(()),
+            ms => std::time::Duration::from_millis(ms),
+        };
+
+        let (shutdown, stopped) = crossbeam::channel::bounded::<()>(0);
+        let blockchain = self.blockchain.clone();
+
+        let worker = std::thread::Builder::new()
+            .name("blockdb-seal".to_string())
+            .spawn(move || loop {
+                // Sleep until the queued records are due, or a full age
+                // when nothing is queued
+                let wait = match blockchain.lock().unwrap().seal_if_due() {
+                    Ok(remaining) => remaining.unwrap_or(max_age),
+                    Err(e) => {
+                        println!("⚠️ Sealing block failed: {}", e);
+                        max_age
+                    }
+                };
+                if !matches!(stopped.recv_timeout(wait), Err(crossbeam::channel::RecvTimeoutError::Timeout)) {
+                    break;
+                }
+            })?;
+
+        self.seal_shutdown = Some(shutdown);
+        self.seal_worker = Some(worker);
+        Ok(())
+    }
+
+    /// Flush swapped out memtables in the background, scheduling a
+    /// compaction after each run
+    fn start_flush_worker(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (trigger, signals) = crossbeam::channel::unbounded::<()>();
+
+        let config = self.config.clone();
+        let options = self.sstable_options();
+        let flush_lock = self.flush_lock.clone();
+        let memtables = self.memtables.clone();
+        let wal = self.wal.clone();
+        let manifest = self.manifest.clone();
+        let sstables = self.sstables.clone();
+        let compactor = self.compactor.clone();
+        let blobs = self.blobs.clone();
+        let blockchain = self.blockchain.clone();
+        let compaction_trigger = self.compaction_trigger.clone();
+
+        let worker = std::thread::Builder::new()
+            .name("blockdb-flush".to_string())
+            .spawn(move || {
+                // Exits once every trigger has been dropped
+                while signals.recv().is_ok() {
+                    // One run flushes every memtable queued so far
+                    while signals.try_recv().is_ok() {}
+
+                    match Self::run_flushes(&config, &options, &flush_lock, &memtables, &wal, &manifest, &sstables, &compactor, &blobs, &blockchain) {
+                        Ok(true) => {
+                            if let Some(trigger) = &compaction_trigger {
+                                let _ = trigger.send(());
+                            }
+                        }
+                        Ok(false) => {}
+                        Err(e) => println!("⚠️ Memtable flush failed: {}", e),
+                    }
+                }
+            })?;
+
+        self.flush_trigger = Some(trigger);
+        self.flush_worker = Some(worker);
+        Ok(())
+    }
+
+    fn start_compaction_worker(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (trigger, signals) = crossbeam::channel::unbounded::<()>();
+
+        let config = self.config.clone();
+        let options = self.sstable_options();
+        let compactor = self.compactor.clone();
+        let manifest = self.manifest.clone();
+        let sstables = self.sstables.clone();
+        let snapshots = self.snapshots.clone();
+        let blockchain = self.blockchain.clone();
+        let blobs = self.blobs.clone();
+
+        let worker = std::thread::Builder::new()
+            .name("blockdb-compaction".to_string())
+            .spawn(move || {
+                // Exits once every trigger has been dropped
+                while signals.recv().is_ok() {
+                    // Coalesce triggers that queued up during the last run
+                    while signals.try_recv().is_ok() {}
+
+                    if let Err(e) = Self::run_compactions(&config, &options, &compactor, &manifest, &sstables, &snapshots, &blockchain, &blobs) {
+                        println!("⚠️ Compaction failed: {}", e);
+                    }
+                }
+            })?;
+
+        // Catch up on any backlog left by the previous session
+        let _ = trigger.send(());
+
+        self.compaction_trigger = Some(trigger);
+        self.compaction_worker = Some(worker);
+        Ok(())
+    }
+
+    /// Merge levels until none is over its limit. Each merge is installed by
+    /// swapping the manifest and the SSTable list under their locks, so
+    /// readers see either the inputs or the output, never neither. Records
+    /// dropped for their TTL are then noted in the blockchain, and blob
+    /// files left holding only garbage are deleted.
+    #[allow(clippy::too_many_arguments)]
+    fn run_compactions(
+        config: &BlockDBConfig,
+        options: &sstable::SSTableOptions,
+        compactor: &Mutex<compaction::Compactor>,
+        manifest: &Mutex<manifest::Manifest>,
+        sstables: &RwLock<Vec<sstable::SSTable>>,
+        snapshots: &Mutex<snapshot::SnapshotList>,
+        blockchain: &Mutex<blockchain::BlockChain>,
+        blobs: &blob::BlobStore,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let data_dir = config.data_dir.as_str();
+        loop {
+            let job = match compactor.lock().unwrap().pick_compaction() {
+                Some(job) => job,
+                None => return Ok(()),
+            };
+
+            // Snapshots taken after this point see only the newest version
+            // of each key, which the merge always keeps
+            let retention = compaction::Retention {
+                snapshots: snapshots.lock().unwrap().sequences(),
+                keep_history: config.versioned,
+                expire_before: Self::expire_before(config),
+            };
+            let compaction::MergeOutput { files: outputs, expired, blob_garbage } =
+                compaction::Compactor::merge(data_dir, &job, options, &retention, Some(blobs))?;
+            let output_sstables = outputs
+                .iter()
+                .map(|output| sstable::SSTable::open_with(&format!("{}/{}", data_dir, output.file_name), options))
+                .collect::<Result<Vec<_>, _>>()?;
+            let output_names: Vec<String> = outputs.iter().map(|output| output.file_name.clone()).collect();
+
+            {
+                let mut manifest = manifest.lock().unwrap();
+
+                // The inputs disappeared while merging (flush_all); drop the result
+                if !job.inputs.iter().all(|file_name| manifest.contains(file_name)) {
+                    for file_name in &output_names {
+                        let _ = std::fs::remove_file(format!("{}/{}", data_dir, file_name));
+                    }
+                    continue;
+                }
+
+                manifest.apply_compaction(&job.inputs, &output_names, job.level + 1);
+                blobs.add_garbage(&blob_garbage);
+                manifest.set_blob_garbage(&blobs.garbage());
+                manifest.save(data_dir)?;
+
+                let mut sstables = sstables.write().unwrap();
+                sstables.retain(|sstable| !job.inputs.iter().any(|input| sstable.file_name() == input));
+                sstables.extend(output_sstables);
+                sstables.sort_by_key(|sstable| manifest.position(sstable.file_name()));
+            }
+
+            if !expired.is_empty() {
+                let timestamp = now_millis();
+                let markers = expired
+                    .into_iter()
+                    .map(|record| Self::create_record(record.key, record.hash, RecordKind::Expired, timestamp, record.sequence_number))
+                    .collect();
+                blockchain.lock().unwrap().add_records(markers)?;
+            }
+
+            compactor.lock().unwrap().complete(&job, &outputs);
+            compaction::Compactor::remove_inputs(data_dir, &job)?;
+
+            if blobs.remove_dead_files()? {
+                let mut manifest = manifest.lock().unwrap();
+                manifest.set_blob_garbage(&blobs.garbage());
+                manifest.save(data_dir)?;
+            }
+        }
+    }
+
+    /// Options for new SSTables, sealed if encryption is enabled and read
+    /// through the block cache
+    fn sstable_options(&self) -> sstable::SSTableOptions {
+        sstable::SSTableOptions {
+            keyring: self.keyring.clone(),
+            block_cache: Some(self.block_cache.clone()),
+            ..sstable::SSTableOptions::from_config(&self.config)
+        }
+    }
+
+    /// Records written before this time have outlived the TTL
+    fn expire_before(config: &BlockDBConfig) -> Option<u64> {
+        config.ttl_seconds.map(|ttl| now_millis().saturating_sub(ttl.saturating_mul(1000)))
+    }
+
+    fn is_expired(&self, record: &Record) -> bool {
+        Self::expire_before(&self.config).is_some_and(|expire_before| record.timestamp < expire_before)
+    }
+
+    fn schedule_compaction(&self) {
+        if let Some(trigger) = &self.compaction_trigger {
+            let _ = trigger.send(());
+        }
+    }
+
+    /// Load the manifest and remove SSTable files it does not reference,
+    /// which are left behind when a crash interrupts a flush
+    fn load_manifest(data_dir: &str) -> Result<manifest::Manifest, Box<dyn std::error::Error>> {
+        let manifest = match manifest::Manifest::load(data_dir)? {
+            Some(manifest) => manifest,
+            // Data directories written before the manifest existed never
+            // truncated the WAL, so it still holds every record
+            None => return Ok(manifest::Manifest::default()),
+        };
+
+        for entry in std::fs::read_dir(data_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.ends_with(".sst") && !manifest.contains(&file_name) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(manifest)
+    }
     
-    fn recover_from_wal(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let wal = self.wal.lock().unwrap();
-        let records = wal.recover()?;
+    fn recover_from_wal(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let log_number = self.manifest.lock().unwrap().log_number;
+        let recovery = self.wal.lock().unwrap().recover(self.config.wal_recovery_mode, log_number)?;
+        let records = recovery.records;
+        if recovery.discarded_bytes > 0 {
+            println!("⚠️ Discarded {} bytes of torn WAL tail in {}", recovery.discarded_bytes, self.config.data_dir);
+        }
+        self.wal_discarded_bytes = recovery.discarded_bytes;
+
+        let last_flushed_sequence = self.manifest.lock().unwrap().last_flushed_sequence;
+
+        // The chain's queue of pending records is not persisted, so writes
+        // after the last sealed block are sealed again from the WAL
+        let sealed_sequence = {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            let sealed_sequence = blockchain.sealed_sequence();
+            let mut unsealed: Vec<Record> = records.iter()
+                .filter(|record| record.sequence_number > sealed_sequence)
+                .cloned()
+                .collect();
+            // Sealed in the form their hashes cover, as when first written
+            for record in &mut unsealed {
+                *record = self.blobs.sealed(record)?;
+            }
+            if !unsealed.is_empty() {
+                unsealed.sort_by_key(|record| record.sequence_number);
+                let count = unsealed.len();
+                blockchain.add_records(unsealed)?;
+                blockchain.force_create_block()?;
+                println!("✅ Sealed {} records recovered from the WAL into the blockchain", count);
+            }
+            blockchain.sealed_sequence()
+        };
         
+        let mut max_sequence = last_flushed_sequence;
         if !records.is_empty() {
-            let mut memtable = self.memtable.write().unwrap();
-            let mut max_sequence = 0u64;
+            let memtables = self.memtables.read().unwrap();
+            let mut memtable = memtables.active.write().unwrap();
             
             for record in records {
+                // Already persisted in an SSTable; the WAL was not truncated
+                // before the last shutdown
+                if record.sequence_number <= last_flushed_sequence {
+                    continue;
+                }
                 max_sequence = max_sequence.max(record.sequence_number);
                 memtable.insert(record);
             }
-            
-            // Update sequence counter
-            let mut counter = self.sequence_counter.lock().unwrap();
-            *counter = max_sequence;
         }
+
+        // Without fsync on every commit a block can outlive the WAL tail
+        // its records were in, and their sequence numbers must not be reused
+        let mut counter = self.sequence_counter.lock().unwrap();
+        *counter = max_sequence.max(sealed_sequence);
         
         Ok(())
     }
 
     fn key_exists(&self, key: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
-        // Check memtable first
-        {
-            let memtable = self.memtable.read().unwrap();
-            if memtable.get(key).is_some() {
-                return Ok(true);
+        // Check memtables first
+        for memtable in self.memtables.read().unwrap().newest_first() {
+            if let Some(record) = memtable.read().unwrap().get(key) {
+                return Ok(!record.is_tombstone() && !self.is_expired(record));
             }
         }
 
-        // Check SSTables
+        // Most puts are new keys, which each table's Bloom filter rules
+        // out without reading the file
         {
-            let mut sstables = self.sstables.write().unwrap();
-            for sstable in sstables.iter_mut().rev() {
-                if sstable.get(key)?.is_some() {
-                    return Ok(true);
+            let sstables = self.sstables.read().unwrap();
+            for sstable in sstables.iter().rev() {
+                if let Some(record) = sstable.get(key)? {
+                    return Ok(!record.is_tombstone() && !self.is_expired(&record));
                 }
             }
         }
@@ -131,77 +718,337 @@ impl BlockDB {
         Ok(false)
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        // Check if key already exists (append-only database)
-        if self.key_exists(key)? {
-            return Err(Box::new(crate::error::BlockDBError::DuplicateKey(
-                format!("Key '{}' already exists. BlockDB is append-only and does not allow updates.", 
-                    String::from_utf8_lossy(key))
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<WriteReceipt, Box<dyn std::error::Error>> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        Ok(self.write_batch(batch)?.remove(0))
+    }
+
+    /// Hide `key` from reads by writing a tombstone. The key's earlier
+    /// records stay in its history and in the blockchain.
+    pub fn delete(&self, key: &[u8]) -> Result<WriteReceipt, Box<dyn std::error::Error>> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        Ok(self.write_batch(batch)?.remove(0))
+    }
+
+    /// Delete `key` and destroy its data encryption key, leaving every value
+    /// ever written to it unrecoverable. The records and the chain over them
+    /// are untouched, so `verify_integrity` still holds. Requires
+    /// `crypto_shredding`.
+    pub fn erase(&self, key: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.config.crypto_shredding {
+            return Err(Box::new(crate::error::BlockDBError::StorageError(
+                "Erasure requires crypto_shredding to be enabled".to_string()
             )));
         }
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
+        // A key deleted earlier still has a DEK to destroy
+        let deleted = match self.delete(key) {
+            Ok(_) => true,
+            Err(e) if matches!(e.downcast_ref(), Some(crate::error::BlockDBError::KeyNotFound(_))) => false,
+            Err(e) => return Err(e),
+        };
+        if !self.keys.write().unwrap().destroy(key)? && !deleted {
+            return Err(Self::key_not_found(key));
+        }
+        Ok(())
+    }
+
+    /// Apply every entry of `batch` atomically: the records get consecutive
+    /// sequence numbers, share one WAL frame and land in the same blockchain
+    /// block. Unless the database is versioned, nothing is written if a put
+    /// targets a live key, a delete targets a missing one, or a key appears
+    /// twice in the batch. Returns a receipt per entry, in batch order.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<Vec<WriteReceipt>, Box<dyn std::error::Error>> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Slow down before taking a place in the commit queue, so the
+        // delay holds up no other writer
+        self.throttle_writes();
+
+        // Check if any key already exists (append-only database). The
+        // lookups may read SSTables, so they run before taking the commit
+        // state lock; keys written meanwhile are rechecked under it.
+        // A versioned database appends a new version instead.
+        let checked = if self.config.versioned {
+            None
+        } else {
+            let mut batch_keys = std::collections::HashSet::new();
+            if let Some((key, _)) = batch.iter().find(|(key, _)| !batch_keys.insert(*key)) {
+                return Err(Self::duplicate_key(key));
+            }
+
+            let sequence = self.group_commit.state.lock().unwrap().begin_key_check();
+            let stored = batch.iter().map(|(key, _)| self.key_exists(key)).collect::<Result<Vec<_>, _>>();
+            Some((sequence, stored))
+        };
+
+        // Creating a key's DEK syncs the key store, and large values go to
+        // a blob file, so both happen before taking the lock too. If one
+        // fails, the values separated before it are released with the rest.
+        let mut entries: Vec<BatchEntry> = Vec::new();
+        let mut result = Ok(());
+        for (key, value) in batch.into_entries() {
+            match self.batch_entry(key, value) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // The commit state lock orders writers: the final duplicate checks,
+        // sequence numbers and queue position are all decided under it
+        let mut state = self.group_commit.state.lock().unwrap();
+        if let Some((sequence, stored)) = checked {
+            if result.is_ok() {
+                result = stored.and_then(|stored| Self::check_keys(&state, &entries, stored));
+            }
+            state.end_key_check(sequence);
+        }
+        if result.is_ok() {
+            if let Some(error) = &state.error {
+                result = Err(Self::wal_failed(error));
+            }
+        }
+        if let Err(e) = result {
+            drop(state);
+            // Values already separated for this batch are never referenced
+            let mut garbage = BTreeMap::new();
+            for pointer in entries.iter().filter_map(|(_, _, _, pointer)| *pointer) {
+                *garbage.entry(pointer.file_number).or_default() += pointer.entry_size();
+            }
+            self.blobs.add_garbage(&garbage);
+            return Err(e);
+        }
+
+        let timestamp = now_millis();
 
-        let sequence_number = {
+        let (records, pointers): (Vec<Record>, Vec<Option<blob::BlobPointer>>) = {
             let mut counter = self.sequence_counter.lock().unwrap();
-            *counter += 1;
-            *counter
+            entries
+                .into_iter()
+                .map(|(key, value, kind, pointer)| {
+                    *counter += 1;
+                    (Self::create_record(key, value, kind, timestamp, *counter), pointer)
+                })
+                .unzip()
         };
+        // The chain seals the digests; everything else stores the pointer
+        let sealed = pointers.iter().any(Option::is_some).then(|| records.clone());
+        let records: Vec<Record> = records.into_iter()
+            .zip(pointers)
+            .map(|(mut record, pointer)| {
+                if let Some(pointer) = pointer {
+                    pointer.store_in(&mut record);
+                }
+                record
+            })
+            .collect();
+        let sequence_number = records.last().unwrap().sequence_number;
+        let receipts: Vec<(u64, Vec<u8>)> = records.iter()
+            .map(|record| (record.sequence_number, record.hash.clone()))
+            .collect();
 
-        let mut hasher = Sha256::new();
-        hasher.update(key);
-        hasher.update(value);
-        hasher.update(&timestamp.to_be_bytes());
-        hasher.update(&sequence_number.to_be_bytes());
-        let hash = hasher.finalize().to_vec();
-
-        let record = Record {
-            key: key.to_vec(),
-            value: value.to_vec(),
+        for record in &records {
+            state.pending_keys.insert(record.key.clone(), (record.sequence_number, !record.is_tombstone()));
+        }
+        state.pending.push(wal::QueuedWrite { records, sealed });
+
+        loop {
+            if let Some(block_index) = state.block_indexes.remove(&sequence_number) {
+                return Ok(receipts
+                    .into_iter()
+                    .map(|(sequence_number, hash)| WriteReceipt {
+                        sequence_number,
+                        timestamp,
+                        hash,
+                        block_index,
+                    })
+                    .collect());
+            }
+            if let Some(error) = &state.error {
+                return Err(Self::wal_failed(error));
+            }
+            if state.leader_active {
+                state = self.group_commit.committed.wait(state).unwrap();
+                continue;
+            }
+
+            // Nobody is committing: lead a group of everything queued so
+            // far, which includes this writer's records
+            state.leader_active = true;
+            let group = std::mem::take(&mut state.pending);
+            drop(state);
+
+            let result = self.commit_group(&group);
+
+            state = self.group_commit.state.lock().unwrap();
+            state.leader_active = false;
+            let needs_flush = match result {
+                Ok((needs_flush, block_indexes)) => {
+                    for (write, block_index) in group.iter().zip(block_indexes) {
+                        let last = write.records.last().unwrap().sequence_number;
+                        state.block_indexes.insert(last, block_index);
+                        state.durable_sequence = last;
+                    }
+                    state.forget_committed_keys();
+                    needs_flush
+                }
+                Err(e) => {
+                    state.error = Some(e.to_string());
+                    false
+                }
+            };
+            self.group_commit.committed.notify_all();
+
+            if needs_flush {
+                drop(state);
+                self.schedule_flush()?;
+                state = self.group_commit.state.lock().unwrap();
+            }
+        }
+    }
+
+    /// Entry of a batch as it is logged: the value sealed if crypto-shredding
+    /// is enabled and, if large enough, written to a blob file with the
+    /// digest the chain seals in its place
+    fn batch_entry(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<BatchEntry, Box<dyn std::error::Error>> {
+        let (value, kind) = match value {
+            None => (Vec::new(), RecordKind::Tombstone),
+            Some(value) if self.config.crypto_shredding => {
+                (self.keys.write().unwrap().encrypt(&key, &value)?, RecordKind::Encrypted)
+            }
+            Some(value) => (value, RecordKind::Value),
+        };
+        match self.config.blob_threshold {
+            Some(threshold) if kind != RecordKind::Tombstone && value.len() >= threshold => {
+                let pointer = self.blobs.append(&value, kind)?;
+                let digest = blob::ValueDigest::of(&value, kind).to_bytes();
+                Ok((key, digest, RecordKind::BlobDigest, Some(pointer)))
+            }
+            _ => Ok((key, value, kind, None)),
+        }
+    }
+
+    /// Refuse a put on a live key or a delete of a missing one, given
+    /// whether each key was live in the stored data. Queued records are not
+    /// in the memtable yet, and records committed since the lookups began
+    /// may have been missed, so `pending_keys` overrides the lookups.
+    fn check_keys(
+        state: &wal::GroupCommitState,
+        entries: &[BatchEntry],
+        stored: Vec<bool>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for ((key, _, kind, _), stored) in entries.iter().zip(stored) {
+            let live = state.pending_keys.get(key).map_or(stored, |&(_, live)| live);
+            match (kind, live) {
+                (RecordKind::Tombstone, false) => return Err(Self::key_not_found(key)),
+                (RecordKind::Tombstone, true) => {}
+                (_, true) => return Err(Self::duplicate_key(key)),
+                (_, false) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn create_record(key: Vec<u8>, value: Vec<u8>, kind: RecordKind, timestamp: u64, sequence_number: u64) -> Record {
+        let mut record = Record {
+            key,
+            value,
             timestamp,
             sequence_number,
-            hash,
+            hash: Vec::new(),
+            kind,
         };
+        record.hash = record.compute_hash();
+        record
+    }
 
-        {
-            let mut wal = self.wal.lock().unwrap();
-            wal.append(&record)?;
+    fn duplicate_key(key: &[u8]) -> Box<dyn std::error::Error> {
+        Box::new(crate::error::BlockDBError::DuplicateKey(
+            format!("Key '{}' already exists. BlockDB is append-only and does not allow updates.",
+                String::from_utf8_lossy(key))
+        ))
+    }
+
+    fn key_not_found(key: &[u8]) -> Box<dyn std::error::Error> {
+        Box::new(crate::error::BlockDBError::KeyNotFound(
+            format!("Key '{}' does not exist", String::from_utf8_lossy(key))
+        ))
+    }
+
+    /// Value of the newest record for a key, or `None` if it is a
+    /// tombstone, has expired or has been shredded
+    fn live_value(&self, mut record: Record) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        if record.is_tombstone() || self.is_expired(&record) {
+            return Ok(None);
         }
+        self.blobs.resolve(&mut record)?;
+        if !self.keys.read().unwrap().decrypt_record(&mut record) {
+            return Ok(None);
+        }
+        Ok(Some(record.value))
+    }
 
-        {
-            let mut memtable = self.memtable.write().unwrap();
-            memtable.insert(record.clone());
-            
-            if memtable.size() > self.config.memtable_size_limit {
-                drop(memtable);
-                self.flush_memtable()?;
-            }
+    /// Write a group of queued writes to the WAL, one frame each, with a
+    /// single sync, then make them visible. Returns whether the memtable is
+    /// due to be swapped out, and the blockchain block of each write.
+    fn commit_group(&self, group: &[wal::QueuedWrite]) -> Result<(bool, Vec<u64>), Box<dyn std::error::Error>> {
+        // The WAL lock keeps a flush from running between the append and
+        // the memtable insert, so every record in the WAL is also in the
+        // memtable whenever a flush takes the lock
+        let mut wal = self.wal.lock().unwrap();
+
+        // Values the records point to must be at least as durable as they are
+        self.blobs.commit(self.config.wal_durability)?;
+        for write in group {
+            wal.write_frame(&write.records)?;
         }
+        wal.commit(self.config.wal_durability)?;
 
-        {
+        let needs_flush = {
+            let memtables = self.memtables.read().unwrap();
+            let mut memtable = memtables.active.write().unwrap();
+            for record in group.iter().flat_map(|write| &write.records) {
+                memtable.insert(record.clone());
+            }
+            memtable.size() > self.config.memtable_size_limit
+        };
+
+        let block_indexes = {
             let mut blockchain = self.blockchain.lock().unwrap();
-            blockchain.add_record(record)?;
-        }
+            group.iter()
+                .map(|write| blockchain.add_records(write.sealed_records().to_vec()))
+                .collect::<Result<Vec<_>, _>>()?
+        };
 
-        Ok(())
+        Ok((needs_flush, block_indexes))
+    }
+
+    fn wal_failed(error: &str) -> Box<dyn std::error::Error> {
+        Box::new(crate::error::BlockDBError::StorageError(
+            format!("Write-ahead log is unusable after a failed commit: {}", error)
+        ))
     }
 
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
-        {
-            let memtable = self.memtable.read().unwrap();
-            if let Some(record) = memtable.get(key) {
-                return Ok(Some(record.value.clone()));
+        let _pin = self.blobs.pin();
+        for memtable in self.memtables.read().unwrap().newest_first() {
+            if let Some(record) = memtable.read().unwrap().get(key) {
+                return self.live_value(record.clone());
             }
         }
 
         {
-            let mut sstables = self.sstables.write().unwrap();
-            for sstable in sstables.iter_mut().rev() {
+            let sstables = self.sstables.read().unwrap();
+            for sstable in sstables.iter().rev() {
                 if let Some(record) = sstable.get(key)? {
-                    return Ok(Some(record.value));
+                    return self.live_value(record);
                 }
             }
         }
@@ -209,47 +1056,579 @@ impl BlockDB {
         Ok(None)
     }
 
-    fn flush_memtable(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let memtable = {
-            let mut memtable_guard = self.memtable.write().unwrap();
-            let old_memtable = std::mem::replace(&mut *memtable_guard, memtable::MemTable::new());
-            old_memtable
+    /// Pin a consistent view of everything committed so far. Reads through
+    /// the snapshot ignore later writes until it is dropped.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // Registered before the commit state lock is released, so no newer
+        // record can be flushed and compacted while compaction is unaware
+        // of the snapshot
+        let state = self.group_commit.state.lock().unwrap();
+        let sequence = state.durable_sequence;
+        self.snapshots.lock().unwrap().acquire(sequence);
+        drop(state);
+        Snapshot::new(self, sequence)
+    }
+
+    pub(crate) fn release_snapshot(&self, sequence: u64) {
+        self.snapshots.lock().unwrap().release(sequence);
+    }
+
+    /// Value `get` would have returned for `key` at `sequence`
+    pub(crate) fn get_visible_value(&self, key: &[u8], sequence: u64) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let _pin = self.blobs.pin();
+        match self.get_visible(key, sequence)? {
+            Some(record) => self.live_value(record),
+            None => Ok(None),
+        }
+    }
+
+    /// Newest record for `key` with a sequence number at or below `sequence`
+    pub(crate) fn get_visible(&self, key: &[u8], sequence: u64) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+        for memtable in self.memtables.read().unwrap().newest_first() {
+            if let Some(record) = memtable.read().unwrap().get_at(key, sequence) {
+                return Ok(Some(record.clone()));
+            }
+        }
+
+        // Compaction may keep several versions of a key in one file for
+        // older snapshots, so look past the newest
+        let sstables = self.sstables.read().unwrap();
+        for sstable in sstables.iter().rev() {
+            if !sstable.may_contain(key) {
+                continue;
+            }
+            for record in sstable.iter(KeyRange::key(key), false)? {
+                let record = record?;
+                if record.sequence_number <= sequence {
+                    return Ok(Some(record));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The version of `key` written at `sequence`, which may be a tombstone.
+    /// A shredded value is returned as its stored ciphertext.
+    pub fn get_version(&self, key: &[u8], sequence: u64) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+        let _pin = self.blobs.pin();
+        let mut record = match self.get_visible(key, sequence)? {
+            Some(record) if record.sequence_number == sequence && !self.is_expired(&record) => record,
+            _ => return Ok(None),
         };
+        self.blobs.resolve(&mut record)?;
+        self.keys.read().unwrap().decrypt_record(&mut record);
+        Ok(Some(record))
+    }
 
-        let sstable_path = format!("{}/sstable_{}.sst", 
-            self.config.data_dir, 
-            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
-        );
+    /// Every stored version of `key`, tombstones included, oldest first.
+    /// Only a versioned database keeps more than the latest one. Expired
+    /// versions are left out and shredded values are returned as their
+    /// stored ciphertext.
+    pub fn history(&self, key: &[u8]) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+        let _pin = self.blobs.pin();
+        // A record being flushed can be in both a memtable and an SSTable
+        let mut versions = BTreeMap::new();
+
+        for memtable in self.memtables.read().unwrap().newest_first() {
+            for record in memtable.read().unwrap().versions(key) {
+                versions.insert(record.sequence_number, record.clone());
+            }
+        }
+
+        let sstables = self.sstables.read().unwrap();
+        for sstable in sstables.iter() {
+            if !sstable.may_contain(key) {
+                continue;
+            }
+            for record in sstable.iter(KeyRange::key(key), false)? {
+                let record = record?;
+                versions.insert(record.sequence_number, record);
+            }
+        }
+
+        let keys = self.keys.read().unwrap();
+        versions
+            .into_values()
+            .filter(|record| !self.is_expired(record))
+            .map(|mut record| {
+                self.blobs.resolve(&mut record)?;
+                keys.decrypt_record(&mut record);
+                Ok(record)
+            })
+            .collect()
+    }
+
+    /// Newest record for `key` written at or before `timestamp`
+    /// (milliseconds since the Unix epoch). Requires `versioned`.
+    pub fn get_as_of(&self, key: &[u8], timestamp: u64) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+        self.scan_as_of(KeyRange::key(key), timestamp)?.next().transpose()
+    }
+
+    /// Records in `range` as they stood at `timestamp`: for each key, the
+    /// newest record written at or before then. Requires `versioned`.
+    pub fn scan_as_of(&self, range: KeyRange, timestamp: u64) -> Result<DBIterator, Box<dyn std::error::Error>> {
+        let options = ScanOptions {
+            as_of: Some(timestamp),
+            ..Default::default()
+        };
+        self.scan_with(range, &options)
+    }
+
+    /// Records in `[start, end)` in key order
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> Result<DBIterator, Box<dyn std::error::Error>> {
+        self.scan_with(KeyRange::new(start, end), &ScanOptions::default())
+    }
+
+    /// Records whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<DBIterator, Box<dyn std::error::Error>> {
+        self.scan_with(KeyRange::prefix(prefix), &ScanOptions::default())
+    }
+
+    /// Stream the records in `range` from the memtables and every SSTable,
+    /// merged in key order. Records are read lazily as the iterator advances.
+    pub fn scan_with(&self, range: KeyRange, options: &ScanOptions) -> Result<DBIterator, Box<dyn std::error::Error>> {
+        // Compaction keeps only the newest version of an unversioned key,
+        // so a read at an earlier time could silently return the wrong one
+        if options.as_of.is_some() && !self.config.versioned {
+            return Err(Box::new(crate::error::BlockDBError::StorageError(
+                "Time-travel reads require versioned to be enabled".to_string()
+            )));
+        }
+
+        // Taken before any source, so the iterator can resolve every
+        // pointer it will meet
+        let blobs = self.blobs.pin();
+        let mut sources: Vec<iterator::RecordSource> = Vec::new();
+
+        // Memtables before SSTables: a flush installs its SSTable before
+        // retiring the memtable, so the records are always in one of them
+        for memtable in self.memtables.read().unwrap().newest_first() {
+            sources.push(Box::new(memtable::MemTableIter::new(memtable, range.clone(), options.reverse)));
+        }
 
-        let sstable = sstable::SSTable::create_from_memtable(&sstable_path, &memtable)?;
-        
         {
-            let mut sstables = self.sstables.write().unwrap();
-            sstables.push(sstable);
+            let sstables = self.sstables.read().unwrap();
+            for sstable in sstables.iter().rev() {
+                sources.push(Box::new(sstable.iter(range.clone(), options.reverse)?));
+            }
         }
 
+        Ok(DBIterator::new(sources, options)
+            .with_keys(self.keys.clone())
+            .with_blobs(blobs)
+            .expiring(Self::expire_before(&self.config)))
+    }
+
+    /// Swap out the active memtable, if it is non-empty and `force` is set
+    /// or it is over `memtable_size_limit`, and start a new WAL segment for
+    /// the records that follow. Returns whether it was swapped.
+    fn swap_memtable(&self, force: bool) -> Result<bool, Box<dyn std::error::Error>> {
+        // Hold the WAL lock so no write lands between swapping the
+        // memtable and rotating the log
+        let mut wal = self.wal.lock().unwrap();
+        {
+            let memtables = self.memtables.read().unwrap();
+            let active = memtables.active.read().unwrap();
+            if active.is_empty() || (!force && active.size() <= self.config.memtable_size_limit) {
+                return Ok(false);
+            }
+        }
+
+        // Everything in the swapped memtable is now in older segments
+        let log_number = wal.rotate()?;
+        self.memtables.write().unwrap().swap(log_number);
+        Ok(true)
+    }
+
+    /// Queue the active memtable for the background flush if it is full
+    fn schedule_flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.swap_memtable(false)? {
+            if let Some(trigger) = &self.flush_trigger {
+                let _ = trigger.send(());
+            }
+        }
         Ok(())
     }
 
+    /// Flush the active memtable and everything queued before it, waiting
+    /// for the SSTables to be installed
+    fn flush_memtable(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.swap_memtable(true)?;
+        let flushed = Self::run_flushes(
+            &self.config,
+            &self.sstable_options(),
+            &self.flush_lock,
+            &self.memtables,
+            &self.wal,
+            &self.manifest,
+            &self.sstables,
+            &self.compactor,
+            &self.blobs,
+            &self.blockchain,
+        )?;
+        if flushed {
+            self.schedule_compaction();
+        }
+        Ok(())
+    }
+
+    /// Write each queued memtable to an SSTable, oldest first, installing
+    /// it in the manifest and then releasing the WAL segments it covered.
+    /// Pending blockchain records are sealed first.
+    /// A memtable stays readable until its SSTable is installed, and one
+    /// whose flush failed stays queued for the next run. Returns whether
+    /// anything was flushed.
+    #[allow(clippy::too_many_arguments)]
+    fn run_flushes(
+        config: &BlockDBConfig,
+        options: &sstable::SSTableOptions,
+        flush_lock: &Mutex<()>,
+        memtables: &RwLock<memtable::MemTableSet>,
+        wal: &Mutex<wal::WriteAheadLog>,
+        manifest: &Mutex<manifest::Manifest>,
+        sstables: &RwLock<Vec<sstable::SSTable>>,
+        compactor: &Mutex<compaction::Compactor>,
+        blobs: &blob::BlobStore,
+        blockchain: &Mutex<blockchain::BlockChain>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        // One flush at a time, so each memtable is written once
+        let _flushing = flush_lock.lock().unwrap();
+        let immutable = memtables.read().unwrap().immutable.clone();
+        if immutable.is_empty() {
+            return Ok(false);
+        }
+
+        // The segments go once the SSTables are in, so the values their
+        // records point to must be durable first
+        blobs.sync()?;
+        // Likewise the records still queued for the chain, which could
+        // only be recovered from those segments. Every record of a queued
+        // memtable was queued before it was swapped out.
+        blockchain.lock().unwrap().force_create_block()?;
+
+        for memtable in &immutable {
+            let file_name = format!("sstable_{}.sst", 
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+            );
+            let sstable_path = format!("{}/{}", config.data_dir, file_name);
+
+            let (sstable, flushed_sequence, log_number) = {
+                let memtable = memtable.read().unwrap();
+                let sstable = sstable::SSTable::create_from_memtable(&sstable_path, &memtable, options)?;
+                (sstable, memtable.max_sequence(), memtable.next_log_number())
+            };
+            let span = compaction::KeySpan::of(&sstable);
+
+            // The SSTable is only live once the manifest references it
+            {
+                let mut manifest = manifest.lock().unwrap();
+                manifest.add_sstable(file_name.clone(), 0);
+                manifest.last_flushed_sequence = manifest.last_flushed_sequence.max(flushed_sequence);
+                manifest.log_number = manifest.log_number.max(log_number);
+                manifest.save(&config.data_dir)?;
+
+                let mut sstables = sstables.write().unwrap();
+                sstables.push(sstable);
+            }
+            memtables.write().unwrap().remove_immutable(memtable);
+
+            compactor.lock().unwrap().add_sstable(file_name, 0, span);
+
+            // Only now that the manifest is durable can the old segments go
+            wal.lock().unwrap().remove_segments_before(log_number)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Delay the calling writer while flushes or compactions are behind
+    fn throttle_writes(&self) {
+        let immutable_memtables = self.memtables.read().unwrap().immutable.len();
+        let level0_files = self.compactor.lock().unwrap().level_size(0);
+        self.write_controller.throttle(immutable_memtables, level0_files);
+    }
+
+    /// Memtable and level 0 backlog, and how much writers have been delayed
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        let immutable_memtables = self.memtables.read().unwrap().immutable.len();
+        let level0_files = self.compactor.lock().unwrap().level_size(0);
+        self.write_controller.stats(immutable_memtables, level0_files)
+    }
+
+    /// Hits and misses of the block cache since the database was opened
+    pub fn cache_stats(&self) -> CacheStats {
+        self.block_cache.stats()
+    }
+
+    /// Bytes of invalid WAL tail dropped while opening the database
+    pub fn wal_discarded_bytes(&self) -> u64 {
+        self.wal_discarded_bytes
+    }
+
     pub fn verify_integrity(&self) -> Result<bool, Box<dyn std::error::Error>> {
         let blockchain = self.blockchain.lock().unwrap();
         blockchain.verify_chain()
     }
 
+    /// Proof that the newest record of `key`, which may be a tombstone, was
+    /// sealed into the chain. A record still queued for a block is sealed
+    /// first. Returns `None` if the key has never been written.
+    pub fn prove(&self, key: &[u8]) -> Result<Option<InclusionProof>, Box<dyn std::error::Error>> {
+        let _pin = self.blobs.pin();
+        let Some(record) = self.latest_record(key)? else {
+            return Ok(None);
+        };
+
+        let mut blockchain = self.blockchain.lock().unwrap();
+        if blockchain.find_record(&record.hash)?.is_none() && record.sequence_number > blockchain.sealed_sequence() {
+            blockchain.force_create_block()?;
+        }
+        let Some((block, path)) = blockchain.get_record_proof(&record.hash)? else {
+            return Err(Box::new(crate::error::BlockDBError::Corruption(
+                format!("Record for key '{}' is not sealed in any block", String::from_utf8_lossy(key))
+            )));
+        };
+        let head = blockchain.get_latest_block().map(|head| proof::ChainHead {
+            index: head.index,
+            hash: head.hash.clone(),
+        }).unwrap();
+        drop(blockchain);
+
+        Ok(Some(InclusionProof {
+            record: self.blobs.sealed(&record)?,
+            path,
+            block: proof::BlockHeader::from(&block),
+            head,
+        }))
+    }
+
+    /// Newest record of `key` in the memtables or SSTables, of any kind
+    fn latest_record(&self, key: &[u8]) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+        for memtable in self.memtables.read().unwrap().newest_first() {
+            if let Some(record) = memtable.read().unwrap().get(key) {
+                return Ok(Some(record.clone()));
+            }
+        }
+
+        let sstables = self.sstables.read().unwrap();
+        for sstable in sstables.iter().rev() {
+            if let Some(record) = sstable.get(key)? {
+                return Ok(Some(record));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Check the chain and the stored data against each other: recompute
+    /// every record hash in the chain and in the memtables and SSTables,
+    /// and check each stored record was sealed into a block. Stops at the
+    /// first problem, which the report names.
+    pub fn verify_deep(&self) -> Result<VerifyReport, Box<dyn std::error::Error>> {
+        let _pin = self.blobs.pin();
+        let mut report = VerifyReport::default();
+        let (sealed, sealed_sequence) = self.verify_chain_records(&mut report)?;
+        if !report.is_intact() {
+            return Ok(report);
+        }
+
+        let memtables = self.memtables.read().unwrap().newest_first();
+        for memtable in memtables {
+            let records: Vec<Record> = memtable.read().unwrap().iter().map(|(_, record)| record.clone()).collect();
+            if !self.verify_stored_records(records, "memtable", &sealed, sealed_sequence, &mut report) {
+                return Ok(report);
+            }
+        }
+
+        let sstables = self.sstables.read().unwrap();
+        for sstable in sstables.iter() {
+            if !self.verify_stored_records(sstable.records()?, sstable.file_name(), &sealed, sealed_sequence, &mut report) {
+                return Ok(report);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Walk the chain for [`verify_deep`](Self::verify_deep). Returns the
+    /// block each record hash was sealed in, and the highest sealed
+    /// sequence number.
+    fn verify_chain_records(&self, report: &mut VerifyReport) -> Result<(SealedRecords, u64), Box<dyn std::error::Error>> {
+        // Walk a snapshot so writers can keep sealing during the walk
+        let (chain, sealed_sequence) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (blockchain.reader(), blockchain.sealed_sequence())
+        };
+        let mut sealed = HashMap::new();
+        let mut previous: Option<blockchain::Block> = None;
+
+        for block in chain.blocks() {
+            let block = block?;
+            let tamper = |kind, record: Option<&Record>, detail: String| Some(Tamper {
+                kind,
+                block: Some(block.index),
+                key: record.map(|record| record.key.clone()),
+                sequence_number: record.map(|record| record.sequence_number),
+                file: chain.segment_file(block.index),
+                detail,
+            });
+
+            let expected_index = previous.as_ref().map_or(0, |previous| previous.index + 1);
+            if block.index != expected_index || previous.as_ref().is_some_and(|previous| block.previous_hash != previous.hash) {
+                report.tamper = tamper(TamperKind::ChainLink, None, format!("Block {} does not follow block {}", block.index, expected_index.saturating_sub(1)));
+                return Ok((sealed, 0));
+            }
+            if !block.verify_integrity() {
+                report.tamper = tamper(TamperKind::BlockHash, None, format!("Block {} does not match its records", block.index));
+                return Ok((sealed, 0));
+            }
+
+            for record in &block.records {
+                // Only chains written before values were sealed at all
+                // hold pointers
+                let resolved = match self.blobs.sealed(record) {
+                    Ok(resolved) => resolved,
+                    Err(e) => {
+                        report.tamper = tamper(TamperKind::UnreadableBlob, Some(record), e.to_string());
+                        return Ok((sealed, 0));
+                    }
+                };
+                if resolved.compute_hash() != record.hash {
+                    report.tamper = tamper(TamperKind::RecordHash, Some(record), "Record hash does not match its contents".to_string());
+                    return Ok((sealed, 0));
+                }
+                report.chain_records_checked += 1;
+                sealed.insert(record.hash.clone(), block.index);
+            }
+
+            report.blocks_checked += 1;
+            previous = Some(block);
+        }
+
+        Ok((sealed, sealed_sequence))
+    }
+
+    /// Check the records of one memtable or SSTable for
+    /// [`verify_deep`](Self::verify_deep). Returns whether they all passed.
+    fn verify_stored_records(
+        &self,
+        records: Vec<Record>,
+        file: &str,
+        sealed: &SealedRecords,
+        sealed_sequence: u64,
+        report: &mut VerifyReport,
+    ) -> bool {
+        for record in records {
+            let block = sealed.get(&record.hash).copied();
+            let tamper = |kind, detail: String| Some(Tamper {
+                kind,
+                block,
+                key: Some(record.key.clone()),
+                sequence_number: Some(record.sequence_number),
+                file: Some(file.to_string()),
+                detail,
+            });
+
+            let resolved = match self.blobs.sealed(&record) {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    report.tamper = tamper(TamperKind::UnreadableBlob, e.to_string());
+                    return false;
+                }
+            };
+            if resolved.compute_hash() != record.hash {
+                report.tamper = tamper(TamperKind::RecordHash, "Record hash does not match its contents".to_string());
+                return false;
+            }
+
+            if block.is_some() {
+                report.stored_records_checked += 1;
+            } else if record.sequence_number > sealed_sequence {
+                report.pending_records += 1;
+            } else {
+                report.tamper = tamper(TamperKind::NotInChain, "Record is not sealed in any block".to_string());
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Re-encrypt the database in `config.data_dir` under a new data key
+    /// wrapped by `new_master_key`. `config.master_key` is the current
+    /// master key, or `None` to encrypt a plaintext directory.
+    ///
+    /// The database must not be open. The old data keys stay in the keyring
+    /// until every file has been rewritten, so an interrupted rotation can
+    /// be run again with the same keys.
+    pub fn rotate_encryption_key(config: &BlockDBConfig, new_master_key: &MasterKeySource) -> Result<(), Box<dyn std::error::Error>> {
+        let data_dir = config.data_dir.as_str();
+
+        // After an interrupted run the keyring is already wrapped by the new key
+        let mut keyring = match encryption::Keyring::load(data_dir, config.master_key.as_ref()) {
+            Ok(keyring) => keyring,
+            Err(e) => encryption::Keyring::load(data_dir, Some(new_master_key)).map_err(|_| e)?,
+        }
+        .unwrap_or_default();
+        keyring.add_key();
+        keyring.save(data_dir, new_master_key)?;
+
+        let shared = Some(Arc::new(keyring.clone()));
+        let options = sstable::SSTableOptions {
+            keyring: shared.clone(),
+            ..sstable::SSTableOptions::from_config(config)
+        };
+        if let Some(manifest) = manifest::Manifest::load(data_dir)? {
+            for entry in &manifest.sstables {
+                let path = format!("{}/{}", data_dir, entry.file_name);
+                // Not in the manifest, so removed on open if left behind
+                let tmp_path = format!("{}/rotating_{}", data_dir, entry.file_name);
+                // Streamed a block at a time, so no table is held in memory
+                let mut writer = sstable::SSTableWriter::create(&tmp_path, &options)?;
+                for record in sstable::SSTable::open_with(&path, &options)?.scan_uncached()? {
+                    writer.add(&record?)?;
+                }
+                writer.finish()?;
+                std::fs::rename(&tmp_path, &path)?;
+            }
+        }
+        wal::WriteAheadLog::new(data_dir, config.compression, shared.clone())?.rewrite_segments()?;
+        blockchain::BlockChain::new(data_dir, shared.clone(), blockchain::SealingPolicy::default())?.rewrite_segments()?;
+        blob::BlobStore::reseal_files(data_dir, &keyring)?;
+        // Opening reseals the data keys of crypto-shredded values
+        keystore::KeyStore::open(data_dir, shared)?;
+        File::open(data_dir)?.sync_all()?;
+
+        // Nothing is sealed under the old keys any more
+        keyring.retain_current();
+        keyring.save(data_dir, new_master_key)?;
+
+        println!("✅ Encryption key rotated for {}", data_dir);
+        Ok(())
+    }
+
     /// Flush all data and reset the database to an empty state
     pub fn flush_all(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Clear memtable
-        {
-            let mut memtable = self.memtable.write().unwrap();
-            *memtable = memtable::MemTable::new();
+        // Let a running flush finish so it cannot install a cleared memtable
+        let _flushing = self.flush_lock.lock().unwrap();
+
+        // Block writers until the reset is complete, letting any batch
+        // already being committed finish first
+        let mut state = self.group_commit.state.lock().unwrap();
+        while state.leader_active || !state.pending.is_empty() {
+            state = self.group_commit.committed.wait(state).unwrap();
         }
+        let mut wal = self.wal.lock().unwrap();
 
-        // Clear WAL
+        // Clear memtables
         {
-            let mut wal = self.wal.lock().unwrap();
-            wal.clear()?;
+            let mut memtables = self.memtables.write().unwrap();
+            *memtables = memtable::MemTableSet::new();
         }
 
+        // Clear WAL
+        wal.clear()?;
+
         // Clear SSTables
         {
             let mut sstables = self.sstables.write().unwrap();
@@ -258,18 +1637,30 @@ impl BlockDB {
                 let _ = std::fs::remove_file(sstable.path());
             }
         }
+        self.block_cache.clear();
+        self.blobs.clear()?;
+
+        // Reset manifest
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            *manifest = manifest::Manifest::default();
+            manifest.save(&self.config.data_dir)?;
+        }
+        self.compactor.lock().unwrap().clear();
 
         // Clear blockchain
         {
             let mut blockchain = self.blockchain.lock().unwrap();
             blockchain.clear()?;
         }
+        self.keys.write().unwrap().clear()?;
 
         // Reset sequence counter
         {
             let mut counter = self.sequence_counter.lock().unwrap();
             *counter = 0;
         }
+        state.durable_sequence = 0;
 
         println!("✅ Database flushed successfully - all data cleared");
         Ok(())
@@ -277,9 +1668,8 @@ impl BlockDB {
 
     /// Force flush memtable to disk
     pub fn force_flush_memtable(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let memtable = self.memtable.read().unwrap();
-        if !memtable.is_empty() {
-            drop(memtable);
+        let is_empty = self.memtables.read().unwrap().active.read().unwrap().is_empty();
+        if !is_empty {
             self.flush_memtable()?;
             println!("✅ Memtable flushed to disk");
         } else {
@@ -287,4 +1677,40 @@ impl BlockDB {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl Drop for BlockDB {
+    fn drop(&mut self) {
+        // The flush worker holds a compaction trigger, so it stops first.
+        // Memtables it has not flushed are replayed from the WAL on open.
+        self.flush_trigger.take();
+        if let Some(worker) = self.flush_worker.take() {
+            let _ = worker.join();
+        }
+
+        // Closing the channel lets the worker finish its current merge and exit
+        self.compaction_trigger.take();
+        if let Some(worker) = self.compaction_worker.take() {
+            let _ = worker.join();
+        }
+
+        self.wal_sync_shutdown.take();
+        if let Some(worker) = self.wal_sync_worker.take() {
+            let _ = worker.join();
+        }
+        self.seal_shutdown.take();
+        if let Some(worker) = self.seal_worker.take() {
+            let _ = worker.join();
+        }
+        if self.config.wal_durability != wal::WalDurability::Fsync {
+            let _ = self.wal.lock().unwrap().sync();
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Serialize, Deserialize};
+use crate::storage::Record;
+use crate::storage::blockchain::{block_hash, merkle_parent, Block};
+
+/// Which side of the path a sibling hash is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// One level of a Merkle path, from the record up to the root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub sibling: Vec<u8>,
+    pub side: Side,
+}
+
+/// Every field a block's hash covers. The hash runs over the hashes of all
+/// the block's records, so they are carried along.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: u64,
+    pub previous_hash: Vec<u8>,
+    pub merkle_root: Vec<u8>,
+    pub nonce: u64,
+    pub record_hashes: Vec<Vec<u8>>,
+    pub hash: Vec<u8>,
+}
+
+impl From<&Block> for BlockHeader {
+    fn from(block: &Block) -> Self {
+        BlockHeader {
+            index: block.index,
+            timestamp: block.timestamp,
+            previous_hash: block.previous_hash.clone(),
+            merkle_root: block.merkle_root.clone(),
+            nonce: block.nonce,
+            record_hashes: block.records.iter().map(|record| record.hash.clone()).collect(),
+            hash: block.hash.clone(),
+        }
+    }
+}
+
+impl BlockHeader {
+    pub fn compute_hash(&self) -> Vec<u8> {
+        block_hash(
+            self.index,
+            self.timestamp,
+            &self.previous_hash,
+            &self.merkle_root,
+            self.nonce,
+            self.record_hashes.iter().map(|hash| hash.as_slice()),
+        )
+    }
+}
+
+/// Newest block of the chain when a proof was made
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainHead {
+    pub index: u64,
+    pub hash: Vec<u8>,
+}
+
+/// Proof that a record was sealed into the chain, which [`verify_proof`]
+/// checks without access to the database. A verifier that trusts a
+/// published chain head compares it against `head`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InclusionProof {
+    /// The record as sealed; a separated value is replaced by its digest
+    pub record: Record,
+    /// Siblings from the record up to the block's Merkle root
+    pub path: Vec<ProofStep>,
+    pub block: BlockHeader,
+    pub head: ChainHead,
+}
+
+/// Check that the record's hash matches its contents, that its Merkle path
+/// leads to the block's root and that the block's hash matches its header.
+/// The block must not be newer than `head`, and must be `head` if at its
+/// index; linking older blocks to `head` is left to the verifier.
+pub fn verify_proof(proof: &InclusionProof) -> bool {
+    if proof.record.compute_hash() != proof.record.hash {
+        return false;
+    }
+
+    let root = proof.path.iter().fold(proof.record.hash.clone(), |hash, step| match step.side {
+        Side::Left => merkle_parent(&step.sibling, &hash),
+        Side::Right => merkle_parent(&hash, &step.sibling),
+    });
+    if root != proof.block.merkle_root || !proof.block.record_hashes.contains(&proof.record.hash) {
+        return false;
+    }
+
+    if proof.block.compute_hash() != proof.block.hash {
+        return false;
+    }
+
+    match proof.head.index.cmp(&proof.block.index) {
+        std::cmp::Ordering::Less => false,
+        std::cmp::Ordering::Equal => proof.head.hash == proof.block.hash,
+        std::cmp::Ordering::Greater => true,
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use crate::storage::blockchain::Block;
+use crate::storage::sstable::read_at;
+
+/// Log of the entries not yet in a run: one `record hash | block index`
+/// entry per sealed record, in block order
+const LOG_FILE: &str = "chain_records.idx";
+/// Runs are named `chain_records_{first block}_{end block}.run`
+const RUN_PREFIX: &str = "chain_records_";
+const RUN_SUFFIX: &str = ".run";
+/// Suffix of a run still being written
+const TMP_SUFFIX: &str = ".tmp";
+const HASH_SIZE: usize = 32;
+const ENTRY_SIZE: usize = HASH_SIZE + 8;
+/// Entries kept in memory before they are written out as a run
+const MAX_LOG_ENTRIES: usize = 16 * 1024;
+
+/// `record hash | block index`
+type Entry = [u8; ENTRY_SIZE];
+
+/// Whether `file_name` is one of the files of a record index
+pub fn is_index_file(file_name: &str) -> bool {
+    file_name == LOG_FILE || (file_name.starts_with(RUN_PREFIX) && file_name.contains(RUN_SUFFIX))
+}
+
+/// Entries of consecutive blocks, sorted by record hash, so a lookup is a
+/// binary search reading one entry per step
+#[derive(Debug)]
+struct Run {
+    /// First block covered
+    from: u64,
+    /// Block after the last one covered
+    to: u64,
+    file: File,
+    entries: u64,
+}
+
+impl Run {
+    fn path(data_dir: &str, from: u64, to: u64) -> String {
+        format!("{}/{}{:010}_{:010}{}", data_dir, RUN_PREFIX, from, to, RUN_SUFFIX)
+    }
+
+    /// Block range of the run named `file_name`
+    fn parse(file_name: &str) -> Option<(u64, u64)> {
+        let (from, to) = file_name.strip_prefix(RUN_PREFIX)?.strip_suffix(RUN_SUFFIX)?.split_once('_')?;
+        Some((from.parse().ok()?, to.parse().ok()?))
+    }
+
+    fn open(data_dir: &str, from: u64, to: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::open(Self::path(data_dir, from, to))?;
+        let entries = file.metadata()?.len() / ENTRY_SIZE as u64;
+        Ok(Run { from, to, file, entries })
+    }
+
+    /// Write `entries`, sorted by hash, as the run of blocks `from..to`.
+    /// It only appears under its name once synced.
+    fn write(
+        data_dir: &str,
+        from: u64,
+        to: u64,
+        entries: impl Iterator<Item = Result<Entry, Box<dyn std::error::Error>>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Self::path(data_dir, from, to);
+        let tmp_path = format!("{}{}", path, TMP_SUFFIX);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for entry in entries {
+            writer.write_all(&entry?)?;
+        }
+        writer.flush()?;
+        writer.get_mut().sync_all()?;
+        drop(writer);
+        fs::rename(&tmp_path, &path)?;
+        File::open(data_dir)?.sync_all()?;
+        Self::open(data_dir, from, to)
+    }
+
+    fn entry(&self, position: u64) -> Result<Entry, Box<dyn std::error::Error>> {
+        let mut entry = [0u8; ENTRY_SIZE];
+        read_at(&self.file, position * ENTRY_SIZE as u64, &mut entry)?;
+        Ok(entry)
+    }
+
+    fn find(&self, record_hash: &[u8]) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let (mut low, mut high) = (0, self.entries);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let entry = self.entry(middle)?;
+            match entry[..HASH_SIZE].cmp(record_hash) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(Some(block_of(&entry))),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Entries in hash order, read sequentially
+    fn entries(&self) -> Result<impl Iterator<Item = Result<Entry, Box<dyn std::error::Error>>> + '_, Box<dyn std::error::Error>> {
+        let mut reader = BufReader::new(self.file.try_clone()?);
+        std::io::Seek::seek(&mut reader, std::io::SeekFrom::Start(0))?;
+        Ok((0..self.entries).map(move |_| {
+            let mut entry = [0u8; ENTRY_SIZE];
+            reader.read_exact(&mut entry)?;
+            Ok(entry)
+        }))
+    }
+}
+
+fn block_of(entry: &[u8]) -> u64 {
+    u64::from_be_bytes(entry[HASH_SIZE..].try_into().unwrap())
+}
+
+fn encode(record_hash: &[u8], block: u64) -> Entry {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..HASH_SIZE].copy_from_slice(record_hash);
+    entry[HASH_SIZE..].copy_from_slice(&block.to_be_bytes());
+    entry
+}
+
+/// Block each sealed record hash is in, kept on disk. New entries go to an
+/// append-only log and a bounded map in memory; once the log is full they
+/// are written out as a run sorted by hash, and runs of similar size are
+/// merged, so a lookup searches a few runs and reads only the entries its
+/// binary searches touch.
+///
+/// Runs are synced and renamed into place before the log is emptied, and
+/// the log needs no sync of its own: blocks missing from both are indexed
+/// again from the chain on open.
+#[derive(Debug)]
+pub struct RecordIndex {
+    data_dir: String,
+    /// Oldest blocks first
+    runs: Vec<Run>,
+    /// Entries of the blocks after the last run, as in the log
+    recent: HashMap<Vec<u8>, u64>,
+    log: File,
+    /// Block after the last one indexed
+    indexed: u64,
+}
+
+impl RecordIndex {
+    /// Open the index files in `data_dir`, creating an empty log if there
+    /// is none. [`load`](Self::load) must be called before use.
+    pub fn open(data_dir: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut runs = Vec::new();
+        for entry in fs::read_dir(data_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !file_name.starts_with(RUN_PREFIX) {
+                continue;
+            }
+            if file_name.ends_with(TMP_SUFFIX) {
+                fs::remove_file(entry.path())?;
+            } else if let Some((from, to)) = Run::parse(&file_name) {
+                runs.push(Run::open(data_dir, from, to)?);
+            }
+        }
+        // A merge that was cut short leaves its inputs next to its output
+        runs.sort_by_key(|run| (run.from, std::cmp::Reverse(run.to)));
+
+        let log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(format!("{}/{}", data_dir, LOG_FILE))?;
+        Ok(RecordIndex {
+            data_dir: data_dir.to_string(),
+            runs,
+            recent: HashMap::new(),
+            log,
+            indexed: 0,
+        })
+    }
+
+    /// Drop whatever does not belong to the first `chain_length` blocks
+    /// and load the log, dropping its last block, whose entries may be
+    /// torn. Returns the first block to index again.
+    pub fn load(&mut self, chain_length: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut end = 0;
+        for run in std::mem::take(&mut self.runs) {
+            if run.from == end && run.to <= chain_length {
+                end = run.to;
+                self.runs.push(run);
+            } else {
+                fs::remove_file(Run::path(&self.data_dir, run.from, run.to))?;
+            }
+        }
+
+        let log_len = self.log.metadata()?.len();
+        let log_entries = log_len / ENTRY_SIZE as u64;
+        let block_at = |position: u64| -> Result<u64, Box<dyn std::error::Error>> {
+            let mut entry = [0u8; ENTRY_SIZE];
+            read_at(&self.log, position * ENTRY_SIZE as u64, &mut entry)?;
+            Ok(block_of(&entry))
+        };
+        // Only the genesis block has no records, so a log that starts past
+        // the end of the runs lost the blocks in between along with a run
+        let reindex_from = match log_entries {
+            0 => end,
+            _ if block_at(0)? > end.max(1) => end,
+            count => block_at(count - 1)?.clamp(end, chain_length),
+        };
+
+        // Entries of runs already written, or cut off by the chain, are
+        // dropped. A log from before runs existed can hold every entry, so
+        // it is streamed into runs.
+        self.indexed = end;
+        let mut kept = 0;
+        let mut reader = BufReader::new(self.log.try_clone()?);
+        std::io::Seek::seek(&mut reader, std::io::SeekFrom::Start(0))?;
+        for _ in 0..log_entries {
+            let mut entry = [0u8; ENTRY_SIZE];
+            reader.read_exact(&mut entry)?;
+            let block = block_of(&entry);
+            if block < end || block >= reindex_from {
+                continue;
+            }
+            if block > self.indexed && self.recent.len() >= MAX_LOG_ENTRIES {
+                self.indexed = block;
+                self.write_run()?;
+                kept = 0;
+            }
+            self.recent.entry(entry[..HASH_SIZE].to_vec()).or_insert(block);
+            self.indexed = block + 1;
+            kept += 1;
+        }
+        self.indexed = reindex_from;
+
+        if kept * ENTRY_SIZE as u64 != log_len {
+            let mut entries: Vec<(&Vec<u8>, &u64)> = self.recent.iter().collect();
+            entries.sort_by_key(|&(_, &block)| block);
+            self.log.set_len(0)?;
+            let mut writer = BufWriter::new(&self.log);
+            for (hash, &block) in entries {
+                writer.write_all(&encode(hash, block))?;
+            }
+            writer.flush()?;
+        }
+        Ok(reindex_from)
+    }
+
+    /// Index the records of `block`, the one after the last indexed
+    pub fn add(&mut self, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        let mut entries = Vec::with_capacity(block.records.len() * ENTRY_SIZE);
+        for record in block.records.iter().filter(|record| record.hash.len() == HASH_SIZE) {
+            entries.extend_from_slice(&encode(&record.hash, block.index));
+            self.recent.entry(record.hash.clone()).or_insert(block.index);
+        }
+        self.log.write_all(&entries)?;
+        self.indexed = block.index + 1;
+
+        if self.recent.len() >= MAX_LOG_ENTRIES {
+            self.write_run()?;
+            self.log.set_len(0)?;
+        }
+        Ok(())
+    }
+
+    /// Block the record with `record_hash` was first sealed in
+    pub fn find(&self, record_hash: &[u8]) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        for run in &self.runs {
+            if let Some(block) = run.find(record_hash)? {
+                return Ok(Some(block));
+            }
+        }
+        Ok(self.recent.get(record_hash).copied())
+    }
+
+    /// Remove every entry
+    pub fn clear(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for run in self.runs.drain(..) {
+            fs::remove_file(Run::path(&self.data_dir, run.from, run.to))?;
+        }
+        self.log.set_len(0)?;
+        self.recent.clear();
+        self.indexed = 0;
+        Ok(())
+    }
+
+    /// Write the entries in memory out as a run, then merge the newest runs
+    /// while the older of the two is no larger, so there are only a
+    /// logarithmic number of them
+    fn write_run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let from = self.runs.last().map_or(0, |run| run.to);
+        let mut entries: Vec<Entry> = self.recent
+            .drain()
+            .map(|(hash, block)| encode(&hash, block))
+            .collect();
+        entries.sort_unstable();
+        self.runs.push(Run::write(&self.data_dir, from, self.indexed, entries.into_iter().map(Ok))?);
+
+        while self.runs.len() >= 2 && self.runs[self.runs.len() - 2].entries <= self.runs[self.runs.len() - 1].entries {
+            let newer = self.runs.pop().unwrap();
+            let older = self.runs.pop().unwrap();
+            let merged = Run::write(&self.data_dir, older.from, newer.to, Self::merge(older.entries()?, newer.entries()?))?;
+            fs::remove_file(Run::path(&self.data_dir, older.from, older.to))?;
+            fs::remove_file(Run::path(&self.data_dir, newer.from, newer.to))?;
+            self.runs.push(merged);
+        }
+        Ok(())
+    }
+
+    /// Entries of two runs in hash order. A hash in both keeps the entry of
+    /// the older run, which holds the earlier block.
+    fn merge<'a>(
+        older: impl Iterator<Item = Result<Entry, Box<dyn std::error::Error>>> + 'a,
+        newer: impl Iterator<Item = Result<Entry, Box<dyn std::error::Error>>> + 'a,
+    ) -> impl Iterator<Item = Result<Entry, Box<dyn std::error::Error>>> + 'a {
+        let mut older = older.peekable();
+        let mut newer = newer.peekable();
+        std::iter::from_fn(move || {
+            let order = match (older.peek(), newer.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(Err(_)), _) => std::cmp::Ordering::Less,
+                (_, Some(Err(_))) => std::cmp::Ordering::Greater,
+                (Some(Ok(a)), Some(Ok(b))) => a[..HASH_SIZE].cmp(&b[..HASH_SIZE]),
+            };
+            match order {
+                std::cmp::Ordering::Greater => newer.next(),
+                std::cmp::Ordering::Equal => {
+                    newer.next();
+                    older.next()
+                }
+                std::cmp::Ordering::Less => older.next(),
+            }
+        })
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use crate::storage::{BlockDB, DBIterator, KeyRange, ScanOptions};
+
+/// Sequence numbers of the live snapshots. Compaction keeps every record a
+/// snapshot in this list can still see.
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    /// Sequence number to the number of snapshots pinned at it
+    sequences: BTreeMap<u64, usize>,
+}
+
+impl SnapshotList {
+    pub fn acquire(&mut self, sequence: u64) {
+        *self.sequences.entry(sequence).or_insert(0) += 1;
+    }
+
+    pub fn release(&mut self, sequence: u64) {
+        if let Some(count) = self.sequences.get_mut(&sequence) {
+            *count -= 1;
+            if *count == 0 {
+                self.sequences.remove(&sequence);
+            }
+        }
+    }
+
+    /// Distinct pinned sequence numbers, ascending
+    pub fn sequences(&self) -> Vec<u64> {
+        self.sequences.keys().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty()
+    }
+}
+
+/// Consistent read-only view of the database as of one sequence number.
+/// Reads through it ignore every record written after it was taken; it is
+/// released when dropped.
+pub struct Snapshot<'a> {
+    db: &'a BlockDB,
+    sequence: u64,
+}
+
+impl<'a> Snapshot<'a> {
+    pub(crate) fn new(db: &'a BlockDB, sequence: u64) -> Self {
+        Snapshot { db, sequence }
+    }
+
+    /// Highest sequence number visible through this snapshot
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        self.db.get_visible_value(key, self.sequence)
+    }
+
+    /// Records in `[start, end)` in key order
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> Result<DBIterator, Box<dyn std::error::Error>> {
+        self.scan_with(KeyRange::new(start, end), &ScanOptions::default())
+    }
+
+    /// Records whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<DBIterator, Box<dyn std::error::Error>> {
+        self.scan_with(KeyRange::prefix(prefix), &ScanOptions::default())
+    }
+
+    pub fn scan_with(&self, range: KeyRange, options: &ScanOptions) -> Result<DBIterator, Box<dyn std::error::Error>> {
+        let options = ScanOptions {
+            snapshot: Some(self.sequence),
+            ..options.clone()
+        };
+        self.db.scan_with(range, &options)
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.db.release_snapshot(self.sequence);
+    }
+}
@@ -1,9 +1,39 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write, Seek, SeekFrom};
+use std::io::Write;
 use std::path::Path;
+use std::sync::Arc;
 use serde::{Serialize, Deserialize};
-use crate::storage::{Record, memtable::MemTable};
+use crate::storage::{Record, RecordV1, BlockDBConfig, memtable::MemTable};
+use crate::storage::bloom::{self, BloomFilter};
+use crate::storage::cache::BlockCache;
+use crate::storage::compression::Compression;
+use crate::storage::encryption::{self, Keyring, PLAINTEXT_KEY_ID};
+use crate::storage::iterator::KeyRange;
+
+/// Marks files written with a versioned footer; the oldest files end in a
+/// bare 16-byte `index_offset | index_size` footer
+const SSTABLE_MAGIC: u64 = 0x424c_4f43_4b44_4253; // "BLOCKDBS"
+/// Version 1: one record per index entry, full key index in memory.
+/// Version 2: checksummed data blocks with a sparse per-block index.
+/// Version 3: as version 2, with each record's kind.
+/// Version 4: as version 3, with each data block stored as `codec | payload`.
+/// Version 5: as version 4, with the id of the data key that sealed the
+/// blocks, index and filter in the footer.
+const FORMAT_VERSION_V1: u32 = 1;
+const FORMAT_VERSION_V2: u32 = 2;
+const FORMAT_VERSION_V3: u32 = 3;
+const FORMAT_VERSION_V4: u32 = 4;
+const FORMAT_VERSION_V5: u32 = 5;
+/// index_offset, index_size, filter_offset, filter_size, version, magic
+const FOOTER_SIZE: u64 = 8 * 4 + 4 + 8;
+/// As above with the key id before the version
+const FOOTER_SIZE_V5: u64 = FOOTER_SIZE + 4;
+/// version, magic: enough to tell which footer a file ends in
+const FOOTER_TRAILER_SIZE: u64 = 4 + 8;
+const LEGACY_FOOTER_SIZE: u64 = 16;
+/// Every v2 block, index and filter section is followed by a CRC32C
+const CHECKSUM_SIZE: u64 = 4;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexEntry {
@@ -12,142 +42,705 @@ pub struct IndexEntry {
     pub size: u32,
 }
 
+/// Location of one v2 data block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHandle {
+    /// Largest key stored in the block
+    pub last_key: Vec<u8>,
+    pub offset: u64,
+    /// Stored block length, excluding the trailing checksum
+    pub size: u32,
+}
+
+/// Sparse v2 index: one entry per data block instead of one per key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockIndex {
+    pub blocks: Vec<BlockHandle>,
+    pub first_key: Option<Vec<u8>>,
+    pub entry_count: u64,
+}
+
+#[derive(Debug)]
+enum TableIndex {
+    /// v1 layout: every key with the offset of its record
+    Full(BTreeMap<Vec<u8>, IndexEntry>),
+    /// v2 layout: data blocks located by their last key
+    Blocks(BlockIndex),
+}
+
+/// Settings applied when writing SSTables
+#[derive(Debug, Clone)]
+pub struct SSTableOptions {
+    /// Bloom filter bits per key; 0 writes no filter
+    pub bloom_bits_per_key: usize,
+    /// Target uncompressed size of a data block in bytes
+    pub block_size: usize,
+    /// Codec for data blocks
+    pub compression: Compression,
+    /// Seals new tables under its current key; `None` writes plaintext
+    pub keyring: Option<Arc<Keyring>>,
+    /// Cache for blocks read from opened tables; `None` reads uncached
+    pub block_cache: Option<Arc<BlockCache>>,
+}
+
+impl SSTableOptions {
+    pub fn from_config(config: &BlockDBConfig) -> Self {
+        SSTableOptions {
+            bloom_bits_per_key: config.bloom_bits_per_key,
+            block_size: config.sstable_block_size,
+            compression: config.compression,
+            keyring: None,
+            block_cache: None,
+        }
+    }
+}
+
+impl Default for SSTableOptions {
+    fn default() -> Self {
+        SSTableOptions::from_config(&BlockDBConfig::default())
+    }
+}
+
 #[derive(Debug)]
 pub struct SSTable {
-    path: String,
-    index: BTreeMap<Vec<u8>, IndexEntry>,
-    file: File,
+    index: TableIndex,
+    filter: Option<BloomFilter>,
+    table: Arc<TableFile>,
 }
 
 impl SSTable {
-    pub fn create_from_memtable(path: &str, memtable: &MemTable) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .read(true)
-            .open(path)?;
+    pub fn create_from_memtable(path: &str, memtable: &MemTable, options: &SSTableOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::write(path, memtable.iter().map(|(_, record)| record), options)?;
+        Self::open_with(path, options)
+    }
 
-        let mut index = BTreeMap::new();
-        let mut offset = 0u64;
-
-        for (key, record) in memtable.iter() {
-            let serialized = bincode::serialize(record)?;
-            let size = serialized.len() as u32;
-            
-            file.write_all(&size.to_be_bytes())?;
-            file.write_all(&serialized)?;
-            
-            index.insert(key.clone(), IndexEntry {
-                key: key.clone(),
-                offset,
-                size,
-            });
-            
-            offset += 4 + serialized.len() as u64;
-        }
-
-        let index_offset = offset;
-        let index_data = bincode::serialize(&index)?;
-        file.write_all(&index_data)?;
-        file.write_all(&index_offset.to_be_bytes())?;
-        file.write_all(&(index_data.len() as u64).to_be_bytes())?;
-        
-        file.flush()?;
-        file.seek(SeekFrom::Start(0))?;
+    /// Write `records`, which must be sorted by key, as a new SSTable at `path`.
+    ///
+    /// Layout (v5): `[block][crc]... [index][crc] [filter][crc] footer`, where
+    /// each block is `codec | payload` and its payload decompresses to
+    /// `key_len | key | record_len | record` entries. With a keyring every
+    /// block, the index and the filter are sealed, bound to their offset.
+    pub fn write<'a, I>(path: &str, records: I, options: &SSTableOptions) -> Result<(), Box<dyn std::error::Error>>
+    where
+        I: IntoIterator<Item = &'a Record>,
+    {
+        let mut writer = SSTableWriter::create(path, options)?;
+        for record in records {
+            writer.add(record)?;
+        }
+        writer.finish()
+    }
 
-        Ok(SSTable {
-            path: path.to_string(),
-            index,
-            file,
+    fn write_block(file: &mut File, offset: &mut u64, block: &[u8], last_key: &[u8], compression: Compression, keyring: Option<&Keyring>) -> Result<BlockHandle, Box<dyn std::error::Error>> {
+        let block_offset = *offset;
+        let size = Self::write_section(file, offset, &compression.encode(block)?, keyring)?;
+
+        Ok(BlockHandle {
+            last_key: last_key.to_vec(),
+            offset: block_offset,
+            size,
         })
     }
 
+    /// Write `data` at `offset`, sealed if there is a keyring, followed by
+    /// its checksum. Returns the stored size.
+    fn write_section(file: &mut File, offset: &mut u64, data: &[u8], keyring: Option<&Keyring>) -> Result<u32, Box<dyn std::error::Error>> {
+        let sealed;
+        let stored = match keyring {
+            Some(keyring) => {
+                sealed = keyring.seal(data, &offset.to_be_bytes())?;
+                sealed.as_slice()
+            }
+            None => data,
+        };
+
+        file.write_all(stored)?;
+        file.write_all(&crc32c::crc32c(stored).to_be_bytes())?;
+        *offset += stored.len() as u64 + CHECKSUM_SIZE;
+
+        Ok(stored.len() as u32)
+    }
+
+    /// Open a table written without encryption, reading it uncached
     pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut file = File::open(path)?;
-        let _file_size = file.metadata()?.len();
-        
-        file.seek(SeekFrom::End(-16))?;
-        let mut footer = [0u8; 16];
-        file.read_exact(&mut footer)?;
-        
-        let index_offset = u64::from_be_bytes([footer[0], footer[1], footer[2], footer[3], footer[4], footer[5], footer[6], footer[7]]);
-        let index_size = u64::from_be_bytes([footer[8], footer[9], footer[10], footer[11], footer[12], footer[13], footer[14], footer[15]]);
-        
-        file.seek(SeekFrom::Start(index_offset))?;
-        let mut index_data = vec![0u8; index_size as usize];
-        file.read_exact(&mut index_data)?;
-        
-        let index: BTreeMap<Vec<u8>, IndexEntry> = bincode::deserialize(&index_data)?;
-        
-        file.seek(SeekFrom::Start(0))?;
-        
-        Ok(SSTable {
+        Self::open_with(path, &SSTableOptions::default())
+    }
+
+    /// Open a table, reading it through `options.block_cache` and with
+    /// `options.keyring` if it is sealed
+    pub fn open_with(path: &str, options: &SSTableOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let too_small = || crate::error::BlockDBError::Corruption(format!("SSTable {} is too small", path));
+        let keyring = options.keyring.clone();
+
+        let mut trailer = [0u8; FOOTER_TRAILER_SIZE as usize];
+        if file_size >= FOOTER_SIZE {
+            read_at(&file, file_size - FOOTER_TRAILER_SIZE, &mut trailer)?;
+        }
+
+        let (version, index_offset, index_size, filter_offset, filter_size, key_id) = if u64::from_be_bytes(trailer[4..12].try_into().unwrap()) == SSTABLE_MAGIC {
+            let version = u32::from_be_bytes(trailer[0..4].try_into().unwrap());
+            if !(FORMAT_VERSION_V1..=FORMAT_VERSION_V5).contains(&version) {
+                return Err(Box::new(crate::error::BlockDBError::InvalidData(
+                    format!("Unsupported SSTable format version {} in {}", version, path)
+                )));
+            }
+
+            let footer_size = if version >= FORMAT_VERSION_V5 { FOOTER_SIZE_V5 } else { FOOTER_SIZE };
+            let mut footer = vec![0u8; footer_size as usize];
+            read_at(&file, file_size.checked_sub(footer_size).ok_or_else(too_small)?, &mut footer)?;
+
+            let key_id = if version >= FORMAT_VERSION_V5 {
+                u32::from_be_bytes(footer[32..36].try_into().unwrap())
+            } else {
+                PLAINTEXT_KEY_ID
+            };
+            encryption::check_key(keyring.as_deref(), key_id, path)?;
+
+            (
+                version,
+                u64::from_be_bytes(footer[0..8].try_into().unwrap()),
+                u64::from_be_bytes(footer[8..16].try_into().unwrap()),
+                u64::from_be_bytes(footer[16..24].try_into().unwrap()),
+                u64::from_be_bytes(footer[24..32].try_into().unwrap()),
+                key_id,
+            )
+        } else {
+            // Files written before filters existed
+            let mut footer = [0u8; LEGACY_FOOTER_SIZE as usize];
+            read_at(&file, file_size.checked_sub(LEGACY_FOOTER_SIZE).ok_or_else(too_small)?, &mut footer)?;
+
+            (
+                FORMAT_VERSION_V1,
+                u64::from_be_bytes(footer[0..8].try_into().unwrap()),
+                u64::from_be_bytes(footer[8..16].try_into().unwrap()),
+                0,
+                0,
+                PLAINTEXT_KEY_ID,
+            )
+        };
+
+        let table = TableFile {
             path: path.to_string(),
-            index,
+            version,
+            key_id,
+            keyring,
             file,
+            cache_id: options.block_cache.as_ref().map_or(0, |cache| cache.new_table_id()),
+            cache: options.block_cache.clone(),
+        };
+        let checksummed = version >= FORMAT_VERSION_V2;
+
+        let index_data = table.read_section(index_offset, index_size, checksummed)?;
+        let index_data = table.unseal(index_data, index_offset)?;
+        let index = if checksummed {
+            TableIndex::Blocks(bincode::deserialize(&index_data)?)
+        } else {
+            TableIndex::Full(bincode::deserialize(&index_data)?)
+        };
+
+        let filter = if filter_size > 0 {
+            let filter_data = table.read_section(filter_offset, filter_size, checksummed)?;
+            let filter_data = table.unseal(filter_data, filter_offset)?;
+            Some(bincode::deserialize(&filter_data)?)
+        } else {
+            None
+        };
+
+        Ok(SSTable {
+            index,
+            filter,
+            table: Arc::new(table),
         })
     }
 
-    pub fn get(&mut self, key: &[u8]) -> Result<Option<Record>, Box<dyn std::error::Error>> {
-        if let Some(entry) = self.index.get(key) {
-            self.file.seek(SeekFrom::Start(entry.offset))?;
-            
-            let mut size_buf = [0u8; 4];
-            self.file.read_exact(&mut size_buf)?;
-            let size = u32::from_be_bytes(size_buf);
-            
-            let mut record_buf = vec![0u8; size as usize];
-            self.file.read_exact(&mut record_buf)?;
-            
-            let record: Record = bincode::deserialize(&record_buf)?;
-            Ok(Some(record))
+    /// Decode a record stored by a table of format `version`
+    fn decode_record(version: u32, data: &[u8]) -> Result<Record, Box<dyn std::error::Error>> {
+        if version >= FORMAT_VERSION_V3 {
+            Ok(bincode::deserialize(data)?)
         } else {
-            Ok(None)
+            Ok(bincode::deserialize::<RecordV1>(data)?.into())
+        }
+    }
+
+    /// Decode the `key_len | key | record_len | record` entries of a v2 block,
+    /// stopping early once `visit` returns false
+    fn for_each_entry<F>(block: &[u8], mut visit: F) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool, Box<dyn std::error::Error>>,
+    {
+        let corrupt = || crate::error::BlockDBError::Corruption("Truncated SSTable block entry".to_string());
+        let mut pos = 0;
+
+        while pos < block.len() {
+            let key_len = u32::from_be_bytes(block.get(pos..pos + 4).ok_or_else(corrupt)?.try_into().unwrap()) as usize;
+            pos += 4;
+            let key = block.get(pos..pos + key_len).ok_or_else(corrupt)?;
+            pos += key_len;
+            let record_len = u32::from_be_bytes(block.get(pos..pos + 4).ok_or_else(corrupt)?.try_into().unwrap()) as usize;
+            pos += 4;
+            let record = block.get(pos..pos + record_len).ok_or_else(corrupt)?;
+            pos += record_len;
+
+            if !visit(key, record)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Index of the first block whose last key is >= `key`
+    fn block_for_key(index: &BlockIndex, key: &[u8]) -> usize {
+        index.blocks.partition_point(|handle| handle.last_key.as_slice() < key)
+    }
+
+    /// False only if the key is definitely absent from this table
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.may_contain(key))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+
+        let version = self.table.version;
+
+        match &self.index {
+            TableIndex::Full(index) => match index.get(key) {
+                Some(entry) => Ok(Some(self.table.read_record(entry.offset)?)),
+                None => Ok(None),
+            },
+            TableIndex::Blocks(index) => {
+                let handle = match index.blocks.get(Self::block_for_key(index, key)) {
+                    Some(handle) => handle,
+                    None => return Ok(None),
+                };
+
+                let block = self.table.block(handle)?;
+                let mut found = None;
+                Self::for_each_entry(&block, |entry_key, record| {
+                    if entry_key == key {
+                        found = Some(Self::decode_record(version, record)?);
+                    }
+                    Ok(entry_key < key)
+                })?;
+                Ok(found)
+            }
         }
     }
 
-    pub fn scan_range(&mut self, start: &[u8], end: &[u8]) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+    pub fn scan_range(&self, start: &[u8], end: &[u8]) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
         let mut results = Vec::new();
-        
-        for (_key, entry) in self.index.range(start.to_vec()..end.to_vec()) {
-            self.file.seek(SeekFrom::Start(entry.offset))?;
-            
-            let mut size_buf = [0u8; 4];
-            self.file.read_exact(&mut size_buf)?;
-            let size = u32::from_be_bytes(size_buf);
-            
-            let mut record_buf = vec![0u8; size as usize];
-            self.file.read_exact(&mut record_buf)?;
-            
-            let record: Record = bincode::deserialize(&record_buf)?;
-            results.push(record);
-        }
-        
+        let version = self.table.version;
+
+        match &self.index {
+            TableIndex::Full(index) => {
+                for (_key, entry) in index.range(start.to_vec()..end.to_vec()) {
+                    results.push(self.table.read_record(entry.offset)?);
+                }
+            }
+            TableIndex::Blocks(index) => {
+                for handle in &index.blocks[Self::block_for_key(index, start)..] {
+                    let block = self.table.block(handle)?;
+                    let mut past_end = false;
+                    Self::for_each_entry(&block, |key, record| {
+                        if key >= end {
+                            past_end = true;
+                            return Ok(false);
+                        }
+                        if key >= start {
+                            results.push(Self::decode_record(version, record)?);
+                        }
+                        Ok(true)
+                    })?;
+                    if past_end {
+                        break;
+                    }
+                }
+            }
+        }
+
         Ok(results)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Vec<u8>> {
-        self.index.keys()
+    /// Iterate over the records in `range` without loading them all. The
+    /// iterator shares this table's open file, so it stays valid after the
+    /// table is compacted away and its file deleted.
+    pub fn iter(&self, range: KeyRange, reverse: bool) -> Result<SSTableIter, Box<dyn std::error::Error>> {
+        let mut pending: VecDeque<Location> = if range.is_empty() {
+            VecDeque::new()
+        } else {
+            match &self.index {
+                TableIndex::Full(index) => index
+                    .range(range.bounds())
+                    .map(|(_, entry)| Location::Record(entry.offset))
+                    .collect(),
+                TableIndex::Blocks(index) => {
+                    let first = match &range.start {
+                        std::ops::Bound::Included(start) | std::ops::Bound::Excluded(start) => Self::block_for_key(index, start),
+                        std::ops::Bound::Unbounded => 0,
+                    };
+                    let mut blocks = VecDeque::new();
+                    for handle in &index.blocks[first..] {
+                        blocks.push_back(Location::Block(handle.clone()));
+                        // Later blocks only hold larger keys
+                        if range.is_past_end(&handle.last_key) {
+                            break;
+                        }
+                    }
+                    blocks
+                }
+            }
+        };
+        if reverse {
+            pending.make_contiguous().reverse();
+        }
+
+        Ok(SSTableIter {
+            table: self.table.clone(),
+            range,
+            reverse,
+            cached: true,
+            pending,
+            buffered: VecDeque::new(),
+        })
+    }
+
+    /// Stream every record in key order. Blocks are read around the cache
+    /// so a full pass, as in compaction, does not evict the working set.
+    pub fn scan_uncached(&self) -> Result<SSTableIter, Box<dyn std::error::Error>> {
+        let mut iter = self.iter(KeyRange::all(), false)?;
+        iter.cached = false;
+        Ok(iter)
+    }
+
+    /// Read every record in key order, around the cache
+    pub fn records(&self) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+        self.scan_uncached()?.collect()
     }
 
     pub fn size(&self) -> usize {
-        self.index.len()
+        match &self.index {
+            TableIndex::Full(index) => index.len(),
+            TableIndex::Blocks(index) => index.entry_count as usize,
+        }
     }
 
     pub fn path(&self) -> &str {
-        &self.path
+        &self.table.path
+    }
+
+    /// File name relative to the data directory, as recorded in the manifest
+    pub fn file_name(&self) -> &str {
+        Path::new(&self.table.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.table.path)
     }
 
-    pub fn contains_key(&self, key: &[u8]) -> bool {
-        self.index.contains_key(key)
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.get(key)?.is_some())
     }
 
     pub fn first_key(&self) -> Option<&Vec<u8>> {
-        self.index.keys().next()
+        match &self.index {
+            TableIndex::Full(index) => index.keys().next(),
+            TableIndex::Blocks(index) => index.first_key.as_ref(),
+        }
     }
 
     pub fn last_key(&self) -> Option<&Vec<u8>> {
-        self.index.keys().last()
+        match &self.index {
+            TableIndex::Full(index) => index.keys().last(),
+            TableIndex::Blocks(index) => index.blocks.last().map(|handle| &handle.last_key),
+        }
+    }
+}
+
+/// Read exactly `buf.len()` bytes at `offset`. Positional reads leave no
+/// shared cursor, so concurrent readers need no lock.
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, mut offset: u64, mut buf: &mut [u8]) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An open table file and what is needed to decode it, shared by the table
+/// and its iterators
+#[derive(Debug)]
+struct TableFile {
+    path: String,
+    /// Format version the file was written with
+    version: u32,
+    /// Data key the sections are sealed with, or `PLAINTEXT_KEY_ID`
+    key_id: u32,
+    keyring: Option<Arc<Keyring>>,
+    file: File,
+    cache: Option<Arc<BlockCache>>,
+    /// Id of this table's blocks in `cache`
+    cache_id: u64,
+}
+
+impl TableFile {
+    /// Read `size` bytes at `offset`, verifying the trailing CRC32C if present
+    fn read_section(&self, offset: u64, size: u64, checksummed: bool) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let checksum_size = if checksummed { CHECKSUM_SIZE } else { 0 };
+        let mut data = vec![0u8; (size + checksum_size) as usize];
+        read_at(&self.file, offset, &mut data)?;
+
+        if checksummed {
+            let checksum = data.split_off(size as usize);
+            if u32::from_be_bytes(checksum.try_into().unwrap()) != crc32c::crc32c(&data) {
+                return Err(Box::new(crate::error::BlockDBError::Corruption(
+                    format!("Checksum mismatch in {} at offset {}", self.path, offset)
+                )));
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn unseal(&self, data: Vec<u8>, offset: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        encryption::unseal(self.keyring.as_deref(), self.key_id, data, &offset.to_be_bytes())
+    }
+
+    /// A data block, from the cache if it is there
+    fn block(&self, handle: &BlockHandle) -> Result<Arc<Vec<u8>>, Box<dyn std::error::Error>> {
+        let Some(cache) = &self.cache else {
+            return Ok(Arc::new(self.load_block(handle)?));
+        };
+        if let Some(block) = cache.get(self.cache_id, handle.offset) {
+            return Ok(block);
+        }
+
+        let block = Arc::new(self.load_block(handle)?);
+        cache.insert(self.cache_id, handle.offset, block.clone());
+        Ok(block)
+    }
+
+    /// Read, verify, decrypt and decompress one data block
+    fn load_block(&self, handle: &BlockHandle) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let stored = self.read_section(handle.offset, handle.size as u64, true)?;
+        let stored = self.unseal(stored, handle.offset)?;
+        if self.version >= FORMAT_VERSION_V4 {
+            Compression::decode(&stored)
+        } else {
+            Ok(stored)
+        }
+    }
+
+    /// Read a single v1 `record_len | record` entry
+    fn read_record(&self, offset: u64) -> Result<Record, Box<dyn std::error::Error>> {
+        let mut size_buf = [0u8; 4];
+        read_at(&self.file, offset, &mut size_buf)?;
+        let size = u32::from_be_bytes(size_buf);
+
+        let mut record_buf = vec![0u8; size as usize];
+        read_at(&self.file, offset + 4, &mut record_buf)?;
+
+        SSTable::decode_record(self.version, &record_buf)
+    }
+}
+
+/// Where the next records of an [`SSTableIter`] are stored
+#[derive(Debug, Clone)]
+enum Location {
+    /// A v2 data block
+    Block(BlockHandle),
+    /// A single v1 `record_len | record` entry
+    Record(u64),
+}
+
+/// Writes an SSTable one record at a time, so a table larger than memory,
+/// such as a compaction output, never has to be held whole
+pub struct SSTableWriter {
+    file: File,
+    options: SSTableOptions,
+    index: BlockIndex,
+    key_hashes: Vec<u64>,
+    block: Vec<u8>,
+    block_last_key: Vec<u8>,
+    offset: u64,
+}
+
+impl SSTableWriter {
+    pub fn create(path: &str, options: &SSTableOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(SSTableWriter {
+            file,
+            options: options.clone(),
+            index: BlockIndex {
+                blocks: Vec::new(),
+                first_key: None,
+                entry_count: 0,
+            },
+            key_hashes: Vec::new(),
+            block: Vec::with_capacity(options.block_size),
+            block_last_key: Vec::new(),
+            offset: 0,
+        })
+    }
+
+    /// Append `record`, whose key must not sort before the previous one's
+    pub fn add(&mut self, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = bincode::serialize(record)?;
+
+        self.block.extend_from_slice(&(record.key.len() as u32).to_be_bytes());
+        self.block.extend_from_slice(&record.key);
+        self.block.extend_from_slice(&(serialized.len() as u32).to_be_bytes());
+        self.block.extend_from_slice(&serialized);
+
+        if self.index.first_key.is_none() {
+            self.index.first_key = Some(record.key.clone());
+        }
+        self.index.entry_count += 1;
+        self.block_last_key.clear();
+        self.block_last_key.extend_from_slice(&record.key);
+        if self.options.bloom_bits_per_key > 0 {
+            self.key_hashes.push(bloom::hash_key(&record.key));
+        }
+
+        if self.block.len() >= self.options.block_size {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far plus the block being built
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn flush_block(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let keyring = self.options.keyring.as_deref();
+        let handle = SSTable::write_block(&mut self.file, &mut self.offset, &self.block, &self.block_last_key, self.options.compression, keyring)?;
+        self.index.blocks.push(handle);
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write the last block, the index, the filter and the footer, and sync
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.block.is_empty() {
+            self.flush_block()?;
+        }
+
+        let keyring = self.options.keyring.as_deref();
+        let file = &mut self.file;
+        let offset = &mut self.offset;
+
+        let index_offset = *offset;
+        let index_size = SSTable::write_section(file, offset, &bincode::serialize(&self.index)?, keyring)?;
+
+        let filter_offset = *offset;
+        let filter_size = if self.options.bloom_bits_per_key > 0 {
+            let filter = BloomFilter::from_hashes(&self.key_hashes, self.options.bloom_bits_per_key);
+            SSTable::write_section(file, offset, &bincode::serialize(&filter)?, keyring)?
+        } else {
+            0
+        };
+
+        file.write_all(&index_offset.to_be_bytes())?;
+        file.write_all(&(index_size as u64).to_be_bytes())?;
+        file.write_all(&filter_offset.to_be_bytes())?;
+        file.write_all(&(filter_size as u64).to_be_bytes())?;
+        file.write_all(&keyring.map_or(PLAINTEXT_KEY_ID, |keyring| keyring.current_id()).to_be_bytes())?;
+        file.write_all(&FORMAT_VERSION_V5.to_be_bytes())?;
+        file.write_all(&SSTABLE_MAGIC.to_be_bytes())?;
+
+        file.flush()?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+/// Streams records in key order from one SSTable, one block at a time
+pub struct SSTableIter {
+    table: Arc<TableFile>,
+    range: KeyRange,
+    reverse: bool,
+    /// Read blocks through the table's cache
+    cached: bool,
+    /// Blocks or records still to read, in iteration order
+    pending: VecDeque<Location>,
+    /// Records decoded from the current block, in iteration order
+    buffered: VecDeque<Record>,
+}
+
+impl SSTableIter {
+    fn fill(&mut self, location: Location) -> Result<(), Box<dyn std::error::Error>> {
+        match location {
+            Location::Block(handle) => {
+                let block = if self.cached {
+                    self.table.block(&handle)?
+                } else {
+                    Arc::new(self.table.load_block(&handle)?)
+                };
+                let range = &self.range;
+                let buffered = &mut self.buffered;
+                let reverse = self.reverse;
+                let version = self.table.version;
+                SSTable::for_each_entry(&block, |key, record| {
+                    if range.is_past_end(key) {
+                        return Ok(false);
+                    }
+                    if !range.is_before_start(key) {
+                        let record = SSTable::decode_record(version, record)?;
+                        if reverse {
+                            buffered.push_front(record);
+                        } else {
+                            buffered.push_back(record);
+                        }
+                    }
+                    Ok(true)
+                })?;
+            }
+            Location::Record(offset) => {
+                self.buffered.push_back(self.table.read_record(offset)?);
+            }
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Iterator for SSTableIter {
+    type Item = Result<Record, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.buffered.pop_front() {
+                return Some(Ok(record));
+            }
+
+            let location = self.pending.pop_front()?;
+            if let Err(e) = self.fill(location) {
+                self.pending.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
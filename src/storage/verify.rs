@@ -0,0 +1,51 @@
+use serde::{Serialize, Deserialize};
+
+/// What deep verification found wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TamperKind {
+    /// A block's hash or Merkle root does not match its records
+    BlockHash,
+    /// A block does not follow the one before it
+    ChainLink,
+    /// A record's hash does not match its key, value, timestamp and
+    /// sequence number
+    RecordHash,
+    /// A stored record is not in any block
+    NotInChain,
+    /// A separated value could not be read back
+    UnreadableBlob,
+}
+
+/// The first tampered block, record and file found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tamper {
+    pub kind: TamperKind,
+    /// Block holding the problem, or the block the record was sealed in
+    pub block: Option<u64>,
+    pub key: Option<Vec<u8>>,
+    pub sequence_number: Option<u64>,
+    /// Chain segment, SSTable or `memtable` the problem was found in
+    pub file: Option<String>,
+    pub detail: String,
+}
+
+/// Result of [`crate::storage::BlockDB::verify_deep`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub blocks_checked: u64,
+    /// Records in the chain whose hashes were recomputed
+    pub chain_records_checked: u64,
+    /// Memtable and SSTable records whose hashes were recomputed and
+    /// found in the chain
+    pub stored_records_checked: u64,
+    /// Stored records not yet sealed, for which only the hash was checked
+    pub pending_records: u64,
+    /// First problem found; verification stops there
+    pub tamper: Option<Tamper>,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.tamper.is_none()
+    }
+}
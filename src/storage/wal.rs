@@ -1,81 +1,565 @@
-use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Write, Seek, SeekFrom};
 use std::path::Path;
-use serde::{Serialize, Deserialize};
-use crate::storage::Record;
+use std::sync::{Arc, Condvar, Mutex};
+use crate::storage::{Record, RecordV1};
+use crate::storage::compression::Compression;
+use crate::storage::encryption::{self, Keyring, PLAINTEXT_KEY_ID};
 
+/// "BDBW" followed by the format version and, from version 6, the id of
+/// the data key frames are sealed with
+const WAL_MAGIC: [u8; 4] = *b"BDBW";
+/// Version 2: each frame holds one record.
+/// Version 3: each frame holds the records of one write, so a batch is
+/// replayed entirely or not at all.
+/// Version 4: as version 3, with each record's kind.
+/// Version 5: as version 4, with each frame payload stored as `codec | payload`.
+/// Version 6: as version 5, with each payload sealed, bound to its frame
+/// offset, when the header names a data key.
+const WAL_VERSION_V2: u32 = 2;
+const WAL_VERSION_V3: u32 = 3;
+const WAL_VERSION_V4: u32 = 4;
+const WAL_VERSION_V5: u32 = 5;
+const WAL_VERSION: u32 = 6;
+/// Pseudo-version for logs written before the header existed
+const WAL_VERSION_LEGACY: u32 = 0;
 const WAL_HEADER_SIZE: usize = 8;
-const WAL_RECORD_HEADER_SIZE: usize = 4;
+const WAL_KEY_ID_SIZE: usize = 4;
+/// Frame header: payload length followed by its CRC32C
+const WAL_RECORD_HEADER_SIZE: usize = 8;
 
+/// How recovery treats a WAL that ends in an invalid frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalRecoveryMode {
+    /// Keep every record before the first invalid frame and truncate the
+    /// rest, which is what a crash in the middle of an append leaves behind
+    TolerateTornTail,
+    /// Refuse to open if any frame is invalid
+    Strict,
+}
+
+/// What has happened to a write by the time `put` returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalDurability {
+    /// Left in the process buffer; written out by the periodic WAL sync.
+    /// Lost if the process dies before then.
+    None,
+    /// Handed to the OS once per commit batch; survives a process crash
+    /// but not a power failure until the periodic WAL sync runs
+    Flush,
+    /// Fsynced once per commit batch before any writer in it returns
+    Fsync,
+}
+
+/// Queue shared by concurrent writers for group commit. The first writer to
+/// find no commit in progress becomes the leader and writes every queued
+/// write with a single sync; the others wait until their sequence numbers
+/// are durable.
+#[derive(Debug, Default)]
+pub struct GroupCommit {
+    pub state: Mutex<GroupCommitState>,
+    /// Signalled whenever a batch finishes or leadership is released
+    pub committed: Condvar,
+}
+
+#[derive(Debug, Default)]
+pub struct GroupCommitState {
+    /// Writes with assigned sequence numbers waiting to be logged, in
+    /// order; each becomes one WAL frame
+    pub pending: Vec<QueuedWrite>,
+    /// Keys of queued records not yet visible in the memtable, and of
+    /// committed ones a key check in progress may have missed, with the
+    /// sequence number of the last write and whether it leaves the key live
+    /// (false for a tombstone)
+    pub pending_keys: HashMap<Vec<u8>, (u64, bool)>,
+    /// Writers checking their keys against the stored data outside the
+    /// lock, counted by the durable sequence number they started at
+    pub key_checks: BTreeMap<u64, usize>,
+    /// A leader is currently writing a batch
+    pub leader_active: bool,
+    /// Every sequence number up to this one has been committed
+    pub durable_sequence: u64,
+    /// Blockchain block of each committed write, keyed by its last sequence
+    /// number, until its writer collects it
+    pub block_indexes: HashMap<u64, u64>,
+    /// Set when a batch fails to reach the log; later writes are refused
+    /// because the state of the log is unknown
+    pub error: Option<String>,
+}
+
+impl GroupCommitState {
+    /// Start checking keys against the stored data, which holds every
+    /// write up to the returned sequence number. Keys committed after it
+    /// stay in `pending_keys` until the check ends.
+    pub fn begin_key_check(&mut self) -> u64 {
+        *self.key_checks.entry(self.durable_sequence).or_default() += 1;
+        self.durable_sequence
+    }
+
+    /// End a check started at `sequence`
+    pub fn end_key_check(&mut self, sequence: u64) {
+        if let Some(count) = self.key_checks.get_mut(&sequence) {
+            *count -= 1;
+            if *count == 0 {
+                self.key_checks.remove(&sequence);
+            }
+        }
+        self.forget_committed_keys();
+    }
+
+    /// Drop committed keys that every check in progress already sees in
+    /// the stored data
+    pub fn forget_committed_keys(&mut self) {
+        let visible = self.key_checks
+            .keys()
+            .next()
+            .map_or(self.durable_sequence, |&oldest| oldest.min(self.durable_sequence));
+        self.pending_keys.retain(|_, &mut (sequence, _)| sequence > visible);
+    }
+}
+
+/// Records of one write waiting for the group commit
+#[derive(Debug)]
+pub struct QueuedWrite {
+    /// Records as logged and stored, where a separated value is a pointer
+    pub records: Vec<Record>,
+    /// The records with the digest of each separated value in place of its
+    /// pointer, if there are any. The chain seals these, so its hashes
+    /// never depend on where garbage collection moves a value.
+    pub sealed: Option<Vec<Record>>,
+}
+
+impl QueuedWrite {
+    /// Records to seal into the chain
+    pub fn sealed_records(&self) -> &[Record] {
+        self.sealed.as_deref().unwrap_or(&self.records)
+    }
+}
+
+/// Outcome of replaying the WAL
+#[derive(Debug, Default)]
+pub struct WalRecovery {
+    pub records: Vec<Record>,
+    /// Bytes dropped from the end of the log after the last valid frame
+    pub discarded_bytes: u64,
+}
+
+/// Records of one write, as logged in a single frame
+type Frame = Vec<Record>;
+
+/// Records read from one segment
+struct SegmentContents {
+    /// Records of each valid frame
+    frames: Vec<Frame>,
+    /// Length of the prefix made of valid frames
+    valid_len: u64,
+    file_len: u64,
+    /// Format version from the header
+    version: u32,
+    /// Data key the frames are sealed with
+    key_id: u32,
+}
+
+/// The WAL is a sequence of numbered segments. Appends go to the newest
+/// one; `rotate` starts a new segment when the memtable is swapped, and
+/// older segments are deleted once the manifest records that their records
+/// are in an SSTable.
 #[derive(Debug)]
 pub struct WriteAheadLog {
+    data_dir: String,
     file: BufWriter<File>,
+    /// Path and number of the segment being appended to
     path: String,
+    log_number: u64,
     offset: u64,
+    /// Codec for new frames
+    compression: Compression,
+    keyring: Option<Arc<Keyring>>,
+    /// Data key new frames are sealed with, or `PLAINTEXT_KEY_ID`
+    key_id: u32,
 }
 
+/// Single-file log written before segmentation
+const LEGACY_WAL_FILE: &str = "wal.log";
+
 impl WriteAheadLog {
-    pub fn new(data_dir: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let path = format!("{}/wal.log", data_dir);
-        
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?;
-        
+    /// Open the log in `data_dir`. New frames are compressed with
+    /// `compression` and sealed under the current key of `keyring`, if any.
+    pub fn new(data_dir: &str, compression: Compression, keyring: Option<Arc<Keyring>>) -> Result<Self, Box<dyn std::error::Error>> {
+        // A pre-segmentation log becomes segment 0, which sorts before
+        // every segment created from now on
+        let legacy_path = format!("{}/{}", data_dir, LEGACY_WAL_FILE);
+        if Path::new(&legacy_path).exists() && Self::list_segments(data_dir)?.is_empty() {
+            fs::rename(&legacy_path, Self::segment_path(data_dir, 0))?;
+        }
+
+        let log_number = Self::list_segments(data_dir)?.last().copied().unwrap_or(1);
+        let path = Self::segment_path(data_dir, log_number);
+
+        let file = Self::open_append(&path)?;
+
         let mut wal = WriteAheadLog {
+            data_dir: data_dir.to_string(),
             file: BufWriter::new(file),
             path,
+            log_number,
             offset: 0,
+            compression,
+            key_id: keyring.as_ref().map_or(PLAINTEXT_KEY_ID, |keyring| keyring.current_id()),
+            keyring,
         };
-        
+
         wal.offset = wal.file.get_ref().metadata()?.len();
-        
+        if wal.offset == 0 {
+            wal.write_header()?;
+        }
+
         Ok(wal)
     }
 
-    pub fn append(&mut self, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
-        let serialized = bincode::serialize(record)?;
+    pub fn segment_path(data_dir: &str, log_number: u64) -> String {
+        format!("{}/wal_{:06}.log", data_dir, log_number)
+    }
+
+    /// Numbers of the segments present in `data_dir`, oldest first
+    pub fn list_segments(data_dir: &str) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(data_dir)? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(number) = file_name
+                .strip_prefix("wal_")
+                .and_then(|rest| rest.strip_suffix(".log"))
+                .and_then(|number| number.parse::<u64>().ok())
+            {
+                segments.push(number);
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Number of the segment currently being appended to
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    /// Sync the current segment and continue in a new one, returning its
+    /// number. Every record written before the call is in an older segment.
+    pub fn rotate(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        self.sync()?;
+
+        let log_number = self.log_number + 1;
+        let path = Self::segment_path(&self.data_dir, log_number);
+        self.file = BufWriter::new(Self::open_append(&path)?);
+        self.path = path;
+        self.log_number = log_number;
+        self.write_header()?;
+        self.sync()?;
+        File::open(&self.data_dir)?.sync_all()?;
+
+        Ok(log_number)
+    }
+
+    /// Delete every segment older than `log_number`
+    pub fn remove_segments_before(&self, log_number: u64) -> Result<(), Box<dyn std::error::Error>> {
+        for number in Self::list_segments(&self.data_dir)? {
+            if number < log_number && number != self.log_number {
+                fs::remove_file(Self::segment_path(&self.data_dir, number))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_append(path: &str) -> Result<File, Box<dyn std::error::Error>> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?)
+    }
+
+    fn write_header(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.write_all(&WAL_MAGIC)?;
+        self.file.write_all(&WAL_VERSION.to_be_bytes())?;
+        self.file.write_all(&self.key_id.to_be_bytes())?;
+        self.file.flush()?;
+        self.offset = (WAL_HEADER_SIZE + WAL_KEY_ID_SIZE) as u64;
+        Ok(())
+    }
+
+    pub fn append(&mut self, records: &[Record]) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_frame(records)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Buffer the records of one write as a single frame without flushing;
+    /// pair with [`Self::commit`]
+    pub fn write_frame(&mut self, records: &[Record]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut serialized = self.compression.encode(&bincode::serialize(records)?)?;
+        if let Some(keyring) = self.keyring.as_ref().filter(|_| self.key_id != PLAINTEXT_KEY_ID) {
+            serialized = keyring.seal(&serialized, &self.offset.to_be_bytes())?;
+        }
         let record_size = serialized.len() as u32;
-        
+
         self.file.write_all(&record_size.to_be_bytes())?;
+        self.file.write_all(&crc32c::crc32c(&serialized).to_be_bytes())?;
         self.file.write_all(&serialized)?;
-        self.file.flush()?;
-        
+
         self.offset += WAL_RECORD_HEADER_SIZE as u64 + serialized.len() as u64;
-        
+
         Ok(())
     }
 
-    pub fn recover(&self) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
-        let file = File::open(&self.path)?;
+    /// Make buffered records as durable as `durability` requires
+    pub fn commit(&mut self, durability: WalDurability) -> Result<(), Box<dyn std::error::Error>> {
+        match durability {
+            WalDurability::None => {}
+            WalDurability::Flush => self.file.flush()?,
+            WalDurability::Fsync => {
+                self.file.flush()?;
+                self.file.get_mut().sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay every segment from `min_log_number` on, deleting older ones
+    /// first: their records are already in SSTables.
+    ///
+    /// Reading a segment stops at the first frame that is incomplete, fails
+    /// its checksum or does not decode. Only the segment being appended to
+    /// can legitimately end that way; in `TolerateTornTail` mode it is
+    /// truncated there, in `Strict` mode recovery fails instead. Older
+    /// segments were synced before rotation, so a bad frame in one of them
+    /// always fails recovery.
+    pub fn recover(&mut self, mode: WalRecoveryMode, min_log_number: u64) -> Result<WalRecovery, Box<dyn std::error::Error>> {
+        self.remove_segments_before(min_log_number)?;
+
+        let mut recovery = WalRecovery::default();
+        let mut rotate = false;
+        for number in Self::list_segments(&self.data_dir)? {
+            let path = Self::segment_path(&self.data_dir, number);
+            let active = number == self.log_number;
+            let SegmentContents { frames, valid_len, file_len, version, key_id } = Self::read_segment(&path, self.keyring.as_deref())?;
+            let discarded_bytes = file_len - valid_len;
+
+            if discarded_bytes > 0 {
+                let mode = if active { mode } else { WalRecoveryMode::Strict };
+                Self::check_discard(&path, mode, discarded_bytes, valid_len)?;
+            }
+
+            if version == WAL_VERSION_LEGACY {
+                // Rewrite in the framed format so new appends are
+                // checksummed; this also drops any torn tail
+                self.rewrite(&path, &frames)?;
+            } else if active && discarded_bytes > 0 {
+                self.file.flush()?;
+                self.file.get_mut().set_len(valid_len)?;
+                self.file.get_mut().sync_all()?;
+                self.offset = valid_len;
+            }
+            // New frames must not be appended to a segment of an older
+            // version or sealed under another key
+            rotate |= active && version != WAL_VERSION_LEGACY && (version != WAL_VERSION || key_id != self.key_id);
+
+            recovery.records.extend(frames.into_iter().flatten());
+            recovery.discarded_bytes += discarded_bytes;
+        }
+
+        if rotate {
+            self.rotate()?;
+        }
+
+        Ok(recovery)
+    }
+
+    /// Rewrite every segment with the current codec and data key, dropping
+    /// any torn tail. Used offline to retire a data key.
+    pub fn rewrite_segments(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for number in Self::list_segments(&self.data_dir)? {
+            let path = Self::segment_path(&self.data_dir, number);
+            let contents = Self::read_segment(&path, self.keyring.as_deref())?;
+            if number != self.log_number {
+                Self::check_discard(&path, WalRecoveryMode::Strict, contents.file_len - contents.valid_len, contents.valid_len)?;
+            }
+            self.rewrite(&path, &contents.frames)?;
+        }
+        Ok(())
+    }
+
+    fn read_segment(path: &str, keyring: Option<&Keyring>) -> Result<SegmentContents, Box<dyn std::error::Error>> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
         let mut reader = BufReader::new(file);
-        let mut records = Vec::new();
-        
+
+        let mut header = [0u8; WAL_HEADER_SIZE];
+        let version = match reader.read_exact(&mut header) {
+            Ok(()) if header[..4] == WAL_MAGIC => u32::from_be_bytes(header[4..8].try_into().unwrap()),
+            Ok(()) => WAL_VERSION_LEGACY,
+            // Shorter than a header: a torn header write or a legacy log
+            // with a torn first record
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => WAL_VERSION_LEGACY,
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        if version == WAL_VERSION_LEGACY {
+            reader.seek(SeekFrom::Start(0))?;
+            let (frames, valid_len) = Self::read_legacy_frames(&mut reader, file_len)?;
+            return Ok(SegmentContents { frames, valid_len, file_len, version, key_id: PLAINTEXT_KEY_ID });
+        }
+        if !(WAL_VERSION_V2..=WAL_VERSION).contains(&version) {
+            return Err(Box::new(crate::error::BlockDBError::InvalidData(
+                format!("Unsupported WAL format version {} in {}", version, path)
+            )));
+        }
+
+        let mut header_size = WAL_HEADER_SIZE as u64;
+        let mut key_id = PLAINTEXT_KEY_ID;
+        if version >= WAL_VERSION {
+            let mut key_id_buf = [0u8; WAL_KEY_ID_SIZE];
+            if !Self::read_full(&mut reader, &mut key_id_buf)? {
+                // Torn header: no frame can follow, so treat it like a
+                // torn legacy log and let recovery rewrite it
+                return Ok(SegmentContents { frames: Vec::new(), valid_len: 0, file_len, version: WAL_VERSION_LEGACY, key_id });
+            }
+            key_id = u32::from_be_bytes(key_id_buf);
+            header_size += WAL_KEY_ID_SIZE as u64;
+        }
+        encryption::check_key(keyring, key_id, path)?;
+
+        let (frames, valid_len) = Self::read_frames(&mut reader, header_size, file_len, version, key_id, keyring)?;
+        Ok(SegmentContents { frames, valid_len, file_len, version, key_id })
+    }
+
+    fn check_discard(path: &str, mode: WalRecoveryMode, discarded_bytes: u64, valid_len: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if discarded_bytes > 0 && mode == WalRecoveryMode::Strict {
+            return Err(Box::new(crate::error::BlockDBError::Corruption(format!(
+                "Invalid WAL frame in {} at offset {} ({} trailing bytes)",
+                path, valid_len, discarded_bytes
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Read `len | crc | payload` frames, returning the records of each and
+    /// the offset just past the last valid frame. A length running past
+    /// the end of the `file_len` byte segment ends the log.
+    fn read_frames(reader: &mut BufReader<File>, start: u64, file_len: u64, version: u32, key_id: u32, keyring: Option<&Keyring>) -> Result<(Vec<Frame>, u64), Box<dyn std::error::Error>> {
+        let mut frames = Vec::new();
+        let mut valid_len = start;
+
+        loop {
+            let mut frame_header = [0u8; WAL_RECORD_HEADER_SIZE];
+            if !Self::read_full(reader, &mut frame_header)? {
+                break;
+            }
+
+            let record_size = u32::from_be_bytes(frame_header[0..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_be_bytes(frame_header[4..8].try_into().unwrap());
+            // Checked before allocating, as a torn length can be anything
+            if valid_len + (WAL_RECORD_HEADER_SIZE + record_size) as u64 > file_len {
+                break;
+            }
+
+            let mut record_buf = vec![0u8; record_size];
+            if !Self::read_full(reader, &mut record_buf)? || crc32c::crc32c(&record_buf) != checksum {
+                break;
+            }
+
+            let decoded = match version {
+                WAL_VERSION_V2 => bincode::deserialize::<RecordV1>(&record_buf).map(|record| vec![record.into()]),
+                WAL_VERSION_V3 => bincode::deserialize::<Vec<RecordV1>>(&record_buf)
+                    .map(|records| records.into_iter().map(Record::from).collect()),
+                WAL_VERSION_V4 => bincode::deserialize::<Vec<Record>>(&record_buf),
+                WAL_VERSION_V5 => match Compression::decode(&record_buf) {
+                    Ok(payload) => bincode::deserialize::<Vec<Record>>(&payload),
+                    Err(_) => break,
+                },
+                _ => match encryption::unseal(keyring, key_id, record_buf, &valid_len.to_be_bytes())
+                    .and_then(|payload| Compression::decode(&payload))
+                {
+                    Ok(payload) => bincode::deserialize::<Vec<Record>>(&payload),
+                    Err(_) => break,
+                },
+            };
+            match decoded {
+                Ok(frame_records) => frames.push(frame_records),
+                Err(_) => break,
+            }
+            valid_len += (WAL_RECORD_HEADER_SIZE + record_size) as u64;
+        }
+
+        Ok((frames, valid_len))
+    }
+
+    /// Read the unversioned `len | payload` frames written before checksums
+    fn read_legacy_frames(reader: &mut BufReader<File>, file_len: u64) -> Result<(Vec<Frame>, u64), Box<dyn std::error::Error>> {
+        let mut frames = Vec::new();
+        let mut valid_len = 0u64;
+
         loop {
             let mut size_buf = [0u8; 4];
-            match reader.read_exact(&mut size_buf) {
-                Ok(()) => {},
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(Box::new(e)),
+            if !Self::read_full(reader, &mut size_buf)? {
+                break;
             }
-            
+
             let record_size = u32::from_be_bytes(size_buf) as usize;
+            if valid_len + 4 + record_size as u64 > file_len {
+                break;
+            }
             let mut record_buf = vec![0u8; record_size];
-            reader.read_exact(&mut record_buf)?;
-            
-            let record: Record = bincode::deserialize(&record_buf)?;
-            records.push(record);
+            if !Self::read_full(reader, &mut record_buf)? {
+                break;
+            }
+
+            match bincode::deserialize::<RecordV1>(&record_buf) {
+                Ok(record) => frames.push(vec![record.into()]),
+                Err(_) => break,
+            }
+            valid_len += 4 + record_size as u64;
         }
-        
-        Ok(records)
+
+        Ok((frames, valid_len))
     }
 
-    pub fn truncate(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.file.get_mut().set_len(0)?;
-        self.file.get_mut().seek(SeekFrom::Start(0))?;
-        self.offset = 0;
+    /// Fill `buf`, returning false if the file ends first
+    fn read_full(reader: &mut BufReader<File>, buf: &mut [u8]) -> Result<bool, Box<dyn std::error::Error>> {
+        match reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /// Atomically replace a segment with `frames` in the current format
+    fn rewrite(&mut self, path: &str, frames: &[Frame]) -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let mut tmp = WriteAheadLog {
+                data_dir: self.data_dir.clone(),
+                file: BufWriter::new(File::create(&tmp_path)?),
+                path: tmp_path.clone(),
+                log_number: self.log_number,
+                offset: 0,
+                compression: self.compression,
+                keyring: self.keyring.clone(),
+                key_id: self.key_id,
+            };
+            tmp.write_header()?;
+            for frame in frames {
+                tmp.append(frame)?;
+            }
+            tmp.sync()?;
+        }
+
+        fs::rename(&tmp_path, path)?;
+        File::open(&self.data_dir)?.sync_all()?;
+
+        if path == self.path {
+            self.file = BufWriter::new(Self::open_append(&self.path)?);
+            self.offset = self.file.get_ref().metadata()?.len();
+        }
         Ok(())
     }
 
@@ -87,11 +571,14 @@ impl WriteAheadLog {
 
     /// Clear all WAL data and reset to empty state
     pub fn clear(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // Truncate the file to zero length
+        self.remove_segments_before(self.log_number)?;
+
+        // Truncate the active segment to zero length. Anything still
+        // buffered is written out first so it cannot land after the header.
+        self.file.flush()?;
         self.file.get_mut().set_len(0)?;
         self.file.get_mut().seek(SeekFrom::Start(0))?;
-        self.file.flush()?;
-        self.offset = 0;
+        self.write_header()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,45 @@
+/// Puts and deletes applied together by [`crate::storage::BlockDB::write_batch`].
+/// Either every entry is stored or, if any entry is rejected, none is.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    /// `None` deletes the key
+    entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.entries.push((key.to_vec(), Some(value.to_vec())));
+        self
+    }
+
+    /// Write a tombstone for `key`
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.entries.push((key.to_vec(), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Entries in order; deletes have no value
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.entries.iter().map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+
+    pub(crate) fn into_entries(self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.entries
+    }
+}
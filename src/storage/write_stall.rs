@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+/// Whether writes are being slowed down because flushes or compactions
+/// are falling behind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteStallStats {
+    /// New writes are currently being delayed
+    pub stalled: bool,
+    /// Memtables waiting for the background flush
+    pub immutable_memtables: usize,
+    /// SSTables waiting in level 0 for compaction
+    pub level0_files: usize,
+    /// Writes delayed since the database was opened
+    pub delayed_writes: u64,
+    /// Time writers have spent delayed, in milliseconds
+    pub delay_ms: u64,
+}
+
+/// Throttles writers while the immutable memtable queue or level 0 is
+/// over its limit. Each write is delayed rather than held until the
+/// backlog clears, so writes keep flowing at a reduced rate.
+#[derive(Debug)]
+pub struct WriteController {
+    max_immutable_memtables: usize,
+    level0_slowdown_trigger: usize,
+    delay: Duration,
+    delayed_writes: AtomicU64,
+    delay_micros: AtomicU64,
+}
+
+impl WriteController {
+    pub fn new(max_immutable_memtables: usize, level0_slowdown_trigger: usize, delay: Duration) -> Self {
+        WriteController {
+            max_immutable_memtables,
+            level0_slowdown_trigger,
+            delay,
+            delayed_writes: AtomicU64::new(0),
+            delay_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_stalled(&self, immutable_memtables: usize, level0_files: usize) -> bool {
+        immutable_memtables >= self.max_immutable_memtables || level0_files >= self.level0_slowdown_trigger
+    }
+
+    /// Delay the calling writer if the backlog is over a limit
+    pub fn throttle(&self, immutable_memtables: usize, level0_files: usize) {
+        if !self.is_stalled(immutable_memtables, level0_files) || self.delay.is_zero() {
+            return;
+        }
+        std::thread::sleep(self.delay);
+        self.delayed_writes.fetch_add(1, Ordering::Relaxed);
+        self.delay_micros.fetch_add(self.delay.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, immutable_memtables: usize, level0_files: usize) -> WriteStallStats {
+        WriteStallStats {
+            stalled: self.is_stalled(immutable_memtables, level0_files),
+            immutable_memtables,
+            level0_files,
+            delayed_writes: self.delayed_writes.load(Ordering::Relaxed),
+            delay_ms: self.delay_micros.load(Ordering::Relaxed) / 1000,
+        }
+    }
+}
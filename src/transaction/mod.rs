@@ -23,7 +23,7 @@ impl TransactionId {
 pub enum Operation {
     Put { key: Vec<u8>, value: Vec<u8> },
     Get { key: Vec<u8> },
-    Delete { key: Vec<u8> }, // For future use, currently not allowed
+    Delete { key: Vec<u8> }, // Applied as a tombstone
 }
 
 impl Operation {
@@ -460,4 +460,1934 @@ async fn test_error_conditions() {
     db.put(b"post_error_key", b"post_error_value").await.unwrap();
     let result = db.get(b"post_error_key").await.unwrap();
     assert_eq!(result, Some(b"post_error_value".to_vec()));
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_sstables_survive_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        ..Default::default()
+    };
+
+    // Test 1: First session - write, flush to an SSTable, then write more
+    {
+        let db = BlockDBHandle::new(config.clone()).unwrap();
+        for i in 0..50 {
+            let key = format!("flushed_{}", i);
+            db.put(key.as_bytes(), b"on_disk").await.unwrap();
+        }
+        db.force_flush().await.unwrap();
+
+        db.put(b"wal_tail", b"in_memory").await.unwrap();
+    }
+
+    // Flushing must have recorded the SSTable and emptied the WAL before the tail write
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    // Test 2: Second session - both the SSTable and the WAL tail are visible
+    {
+        let db = BlockDBHandle::new(config).unwrap();
+        for i in 0..50 {
+            let key = format!("flushed_{}", i);
+            let result = db.get(key.as_bytes()).await.unwrap();
+            assert_eq!(result, Some(b"on_disk".to_vec()));
+        }
+        let tail = db.get(b"wal_tail").await.unwrap();
+        assert_eq!(tail, Some(b"in_memory".to_vec()));
+
+        // Flushed keys still count as existing
+        assert!(db.put(b"flushed_0", b"overwrite").await.is_err());
+
+        // Sequence numbers continue past the restored counter
+        db.put(b"after_restart", b"value").await.unwrap();
+        let result = db.get(b"after_restart").await.unwrap();
+        assert_eq!(result, Some(b"value".to_vec()));
+    }
+}
+
+#[tokio::test]
+async fn test_background_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        compaction_threshold: 2,
+        ..Default::default()
+    };
+
+    let sstable_files = |dir: &std::path::Path| -> Vec<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".sst"))
+            .collect()
+    };
+
+    {
+        let db = BlockDBHandle::new(config.clone()).unwrap();
+
+        // Test 1: Each flush produces a level-0 SSTable; two of them trigger a merge
+        for batch in 0..4 {
+            for i in 0..25 {
+                let key = format!("batch{}_key{}", batch, i);
+                db.put(key.as_bytes(), format!("value{}", i).as_bytes()).await.unwrap();
+            }
+            db.force_flush().await.unwrap();
+        }
+
+        // Test 2: The worker eventually replaces the inputs with a compacted file
+        let mut compacted = false;
+        for _ in 0..100 {
+            let files = sstable_files(temp_dir.path());
+            if files.iter().any(|name| name.starts_with("compacted_"))
+                && files.iter().filter(|name| name.starts_with("sstable_")).count() < 2 {
+                compacted = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(compacted);
+
+        // Test 3: Reads see every record after the swap
+        for batch in 0..4 {
+            for i in 0..25 {
+                let key = format!("batch{}_key{}", batch, i);
+                let result = db.get(key.as_bytes()).await.unwrap();
+                assert_eq!(result, Some(format!("value{}", i).into_bytes()));
+            }
+        }
+    }
+
+    // Test 4: The compacted layout is what reopens
+    let db = BlockDBHandle::new(config).unwrap();
+    let result = db.get(b"batch0_key0").await.unwrap();
+    assert_eq!(result, Some(b"value0".to_vec()));
+    assert!(db.put(b"batch3_key24", b"duplicate").await.is_err());
+}
+
+#[test]
+fn test_sstable_bloom_filter() {
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::sstable::{SSTable, SSTableOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let records: Vec<Record> = (0..1000)
+        .map(|i| Record {
+            key: format!("present_{:04}", i).into_bytes(),
+            value: b"value".to_vec(),
+            timestamp: 0,
+            sequence_number: i + 1,
+            hash: vec![0u8; 32],
+            kind: RecordKind::Value,
+        })
+        .collect();
+
+    // Test 1: Every written key passes the filter and is readable
+    let path = temp_dir.path().join("filtered.sst").to_string_lossy().to_string();
+    SSTable::write(&path, &records, &SSTableOptions { bloom_bits_per_key: 10, ..Default::default() }).unwrap();
+    let sstable = SSTable::open(&path).unwrap();
+    for record in &records {
+        assert!(sstable.may_contain(&record.key));
+        assert!(sstable.get(&record.key).unwrap().is_some());
+    }
+
+    // Test 2: Absent keys are almost always rejected without an index lookup
+    let false_positives = (0..1000)
+        .filter(|i| sstable.may_contain(format!("absent_{:04}", i).as_bytes()))
+        .count();
+    assert!(false_positives < 50, "false positive rate too high: {}/1000", false_positives);
+    assert!(sstable.get(b"absent_0000").unwrap().is_none());
+
+    // Test 3: Tables written without a filter fall back to the index
+    let path = temp_dir.path().join("unfiltered.sst").to_string_lossy().to_string();
+    SSTable::write(&path, &records, &SSTableOptions { bloom_bits_per_key: 0, ..Default::default() }).unwrap();
+    let sstable = SSTable::open(&path).unwrap();
+    assert!(sstable.may_contain(b"absent_0000"));
+    assert!(sstable.get(b"absent_0000").unwrap().is_none());
+    assert!(sstable.get(b"present_0042").unwrap().is_some());
+}
+
+#[test]
+fn test_sstable_block_format() {
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::sstable::{SSTable, SSTableOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let records: Vec<Record> = (0..500)
+        .map(|i| Record {
+            key: format!("key_{:04}", i).into_bytes(),
+            value: vec![b'v'; 100],
+            timestamp: 0,
+            sequence_number: i + 1,
+            hash: vec![0u8; 32],
+            kind: RecordKind::Value,
+        })
+        .collect();
+
+    // Test 1: Small blocks spread the records over many checksummed blocks
+    let path = temp_dir.path().join("blocks.sst").to_string_lossy().to_string();
+    let options = SSTableOptions { block_size: 512, ..Default::default() };
+    SSTable::write(&path, &records, &options).unwrap();
+
+    let sstable = SSTable::open(&path).unwrap();
+    assert_eq!(sstable.size(), 500);
+    assert_eq!(sstable.first_key(), Some(&b"key_0000".to_vec()));
+    assert_eq!(sstable.last_key(), Some(&b"key_0499".to_vec()));
+    for record in &records {
+        let found = sstable.get(&record.key).unwrap().unwrap();
+        assert_eq!(found.sequence_number, record.sequence_number);
+    }
+    assert!(sstable.get(b"key_0250a").unwrap().is_none());
+    assert!(sstable.get(b"zzz").unwrap().is_none());
+
+    // Test 2: Range scans cross block boundaries
+    let scanned = sstable.scan_range(b"key_0100", b"key_0300").unwrap();
+    assert_eq!(scanned.len(), 200);
+    assert_eq!(scanned[0].key, b"key_0100".to_vec());
+    assert_eq!(scanned[199].key, b"key_0299".to_vec());
+    assert_eq!(sstable.records().unwrap().len(), 500);
+
+    // Test 3: A flipped byte in a data block is reported, not returned
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[10] ^= 0xff;
+    std::fs::write(&path, &bytes).unwrap();
+    let corrupted = SSTable::open(&path).unwrap();
+    let error = corrupted.get(b"key_0000").unwrap_err();
+    assert!(error.to_string().contains("Checksum mismatch"));
+}
+
+#[test]
+fn test_sstable_reads_v1_layout() {
+    use blockdb::storage::RecordV1;
+    use blockdb::storage::sstable::{IndexEntry, SSTable};
+    use std::collections::BTreeMap;
+    use std::io::Write;
+
+    // Hand-write the original layout: `size | record` entries, a bincode
+    // index and a bare 16-byte footer
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("legacy.sst").to_string_lossy().to_string();
+    let mut file = std::fs::File::create(&path).unwrap();
+    let mut index = BTreeMap::new();
+    let mut offset = 0u64;
+    for i in 0..10u64 {
+        let record = RecordV1 {
+            key: format!("legacy_{}", i).into_bytes(),
+            value: format!("value_{}", i).into_bytes(),
+            timestamp: 0,
+            sequence_number: i + 1,
+            hash: vec![0u8; 32],
+        };
+        let serialized = bincode::serialize(&record).unwrap();
+        file.write_all(&(serialized.len() as u32).to_be_bytes()).unwrap();
+        file.write_all(&serialized).unwrap();
+        index.insert(record.key.clone(), IndexEntry { key: record.key.clone(), offset, size: serialized.len() as u32 });
+        offset += 4 + serialized.len() as u64;
+    }
+    let index_data = bincode::serialize(&index).unwrap();
+    file.write_all(&index_data).unwrap();
+    file.write_all(&offset.to_be_bytes()).unwrap();
+    file.write_all(&(index_data.len() as u64).to_be_bytes()).unwrap();
+    drop(file);
+
+    let sstable = SSTable::open(&path).unwrap();
+    assert_eq!(sstable.size(), 10);
+    let record = sstable.get(b"legacy_7").unwrap().unwrap();
+    assert_eq!(record.value, b"value_7".to_vec());
+    assert!(sstable.get(b"legacy_70").unwrap().is_none());
+    assert_eq!(sstable.records().unwrap().len(), 10);
+}
+
+#[test]
+fn test_wal_torn_tail_recovery() {
+    use blockdb::BlockDB;
+    use blockdb::storage::wal::WalRecoveryMode;
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let wal_path = temp_dir.path().join("wal_000001.log");
+
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        db.put(b"intact_1", b"value_1").unwrap();
+        db.put(b"intact_2", b"value_2").unwrap();
+    }
+    let intact_len = std::fs::metadata(&wal_path).unwrap().len();
+
+    // Simulate a crash halfway through appending a third record
+    {
+        let mut wal = std::fs::OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(&200u32.to_be_bytes()).unwrap();
+        wal.write_all(&[0xab; 37]).unwrap();
+    }
+
+    // Test 1: Strict mode refuses to open
+    let strict_config = BlockDBConfig {
+        wal_recovery_mode: WalRecoveryMode::Strict,
+        ..config.clone()
+    };
+    assert!(BlockDB::new(strict_config).is_err());
+
+    // Test 2: The default mode keeps the intact records and truncates the tail
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        assert_eq!(db.wal_discarded_bytes(), 41);
+        assert_eq!(db.get(b"intact_1").unwrap(), Some(b"value_1".to_vec()));
+        assert_eq!(db.get(b"intact_2").unwrap(), Some(b"value_2".to_vec()));
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), intact_len);
+
+        db.put(b"after_crash", b"value_3").unwrap();
+    }
+
+    // Test 3: Appends after the truncation replay cleanly
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        assert_eq!(db.wal_discarded_bytes(), 0);
+        assert_eq!(db.get(b"after_crash").unwrap(), Some(b"value_3".to_vec()));
+    }
+
+    // Test 4: A corrupt length far past the end of the segment is treated
+    // as a torn tail instead of being allocated
+    {
+        let mut wal = std::fs::OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(&0xffff_fff0u32.to_be_bytes()).unwrap();
+        wal.write_all(&[0xab; 12]).unwrap();
+    }
+    let db = BlockDB::new(config).unwrap();
+    assert_eq!(db.wal_discarded_bytes(), 16);
+    assert_eq!(db.get(b"after_crash").unwrap(), Some(b"value_3".to_vec()));
+}
+
+#[test]
+fn test_wal_reads_legacy_frames() {
+    use blockdb::BlockDB;
+    use blockdb::storage::RecordV1;
+    use std::io::Write;
+
+    // Logs written before checksums were plain `len | record` frames
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut wal = std::fs::File::create(temp_dir.path().join("wal.log")).unwrap();
+        for i in 1..=3u64 {
+            let record = RecordV1 {
+                key: format!("legacy_{}", i).into_bytes(),
+                value: b"value".to_vec(),
+                timestamp: 0,
+                sequence_number: i,
+                hash: vec![0u8; 32],
+            };
+            let serialized = bincode::serialize(&record).unwrap();
+            wal.write_all(&(serialized.len() as u32).to_be_bytes()).unwrap();
+            wal.write_all(&serialized).unwrap();
+        }
+    }
+
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        ..Default::default()
+    };
+
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        assert_eq!(db.get(b"legacy_2").unwrap(), Some(b"value".to_vec()));
+        db.put(b"framed", b"value").unwrap();
+    }
+
+    // The converted log holds both the legacy and the new records
+    let db = BlockDB::new(config).unwrap();
+    assert_eq!(db.get(b"legacy_3").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.get(b"framed").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_wal_group_commit() {
+    use blockdb::BlockDB;
+    use blockdb::storage::wal::WalDurability;
+
+    for durability in [WalDurability::Fsync, WalDurability::Flush, WalDurability::None] {
+        let temp_dir = TempDir::new().unwrap();
+        let config = BlockDBConfig {
+            data_dir: temp_dir.path().to_string_lossy().to_string(),
+            wal_durability: durability,
+            wal_sync_interval_ms: 10,
+            ..Default::default()
+        };
+
+        {
+            let db = Arc::new(BlockDB::new(config.clone()).unwrap());
+
+            // Test 1: Concurrent writers all commit
+            let handles: Vec<_> = (0..8)
+                .map(|writer| {
+                    let db = db.clone();
+                    std::thread::spawn(move || {
+                        for i in 0..50 {
+                            let key = format!("writer_{}_key_{}", writer, i);
+                            db.put(key.as_bytes(), b"value").unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+
+            // Test 2: A key queued in the same batch is still a duplicate
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let db = db.clone();
+                    std::thread::spawn(move || db.put(b"contended", b"value").is_ok())
+                })
+                .collect();
+            let successes = handles.into_iter().map(|handle| handle.join().unwrap()).filter(|&ok| ok).count();
+            assert_eq!(successes, 1);
+            assert!(db.verify_integrity().unwrap());
+        }
+
+        // Test 3: Every acknowledged write is replayed after reopening
+        let db = BlockDB::new(config).unwrap();
+        for writer in 0..8 {
+            for i in 0..50 {
+                let key = format!("writer_{}_key_{}", writer, i);
+                assert_eq!(db.get(key.as_bytes()).unwrap(), Some(b"value".to_vec()), "{:?}", durability);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_wal_segment_rotation() {
+    use blockdb::BlockDB;
+    use blockdb::storage::wal::WriteAheadLog;
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_string_lossy().to_string();
+    let config = BlockDBConfig {
+        data_dir: data_dir.clone(),
+        memtable_size_limit: 1024,
+        compaction_threshold: 100,
+        ..Default::default()
+    };
+
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for i in 0..200 {
+            let key = format!("segment_key_{:03}", i);
+            db.put(key.as_bytes(), &[b'v'; 64]).unwrap();
+        }
+
+        // Test 1: Each flush rotated the log and dropped the flushed segment
+        let segments = WriteAheadLog::list_segments(&data_dir).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0] > 1);
+
+        db.put(b"unflushed", b"value").unwrap();
+    }
+
+    // Test 2: Flushed records come back from SSTables, the rest from the log
+    let db = BlockDB::new(config).unwrap();
+    for i in 0..200 {
+        let key = format!("segment_key_{:03}", i);
+        assert_eq!(db.get(key.as_bytes()).unwrap(), Some(vec![b'v'; 64]));
+    }
+    assert_eq!(db.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+    assert!(!temp_dir.path().join("wal.log").exists());
+}
+
+#[test]
+fn test_range_and_prefix_scans() {
+    use blockdb::BlockDB;
+    use blockdb::storage::{DBIterator, KeyRange, ScanOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        memtable_size_limit: 2048, // Spread keys over several SSTables
+        sstable_block_size: 256,
+        compaction_threshold: 100,
+        ..Default::default()
+    };
+    let db = BlockDB::new(config).unwrap();
+
+    // Interleave two prefixes so every SSTable holds some of each
+    for i in 0..100 {
+        db.put(format!("apple_{:03}", i).as_bytes(), format!("a{}", i).as_bytes()).unwrap();
+        db.put(format!("berry_{:03}", i).as_bytes(), format!("b{}", i).as_bytes()).unwrap();
+    }
+    db.put(b"cherry", b"c").unwrap();
+    assert!(std::fs::read_dir(temp_dir.path()).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".sst"))
+        .count() > 1);
+
+    let keys = |iter: DBIterator| -> Vec<String> {
+        iter.map(|record| String::from_utf8(record.unwrap().key).unwrap()).collect()
+    };
+
+    // Test 1: Full scan is ordered and complete across memtable and SSTables
+    let all = keys(db.scan_with(KeyRange::all(), &ScanOptions::default()).unwrap());
+    assert_eq!(all.len(), 201);
+    assert!(all.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // Test 2: Half-open range
+    let range = keys(db.scan(b"apple_010", b"apple_013").unwrap());
+    assert_eq!(range, vec!["apple_010", "apple_011", "apple_012"]);
+
+    // Test 3: Prefix scan
+    let berries = keys(db.scan_prefix(b"berry_").unwrap());
+    assert_eq!(berries.len(), 100);
+    assert_eq!(berries[0], "berry_000");
+    assert_eq!(berries[99], "berry_099");
+
+    // Test 4: Reverse with limit
+    let options = ScanOptions { reverse: true, limit: Some(3), ..Default::default() };
+    let last = keys(db.scan_with(KeyRange::prefix(b"apple_"), &options).unwrap());
+    assert_eq!(last, vec!["apple_099", "apple_098", "apple_097"]);
+
+    // Test 5: Values come through, and empty ranges yield nothing
+    let record = db.scan(b"cherry", b"cherz").unwrap().next().unwrap().unwrap();
+    assert_eq!(record.value, b"c".to_vec());
+    assert_eq!(db.scan(b"zzz", b"aaa").unwrap().count(), 0);
+}
+
+#[test]
+fn test_snapshot_reads() {
+    use blockdb::BlockDB;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        memtable_size_limit: 1024,
+        compaction_threshold: 2,
+        ..Default::default()
+    };
+    let db = BlockDB::new(config).unwrap();
+
+    for i in 0..20 {
+        db.put(format!("report_{:02}", i).as_bytes(), b"before").unwrap();
+    }
+    let snapshot = db.snapshot();
+
+    // Keep writing through flushes and compactions
+    for i in 20..80 {
+        db.put(format!("report_{:02}", i).as_bytes(), &[b'x'; 64]).unwrap();
+    }
+    db.force_flush_memtable().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    // Test 1: Point reads ignore newer records
+    assert_eq!(snapshot.get(b"report_05").unwrap(), Some(b"before".to_vec()));
+    assert_eq!(snapshot.get(b"report_50").unwrap(), None);
+    assert_eq!(db.get(b"report_50").unwrap(), Some(vec![b'x'; 64]));
+
+    // Test 2: Scans see exactly the records committed before the snapshot
+    let keys: Vec<Vec<u8>> = snapshot.scan_prefix(b"report_").unwrap().map(|record| record.unwrap().key).collect();
+    assert_eq!(keys.len(), 20);
+    assert_eq!(keys.last().unwrap(), &b"report_19".to_vec());
+    assert_eq!(db.scan_prefix(b"report_").unwrap().count(), 80);
+
+    // Test 3: A new snapshot sees everything
+    drop(snapshot);
+    let snapshot = db.snapshot();
+    assert_eq!(snapshot.scan(b"report_", b"report_99").unwrap().count(), 80);
+    drop(snapshot);
+
+    // Test 4: Snapshots taken while writes, flushes and compactions run
+    // keep reading the same values
+    let db = Arc::new(db);
+    let writer = {
+        let db = db.clone();
+        std::thread::spawn(move || {
+            for i in 0..200 {
+                let key = format!("churn_{:03}", i);
+                db.put(key.as_bytes(), &[b'y'; 64]).unwrap();
+                db.delete(key.as_bytes()).unwrap();
+                if i % 10 == 9 {
+                    db.force_flush_memtable().unwrap();
+                }
+            }
+        })
+    };
+    let read_all = |snapshot: &blockdb::storage::Snapshot| -> Vec<Option<Vec<u8>>> {
+        (0..200).map(|i| snapshot.get(format!("churn_{:03}", i).as_bytes()).unwrap()).collect()
+    };
+    while !writer.is_finished() {
+        let snapshot = db.snapshot();
+        let before = read_all(&snapshot);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(read_all(&snapshot), before);
+    }
+    writer.join().unwrap();
+}
+
+#[test]
+fn test_memtable_latest_by_prefix() {
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::memtable::MemTable;
+
+    let record = |key: &str, sequence_number: u64| Record {
+        key: key.as_bytes().to_vec(),
+        value: format!("v{}", sequence_number).into_bytes(),
+        timestamp: 0,
+        sequence_number,
+        hash: vec![0u8; 32],
+        kind: RecordKind::Value,
+    };
+    let mut memtable = MemTable::new();
+    for record in [record("log_1", 1), record("log_2", 2), record("log_2", 5), record("log_2", 3), record("other", 4)] {
+        memtable.insert(record);
+    }
+
+    // Test 1: The greatest matching key is found at its newest version
+    let latest = memtable.get_latest_by_prefix(b"log_").unwrap();
+    assert_eq!((latest.key.as_slice(), latest.sequence_number), (b"log_2".as_slice(), 5));
+
+    // Test 2: No matching key finds nothing
+    assert!(memtable.get_latest_by_prefix(b"missing").is_none());
+}
+
+#[test]
+fn test_compaction_keeps_versions_for_snapshots() {
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::compaction::{CompactionJob, Compactor, Retention};
+    use blockdb::storage::sstable::{SSTable, SSTableOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_string_lossy().to_string();
+    let record = |sequence_number: u64| Record {
+        key: b"shared".to_vec(),
+        value: format!("v{}", sequence_number).into_bytes(),
+        timestamp: 0,
+        sequence_number,
+        hash: vec![0u8; 32],
+        kind: RecordKind::Value,
+    };
+
+    SSTable::write(&format!("{}/old.sst", data_dir), &[record(1)], &SSTableOptions::default()).unwrap();
+    SSTable::write(&format!("{}/mid.sst", data_dir), &[record(5)], &SSTableOptions::default()).unwrap();
+    SSTable::write(&format!("{}/new.sst", data_dir), &[record(9)], &SSTableOptions::default()).unwrap();
+    let job = CompactionJob {
+        level: 0,
+        inputs: vec!["old.sst".to_string(), "mid.sst".to_string(), "new.sst".to_string()],
+        max_output_size: u64::MAX,
+    };
+    let versions = |snapshots: &[u64]| -> Vec<u64> {
+        let retention = Retention { snapshots: snapshots.to_vec(), ..Default::default() };
+        let output = Compactor::merge(&data_dir, &job, &SSTableOptions::default(), &retention, None).unwrap().files.remove(0);
+        let sstable = SSTable::open(&format!("{}/{}", data_dir, output.file_name)).unwrap();
+        sstable.records().unwrap().iter().map(|record| record.sequence_number).collect()
+    };
+
+    // Test 1: Without snapshots only the newest version survives
+    assert_eq!(versions(&[]), vec![9]);
+
+    // Test 2: Each snapshot keeps the version it reads, newest first
+    assert_eq!(versions(&[3]), vec![9, 1]);
+    assert_eq!(versions(&[1, 6]), vec![9, 5, 1]);
+    assert_eq!(versions(&[9, 12]), vec![9]);
+}
+
+#[test]
+fn test_compaction_merges_overlapping_next_level() {
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::compaction::{Compactor, KeySpan, Retention};
+    use blockdb::storage::sstable::{SSTable, SSTableOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_string_lossy().to_string();
+    // Small blocks so every input streams over many of them
+    let options = SSTableOptions { block_size: 256, ..Default::default() };
+    let record = |i: usize, sequence_number: u64| Record {
+        key: format!("key_{:04}", i).into_bytes(),
+        value: format!("v{}", sequence_number).into_bytes(),
+        timestamp: 0,
+        sequence_number,
+        hash: vec![0u8; 32],
+        kind: RecordKind::Value,
+    };
+    let write = |file_name: &str, records: Vec<Record>| {
+        let path = format!("{}/{}", data_dir, file_name);
+        SSTable::write(&path, &records, &options).unwrap();
+        KeySpan::of(&SSTable::open(&path).unwrap())
+    };
+
+    let mut compactor = Compactor::new(&data_dir, 2, 64 * 1024);
+    let span = write("low.sst", (0..100).map(|i| record(i, 1)).collect());
+    compactor.add_sstable("low.sst".to_string(), 1, span);
+    let span = write("high.sst", (500..600).map(|i| record(i, 2)).collect());
+    compactor.add_sstable("high.sst".to_string(), 1, span);
+    let span = write("l0_a.sst", (50..150).map(|i| record(i, 3)).collect());
+    compactor.add_sstable("l0_a.sst".to_string(), 0, span);
+    let span = write("l0_b.sst", (0..150).step_by(2).map(|i| record(i, 4)).collect());
+    compactor.add_sstable("l0_b.sst".to_string(), 0, span);
+
+    // Test 1: The job takes the overlapping level 1 table, oldest first
+    let job = compactor.pick_compaction().unwrap();
+    assert_eq!(job.level, 0);
+    assert_eq!(job.inputs, vec!["low.sst", "l0_a.sst", "l0_b.sst"]);
+
+    // Test 2: The output holds the newest version of every key, in order
+    let output = Compactor::merge(&data_dir, &job, &options, &Retention::default(), None).unwrap();
+    assert_eq!(output.files.len(), 1);
+    let records = SSTable::open(&format!("{}/{}", data_dir, output.files[0].file_name)).unwrap().records().unwrap();
+    assert_eq!(records.len(), 150);
+    for (i, record) in records.iter().enumerate() {
+        assert_eq!(record.key, format!("key_{:04}", i).into_bytes());
+        let newest = if i % 2 == 0 { 4 } else if i >= 50 { 3 } else { 1 };
+        assert_eq!(record.sequence_number, newest);
+    }
+    assert_eq!(output.files[0].span.first, b"key_0000".to_vec());
+    assert_eq!(output.files[0].span.last, b"key_0149".to_vec());
+
+    // Test 3: The overlapping table leaves level 1 with the level 0 inputs
+    compactor.complete(&job, &output.files);
+    assert_eq!(compactor.get_level_info()[..2], [0, 2]);
+    assert!(compactor.pick_compaction().is_none());
+}
+
+#[test]
+fn test_leveled_compaction_splits_outputs() {
+    use std::collections::BTreeMap;
+    use blockdb::BlockDB;
+    use blockdb::storage::compaction::KeySpan;
+    use blockdb::storage::manifest::Manifest;
+    use blockdb::storage::sstable::SSTable;
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_string_lossy().to_string();
+    let db = BlockDB::new(BlockDBConfig {
+        data_dir: data_dir.clone(),
+        compaction_threshold: 2,
+        sstable_target_size: 8 * 1024,
+        ..Default::default()
+    }).unwrap();
+
+    // Live files by level, once level 0 has been merged down
+    let levels = || -> BTreeMap<usize, Vec<String>> {
+        for _ in 0..200 {
+            let manifest = Manifest::load(&data_dir).unwrap().unwrap_or_default();
+            if manifest.sstables.iter().filter(|entry| entry.level == 0).count() < 2 {
+                let mut levels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+                for entry in manifest.sstables {
+                    levels.entry(entry.level).or_default().push(entry.file_name);
+                }
+                return levels;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("level 0 was never compacted");
+    };
+
+    // Test 1: Overlapping flushes are merged into several level 1 tables.
+    // The keys fit within level 1's size limit, so none move on to level 2.
+    for flush in 0..6 {
+        for i in (flush..300).step_by(6) {
+            db.put(format!("key_{:05}", i).as_bytes(), &[b'v'; 64]).unwrap();
+        }
+        db.force_flush_memtable().unwrap();
+        // Each pair of flushes is merged down before the next starts
+        if flush % 2 == 1 {
+            levels();
+        }
+    }
+    let level1 = levels().remove(&1).unwrap();
+    assert!(level1.len() > 1);
+
+    // Test 2: Their key ranges are disjoint
+    let mut spans: Vec<KeySpan> = level1
+        .iter()
+        .map(|file_name| KeySpan::of(&SSTable::open(&format!("{}/{}", data_dir, file_name)).unwrap()).unwrap())
+        .collect();
+    spans.sort_by(|a, b| a.first.cmp(&b.first));
+    for pair in spans.windows(2) {
+        assert!(pair[0].last < pair[1].first);
+    }
+
+    // Test 3: Flushes touching a narrow range leave the rest of level 1 alone
+    for flush in 0..2 {
+        db.put(format!("key_00150_{}", flush).as_bytes(), b"late").unwrap();
+        db.force_flush_memtable().unwrap();
+    }
+    let after = levels();
+    assert!(level1.iter().filter(|file_name| after[&1].contains(file_name)).count() >= level1.len() - 2);
+    assert_eq!(db.get(b"key_00150_1").unwrap(), Some(b"late".to_vec()));
+    assert_eq!(db.get(b"key_00299").unwrap(), Some(vec![b'v'; 64]));
+}
+
+#[test]
+fn test_write_batch() {
+    use blockdb::{BlockDB, WriteBatch};
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        ..Default::default()
+    };
+
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        let receipt = db.put(b"batch_existing", b"value").unwrap();
+        assert_eq!(receipt.hash.len(), 32);
+
+        // Test 1: A batch touching an existing key stores nothing
+        let mut batch = WriteBatch::new();
+        batch.put(b"batch_a", b"1").put(b"batch_existing", b"2");
+        assert!(db.write_batch(batch).is_err());
+        assert_eq!(db.get(b"batch_a").unwrap(), None);
+
+        // Test 2: So does a batch that repeats a key
+        let mut batch = WriteBatch::new();
+        batch.put(b"batch_a", b"1").put(b"batch_a", b"2");
+        assert!(db.write_batch(batch).is_err());
+        assert_eq!(db.get(b"batch_a").unwrap(), None);
+
+        // Test 3: A valid batch gets one contiguous run of sequence numbers
+        let mut batch = WriteBatch::new();
+        batch.put(b"batch_a", b"1").put(b"batch_b", b"2").put(b"batch_c", b"3");
+        assert_eq!(batch.len(), 3);
+        let receipts = db.write_batch(batch).unwrap();
+        assert_eq!(receipts.len(), 3);
+        assert_eq!(receipts[0].sequence_number, receipt.sequence_number + 1);
+
+        // Test 4: Receipts match the stored records and share one block
+        let records: Vec<_> = db.scan_prefix(b"batch_").unwrap()
+            .map(|record| record.unwrap())
+            .filter(|record| record.key != b"batch_existing")
+            .collect();
+        assert_eq!(records.len(), 3);
+        for (record, receipt) in records.iter().zip(&receipts) {
+            assert_eq!(record.sequence_number, receipt.sequence_number);
+            assert_eq!(record.timestamp, receipt.timestamp);
+            assert_eq!(record.hash, receipt.hash);
+            assert_eq!(receipt.block_index, receipts[0].block_index);
+        }
+        assert_eq!(receipts[2].sequence_number, receipts[0].sequence_number + 2);
+    }
+
+    // Test 5: The batch is replayed from the log after a restart
+    let db = BlockDB::new(config).unwrap();
+    assert_eq!(db.get(b"batch_a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(db.get(b"batch_b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(db.get(b"batch_c").unwrap(), Some(b"3".to_vec()));
+    assert!(db.verify_integrity().unwrap());
+
+    // Test 6: Of concurrent puts racing on one key, exactly one is stored
+    let db = Arc::new(db);
+    for round in 0..20 {
+        let key = format!("race_{}", round);
+        let handles: Vec<_> = (0..8)
+            .map(|writer| {
+                let db = db.clone();
+                let key = key.clone();
+                std::thread::spawn(move || db.put(key.as_bytes(), format!("{}", writer).as_bytes()).is_ok())
+            })
+            .collect();
+        let stored = handles.into_iter().map(|handle| handle.join().unwrap()).filter(|&ok| ok).count();
+        assert_eq!(stored, 1);
+        assert_eq!(db.history(key.as_bytes()).unwrap().len(), 1);
+    }
+}
+
+#[test]
+fn test_versioned_keys() {
+    use blockdb::BlockDB;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        memtable_size_limit: 1024,
+        compaction_threshold: 2,
+        versioned: true,
+        ..Default::default()
+    };
+
+    let mut receipts = Vec::new();
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+
+        // Test 1: Each put appends a version; get returns the latest
+        for version in 0..5 {
+            receipts.push(db.put(b"entity", format!("state_{}", version).as_bytes()).unwrap());
+            // Filler pushes versions through flushes and compactions
+            for i in 0..10 {
+                db.put(format!("filler_{}_{}", version, i).as_bytes(), &[b'f'; 64]).unwrap();
+            }
+        }
+        assert_eq!(db.get(b"entity").unwrap(), Some(b"state_4".to_vec()));
+
+        // Test 2: Older versions stay readable by sequence number
+        let version = db.get_version(b"entity", receipts[1].sequence_number).unwrap().unwrap();
+        assert_eq!(version.value, b"state_1".to_vec());
+        assert_eq!(version.hash, receipts[1].hash);
+        assert!(db.get_version(b"entity", receipts[1].sequence_number + 1).unwrap().is_none());
+
+        let snapshot = db.snapshot();
+        db.put(b"entity", b"state_5").unwrap();
+        assert_eq!(snapshot.get(b"entity").unwrap(), Some(b"state_4".to_vec()));
+        drop(snapshot);
+
+        // Test 3: Scans see only the latest version of each key
+        let records: Vec<_> = db.scan_prefix(b"entity").unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].value, b"state_5".to_vec());
+    }
+
+    // Test 4: History survives flushes, compaction and a restart
+    let db = BlockDB::new(config.clone()).unwrap();
+    let history = db.history(b"entity").unwrap();
+    let values: Vec<_> = history.iter().map(|record| String::from_utf8(record.value.clone()).unwrap()).collect();
+    assert_eq!(values, (0..6).map(|version| format!("state_{}", version)).collect::<Vec<_>>());
+    assert!(history.windows(2).all(|pair| pair[0].sequence_number < pair[1].sequence_number));
+    assert!(db.verify_integrity().unwrap());
+    drop(db);
+
+    // Test 5: Without versioning a second put is still refused
+    let config = BlockDBConfig { versioned: false, ..config };
+    let db = BlockDB::new(config).unwrap();
+    assert!(db.put(b"entity", b"state_6").is_err());
+    assert_eq!(db.get(b"entity").unwrap(), Some(b"state_5".to_vec()));
+}
+
+#[test]
+fn test_time_travel_reads() {
+    use blockdb::BlockDB;
+    use blockdb::storage::KeyRange;
+    use blockdb::storage::manifest::Manifest;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        versioned: true,
+        compaction_threshold: 2,
+        ..Default::default()
+    };
+    let db = BlockDB::new(config.clone()).unwrap();
+
+    let mut receipts = Vec::new();
+    for version in 0..3 {
+        db.put(format!("trade_{}", version).as_bytes(), b"filled").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        receipts.push(db.put(b"price", format!("{}", 100 + version).as_bytes()).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    db.force_flush_memtable().unwrap();
+    db.put(b"price", b"200").unwrap();
+
+    // Test 1: Point reads resolve to the newest record at or before the time
+    assert!(db.get_as_of(b"price", receipts[0].timestamp - 1).unwrap().is_none());
+    for (version, receipt) in receipts.iter().enumerate() {
+        let record = db.get_as_of(b"price", receipt.timestamp).unwrap().unwrap();
+        assert_eq!(record.value, format!("{}", 100 + version).into_bytes());
+        assert_eq!(record.sequence_number, receipt.sequence_number);
+    }
+    let record = db.get_as_of(b"price", receipts[1].timestamp + 2).unwrap().unwrap();
+    assert_eq!(record.value, b"101".to_vec());
+
+    // Test 2: Scans show each key as it stood at that time
+    let records: Vec<_> = db.scan_as_of(KeyRange::all(), receipts[1].timestamp).unwrap()
+        .map(|record| record.unwrap())
+        .collect();
+    let keys: Vec<_> = records.iter().map(|record| record.key.clone()).collect();
+    assert_eq!(keys, vec![b"price".to_vec(), b"trade_0".to_vec(), b"trade_1".to_vec()]);
+    assert_eq!(records[0].value, b"101".to_vec());
+
+    // Test 3: Versions replaced by a delete and a new put survive compaction
+    let listed = db.put(b"listing", b"open").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let delisted = db.delete(b"listing").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let relisted = db.put(b"listing", b"reopened").unwrap();
+    // The second level 0 table triggers a merge of both
+    db.force_flush_memtable().unwrap();
+    let mut compacted = false;
+    for _ in 0..200 {
+        let manifest = Manifest::load(&config.data_dir).unwrap().unwrap();
+        if manifest.sstables.iter().all(|entry| entry.level > 0) {
+            compacted = true;
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert!(compacted);
+    assert_eq!(db.get_as_of(b"listing", listed.timestamp).unwrap().unwrap().value, b"open".to_vec());
+    assert!(db.get_as_of(b"listing", delisted.timestamp).unwrap().is_none());
+    assert_eq!(db.get_as_of(b"listing", relisted.timestamp).unwrap().unwrap().value, b"reopened".to_vec());
+    drop(db);
+
+    // Test 4: Without versioning, which compaction relies on, reads as of a
+    // time are refused rather than answered from whatever is left
+    let db = BlockDB::new(BlockDBConfig { versioned: false, ..config }).unwrap();
+    assert!(db.get_as_of(b"price", receipts[0].timestamp).is_err());
+    assert!(db.scan_as_of(KeyRange::all(), receipts[0].timestamp).is_err());
+}
+
+#[test]
+fn test_tombstones_and_crypto_shredding() {
+    use blockdb::BlockDB;
+    use blockdb::storage::RecordKind;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        crypto_shredding: true,
+        ..Default::default()
+    };
+
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        db.put(b"user_1", b"alice@example.com").unwrap();
+        db.put(b"user_2", b"bob@example.com").unwrap();
+        db.put(b"user_3", b"carol@example.com").unwrap();
+
+        // Test 1: Values are stored encrypted but read back as written
+        let history = db.history(b"user_1").unwrap();
+        assert_eq!(history[0].kind, RecordKind::Encrypted);
+        assert_eq!(history[0].value, b"alice@example.com".to_vec());
+
+        // Test 2: A tombstone hides the key from reads and scans
+        db.delete(b"user_1").unwrap();
+        assert_eq!(db.get(b"user_1").unwrap(), None);
+        let keys: Vec<_> = db.scan_prefix(b"user_").unwrap()
+            .map(|record| record.unwrap().key)
+            .collect();
+        assert_eq!(keys, vec![b"user_2".to_vec(), b"user_3".to_vec()]);
+        assert!(db.delete(b"user_1").is_err());
+        assert!(db.delete(b"missing").is_err());
+
+        // Test 3: A deleted key can be written again
+        db.put(b"user_1", b"alice@example.org").unwrap();
+        assert_eq!(db.get(b"user_1").unwrap(), Some(b"alice@example.org".to_vec()));
+        assert!(db.put(b"user_1", b"again").is_err());
+
+        // Test 4: Erasure makes the value unrecoverable but keeps the chain valid
+        db.erase(b"user_2").unwrap();
+        assert_eq!(db.get(b"user_2").unwrap(), None);
+        assert!(db.verify_integrity().unwrap());
+    }
+
+    // Test 5: Tombstones and destroyed keys survive a restart and a flush
+    let db = BlockDB::new(config).unwrap();
+    db.force_flush_memtable().unwrap();
+    assert_eq!(db.get(b"user_2").unwrap(), None);
+    assert_eq!(db.get(b"user_3").unwrap(), Some(b"carol@example.com".to_vec()));
+    let history = db.history(b"user_2").unwrap();
+    assert!(history.last().unwrap().is_tombstone());
+    assert!(history.iter().all(|record| !record.value.windows(3).any(|window| window == b"bob")));
+    assert!(db.verify_integrity().unwrap());
+
+    // Test 6: Erasure needs crypto-shredding enabled
+    let plain_dir = TempDir::new().unwrap();
+    let plain = BlockDB::new(BlockDBConfig {
+        data_dir: plain_dir.path().to_string_lossy().to_string(),
+        ..Default::default()
+    }).unwrap();
+    plain.put(b"key", b"value").unwrap();
+    assert!(plain.erase(b"key").is_err());
+}
+
+#[test]
+fn test_ttl_expiry() {
+    use blockdb::BlockDB;
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::compaction::{CompactionJob, Compactor, Retention};
+    use blockdb::storage::sstable::{SSTable, SSTableOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        ttl_seconds: Some(1),
+        ..Default::default()
+    };
+    let db = BlockDB::new(config).unwrap();
+
+    db.put(b"session_1", b"token").unwrap();
+    db.force_flush_memtable().unwrap();
+    db.put(b"session_2", b"token").unwrap();
+    assert_eq!(db.get(b"session_1").unwrap(), Some(b"token".to_vec()));
+
+    // Test 1: Expired records disappear from reads and scans
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    db.put(b"session_3", b"token").unwrap();
+    assert_eq!(db.get(b"session_1").unwrap(), None);
+    assert_eq!(db.get(b"session_2").unwrap(), None);
+    let keys: Vec<_> = db.scan_prefix(b"session_").unwrap()
+        .map(|record| record.unwrap().key)
+        .collect();
+    assert_eq!(keys, vec![b"session_3".to_vec()]);
+    assert!(db.history(b"session_1").unwrap().is_empty());
+
+    // Test 2: An expired key can be written again
+    db.put(b"session_1", b"renewed").unwrap();
+    assert_eq!(db.get(b"session_1").unwrap(), Some(b"renewed".to_vec()));
+    assert!(db.verify_integrity().unwrap());
+
+    // Test 3: Compaction drops expired records and reports them
+    let data_dir = TempDir::new().unwrap();
+    let data_dir = data_dir.path().to_string_lossy().to_string();
+    let record = |key: &str, timestamp: u64, sequence_number: u64| Record {
+        key: key.as_bytes().to_vec(),
+        value: b"value".to_vec(),
+        timestamp,
+        sequence_number,
+        hash: vec![sequence_number as u8; 32],
+        kind: RecordKind::Value,
+    };
+    SSTable::write(&format!("{}/a.sst", data_dir), &[record("old", 1_000, 1), record("young", 5_000, 2)], &SSTableOptions::default()).unwrap();
+    let job = CompactionJob { level: 0, inputs: vec!["a.sst".to_string()], max_output_size: u64::MAX };
+
+    let retention = Retention { expire_before: Some(2_000), ..Default::default() };
+    let output = Compactor::merge(&data_dir, &job, &SSTableOptions::default(), &retention, None).unwrap();
+    let sstable = SSTable::open(&format!("{}/{}", data_dir, output.files[0].file_name)).unwrap();
+    let kept: Vec<_> = sstable.records().unwrap().into_iter().map(|record| record.key).collect();
+    assert_eq!(kept, vec![b"young".to_vec()]);
+    assert_eq!(output.expired.len(), 1);
+    assert_eq!(output.expired[0].hash, vec![1u8; 32]);
+
+    let retention = Retention { expire_before: Some(10_000), ..Default::default() };
+    let output = Compactor::merge(&data_dir, &job, &SSTableOptions::default(), &retention, None).unwrap();
+    assert!(output.files.is_empty());
+    assert_eq!(output.expired.len(), 2);
+}
+
+#[test]
+fn test_compression() {
+    use blockdb::BlockDB;
+    use blockdb::storage::{Compression, Record, RecordKind};
+    use blockdb::storage::sstable::{SSTable, SSTableOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let records: Vec<Record> = (0..500)
+        .map(|i| Record {
+            key: format!("order_{:04}", i).into_bytes(),
+            value: format!(r#"{{"customer":"customer_{}","status":"shipped","items":[{{"sku":"A-100","qty":1}}]}}"#, i % 7).into_bytes(),
+            timestamp: 0,
+            sequence_number: i + 1,
+            hash: vec![0u8; 32],
+            kind: RecordKind::Value,
+        })
+        .collect();
+
+    // Test 1: Every codec round-trips, and compressed tables are smaller
+    let mut sizes = Vec::new();
+    for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+        let path = temp_dir.path().join(format!("{:?}.sst", compression)).to_string_lossy().to_string();
+        SSTable::write(&path, &records, &SSTableOptions { compression, ..Default::default() }).unwrap();
+        sizes.push(std::fs::metadata(&path).unwrap().len());
+
+        let sstable = SSTable::open(&path).unwrap();
+        let record = sstable.get(b"order_0042").unwrap().unwrap();
+        assert_eq!(record.value, records[42].value);
+        assert_eq!(sstable.records().unwrap().len(), 500);
+    }
+    assert!(sizes[1] < sizes[0] / 2);
+    assert!(sizes[2] < sizes[1]);
+
+    // Test 2: Compressed WAL frames and SSTables survive reopening with another codec
+    let data_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: data_dir.path().to_string_lossy().to_string(),
+        compression: Compression::Zstd,
+        ..Default::default()
+    };
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for record in records.iter().take(100) {
+            db.put(&record.key, &record.value).unwrap();
+        }
+        db.force_flush_memtable().unwrap();
+        db.put(b"order_9999", &records[0].value).unwrap();
+    }
+
+    let db = BlockDB::new(BlockDBConfig { compression: Compression::Lz4, ..config }).unwrap();
+    assert_eq!(db.get(b"order_0007").unwrap(), Some(records[7].value.clone()));
+    assert_eq!(db.get(b"order_9999").unwrap(), Some(records[0].value.clone()));
+    assert!(db.verify_integrity().unwrap());
+}
+
+#[test]
+fn test_encryption_at_rest() {
+    use blockdb::BlockDB;
+    use blockdb::storage::MasterKeySource;
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("db").to_string_lossy().to_string();
+    let key_path = |name: &str, byte: u8| {
+        let path = temp_dir.path().join(name).to_string_lossy().to_string();
+        std::fs::write(&path, format!("{}\n", format!("{:02x}", byte).repeat(32))).unwrap();
+        MasterKeySource::KeyFile(path)
+    };
+    let old_key = key_path("old.key", 0x11);
+    let new_key = key_path("new.key", 0x22);
+    let config = BlockDBConfig {
+        data_dir: data_dir.clone(),
+        blockchain_batch_size: 1,
+        master_key: Some(old_key.clone()),
+        blob_threshold: Some(128),
+        crypto_shredding: true,
+        ..Default::default()
+    };
+    let contains = |text: &[u8]| {
+        std::fs::read_dir(&data_dir).unwrap().any(|entry| {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            data.windows(text.len()).any(|window| window == text)
+        })
+    };
+    let contains_plaintext = || contains(b"confidential") || contains(b"patient_");
+
+    let scan = b"confidential imaging ".repeat(8);
+
+    // Test 1: SSTables, WAL segments, blob files and the chain hold no plaintext
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        db.put(b"patient_1", b"confidential diagnosis").unwrap();
+        db.put(b"patient_scan", &scan).unwrap();
+        db.force_flush_memtable().unwrap();
+        db.put(b"patient_2", b"confidential prescription").unwrap();
+        assert!(db.verify_integrity().unwrap());
+    }
+    assert!(!contains_plaintext());
+
+    // Test 2: Opening requires the right master key
+    assert!(BlockDB::new(BlockDBConfig { master_key: None, ..config.clone() }).is_err());
+    assert!(BlockDB::new(BlockDBConfig { master_key: Some(new_key.clone()), ..config.clone() }).is_err());
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        assert_eq!(db.get(b"patient_1").unwrap(), Some(b"confidential diagnosis".to_vec()));
+        assert_eq!(db.get(b"patient_2").unwrap(), Some(b"confidential prescription".to_vec()));
+    }
+
+    // Test 3: Rotation re-encrypts everything under the new master key
+    BlockDB::rotate_encryption_key(&config, &new_key).unwrap();
+    assert!(!contains_plaintext());
+    assert!(BlockDB::new(config.clone()).is_err());
+
+    let db = BlockDB::new(BlockDBConfig { master_key: Some(new_key), ..config }).unwrap();
+    assert_eq!(db.get(b"patient_1").unwrap(), Some(b"confidential diagnosis".to_vec()));
+    assert_eq!(db.get(b"patient_2").unwrap(), Some(b"confidential prescription".to_vec()));
+    assert_eq!(db.get(b"patient_scan").unwrap(), Some(scan));
+    db.put(b"patient_3", b"confidential referral").unwrap();
+    assert!(db.verify_integrity().unwrap());
+
+    // Test 4: Per-key data keys are resealed with everything else and can
+    // still be destroyed
+    db.erase(b"patient_1").unwrap();
+    assert_eq!(db.get(b"patient_1").unwrap(), None);
+    assert_eq!(db.get(b"patient_3").unwrap(), Some(b"confidential referral".to_vec()));
+    assert!(!contains(b"patient_"));
+}
+
+#[test]
+fn test_block_cache() {
+    use blockdb::BlockDB;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        sstable_block_size: 512,
+        block_cache_size: 4 * 1024,
+        ..Default::default()
+    };
+    let db = BlockDB::new(config).unwrap();
+    for i in 0..200 {
+        db.put(format!("key_{:04}", i).as_bytes(), format!("value_{}", i).as_bytes()).unwrap();
+    }
+    db.force_flush_memtable().unwrap();
+
+    // Test 1: The first read of a block misses, later ones hit
+    let before = db.cache_stats();
+    assert_eq!(db.get(b"key_0042").unwrap(), Some(b"value_42".to_vec()));
+    assert_eq!(db.get(b"key_0042").unwrap(), Some(b"value_42".to_vec()));
+    let after = db.cache_stats();
+    assert_eq!(after.misses - before.misses, 1);
+    assert_eq!(after.hits - before.hits, 1);
+
+    // Test 2: Concurrent readers share the tables, and the cache stays
+    // within its capacity
+    std::thread::scope(|scope| {
+        for thread in 0..4 {
+            let db = &db;
+            scope.spawn(move || {
+                for i in (thread..200).step_by(4) {
+                    let value = db.get(format!("key_{:04}", i).as_bytes()).unwrap();
+                    assert_eq!(value, Some(format!("value_{}", i).into_bytes()));
+                }
+            });
+        }
+    });
+    let stats = db.cache_stats();
+    assert!(stats.size <= stats.capacity);
+    assert!(stats.hits + stats.misses >= 202);
+}
+
+#[test]
+fn test_blob_separation() {
+    use blockdb::BlockDB;
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_string_lossy().to_string();
+    // Two 8KB values fit in each blob file
+    let config = BlockDBConfig {
+        data_dir: data_dir.clone(),
+        compaction_threshold: 2,
+        blob_threshold: Some(1024),
+        blob_file_size: 20 * 1024,
+        ..Default::default()
+    };
+    let value = |i: usize| vec![i as u8; 8 * 1024];
+    let blob_file = |number: u64| temp_dir.path().join(format!("blob_{:06}.blob", number));
+
+    let files_size = |prefix: &str| -> u64 {
+        std::fs::read_dir(&data_dir).unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    // Test 1: Large values are written once to blob files; the WAL and
+    // SSTables hold pointers and the chain their digests
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for i in 0..6 {
+            db.put(format!("key_{}", i).as_bytes(), &value(i)).unwrap();
+        }
+        db.put(b"small", b"inline").unwrap();
+
+        assert!(files_size("wal_") < 8 * 1024);
+        assert!(files_size("chain_") < 8 * 1024);
+        assert!(blob_file(1).exists() && blob_file(2).exists() && blob_file(3).exists());
+
+        assert_eq!(db.get(b"key_3").unwrap(), Some(value(3)));
+        db.force_flush_memtable().unwrap();
+        assert_eq!(db.get(b"key_3").unwrap(), Some(value(3)));
+        assert_eq!(db.get(b"small").unwrap(), Some(b"inline".to_vec()));
+        let records: Vec<_> = db.scan_prefix(b"key_").unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[5].value, value(5));
+        assert!(db.verify_integrity().unwrap());
+    }
+
+    // Test 2: Compaction counts the values of deleted keys as garbage and
+    // moves the live value out of a half-dead file, but keeps dead files
+    // while an iterator opened before then can still read them
+    let db = BlockDB::new(config.clone()).unwrap();
+    assert_eq!(db.get(b"key_0").unwrap(), Some(value(0)));
+    // The snapshot is gone before compaction runs; only the iterator remains
+    let mut iter = db.snapshot().scan_prefix(b"key_").unwrap();
+    for key in [b"key_0", b"key_2", b"key_3"] {
+        db.delete(key).unwrap();
+    }
+    db.force_flush_memtable().unwrap();
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while db.write_stall_stats().level0_files > 0 && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(db.write_stall_stats().level0_files, 0);
+    assert!(blob_file(1).exists() && blob_file(2).exists());
+    for i in 0..6 {
+        assert_eq!(iter.next().unwrap().unwrap().value, value(i));
+    }
+    assert!(iter.next().is_none());
+    drop(iter);
+
+    // Test 3: The next compaction deletes them once the iterator is gone
+    for key in [b"more_1", b"more_2"] {
+        db.put(key, b"inline").unwrap();
+        db.force_flush_memtable().unwrap();
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while (blob_file(1).exists() || blob_file(2).exists()) && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert!(!blob_file(1).exists());
+    assert!(!blob_file(2).exists());
+    assert_eq!(db.get(b"key_0").unwrap(), None);
+    assert_eq!(db.get(b"key_1").unwrap(), Some(value(1)));
+    drop(db);
+
+    // Test 4: Relocated values survive a reopen
+    let db = BlockDB::new(config.clone()).unwrap();
+    for i in [1, 4, 5] {
+        assert_eq!(db.get(format!("key_{}", i).as_bytes()).unwrap(), Some(value(i)));
+    }
+    assert_eq!(db.get(b"key_2").unwrap(), None);
+
+    // Test 5: The chain does not depend on the collected files
+    let report = db.verify_deep().unwrap();
+    assert!(report.is_intact(), "{:?}", report.tamper);
+    assert_eq!(report.chain_records_checked, 12);
+    assert!(blockdb::storage::verify_proof(&db.prove(b"key_1").unwrap().unwrap()));
+    drop(db);
+
+    // Test 6: Values separated before a batch fails are garbage, so their
+    // file is still deleted
+    let failed_dir = TempDir::new().unwrap();
+    let db = BlockDB::new(BlockDBConfig {
+        data_dir: failed_dir.path().to_string_lossy().to_string(),
+        ..config
+    }).unwrap();
+    let failed_file = |number: u64| failed_dir.path().join(format!("blob_{:06}.blob", number));
+    // The third value needs a second file, which cannot be created
+    std::fs::create_dir(failed_file(2)).unwrap();
+    let mut batch = blockdb::WriteBatch::new();
+    batch.put(b"failed_0", &value(0)).put(b"failed_1", &value(1)).put(b"failed_2", &value(2));
+    assert!(db.write_batch(batch).is_err());
+    assert!(failed_file(1).exists());
+    assert_eq!(db.get(b"failed_0").unwrap(), None);
+
+    std::fs::remove_dir(failed_file(2)).unwrap();
+    for key in [b"small_1", b"small_2"] {
+        db.put(key, b"inline").unwrap();
+        db.force_flush_memtable().unwrap();
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while failed_file(1).exists() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert!(!failed_file(1).exists());
+}
+
+#[test]
+fn test_background_flush_and_write_stalls() {
+    use blockdb::BlockDB;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        memtable_size_limit: 1024,
+        compaction_threshold: 100,
+        level0_slowdown_trigger: 3,
+        write_stall_delay_ms: 5,
+        ..Default::default()
+    };
+    let sstable_count = || {
+        std::fs::read_dir(temp_dir.path()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".sst"))
+            .count()
+    };
+
+    // Test 1: Full memtables are flushed by the background worker
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for i in 0..40 {
+            db.put(format!("key_{:03}", i).as_bytes(), &[b'x'; 100]).unwrap();
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while db.write_stall_stats().immutable_memtables > 0 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(sstable_count() >= 2);
+        for i in 0..40 {
+            assert_eq!(db.get(format!("key_{:03}", i).as_bytes()).unwrap(), Some(vec![b'x'; 100]));
+        }
+    }
+
+    // Test 2: Once level 0 passes its limit, writes are delayed but still succeed
+    let db = BlockDB::new(config).unwrap();
+    for i in 0..40 {
+        assert_eq!(db.get(format!("key_{:03}", i).as_bytes()).unwrap(), Some(vec![b'x'; 100]));
+    }
+    while db.write_stall_stats().level0_files < 3 {
+        db.put(format!("more_{}", sstable_count()).as_bytes(), b"value").unwrap();
+        db.force_flush_memtable().unwrap();
+    }
+    let stats = db.write_stall_stats();
+    assert!(stats.stalled);
+
+    let start = std::time::Instant::now();
+    db.put(b"delayed", b"value").unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(5));
+    assert_eq!(db.get(b"delayed").unwrap(), Some(b"value".to_vec()));
+    let stats = db.write_stall_stats();
+    assert_eq!(stats.delayed_writes, 1);
+    assert!(stats.delay_ms >= 5);
+}
+
+#[test]
+fn test_block_log() {
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::blockchain::{Block, BlockChain, SealingPolicy};
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_string_lossy().to_string();
+    let record = |i: u64| Record {
+        key: format!("key_{}", i).into_bytes(),
+        value: vec![b'v'; 64],
+        timestamp: i,
+        sequence_number: i,
+        hash: vec![i as u8; 32],
+        kind: RecordKind::Value,
+    };
+    let segment_path = temp_dir.path().join("chain_000001.log");
+
+    // Test 1: Blocks are appended to the log and paged in after a reopen
+    {
+        let mut chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+        for i in 1..=10 {
+            chain.add_records(vec![record(i)]).unwrap();
+            chain.force_create_block().unwrap();
+        }
+        assert_eq!(chain.get_chain_length(), 11);
+    }
+    let chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+    assert_eq!(chain.get_chain_length(), 11);
+    assert!(chain.verify_chain().unwrap());
+    assert_eq!(chain.get_block(4).unwrap().unwrap().records[0].key, b"key_4");
+    assert_eq!(chain.get_latest_block().unwrap().index, 10);
+    assert!(chain.get_block(11).unwrap().is_none());
+    drop(chain);
+
+    // Test 2: A torn frame at the end of the log is dropped on open
+    let valid_len = std::fs::metadata(&segment_path).unwrap().len();
+    let mut segment = std::fs::OpenOptions::new().append(true).open(&segment_path).unwrap();
+    segment.write_all(&[0, 0, 1, 0, 0xde, 0xad]).unwrap();
+    drop(segment);
+    let mut chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+    assert_eq!(std::fs::metadata(&segment_path).unwrap().len(), valid_len);
+    assert_eq!(chain.get_chain_length(), 11);
+    chain.add_records(vec![record(11)]).unwrap();
+    chain.force_create_block().unwrap();
+    assert!(chain.verify_chain().unwrap());
+    drop(chain);
+
+    // Test 3: A lost offset index is rebuilt from the segments
+    std::fs::remove_file(temp_dir.path().join("chain.idx")).unwrap();
+    let chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+    assert_eq!(chain.get_chain_length(), 12);
+    assert_eq!(chain.get_block(11).unwrap().unwrap().records[0].key, b"key_11");
+    assert!(chain.verify_chain().unwrap());
+    drop(chain);
+
+    // Test 4: A chain saved as a single blockchain.dat is migrated to the log
+    let legacy_dir = TempDir::new().unwrap();
+    let genesis = Block::new(0, vec![0u8; 32], Vec::new());
+    let block = Block::new(1, genesis.hash.clone(), vec![record(1), record(2)]);
+    let mut legacy = b"BDBC".to_vec();
+    legacy.extend_from_slice(&2u32.to_be_bytes());
+    legacy.extend_from_slice(&bincode::serialize(&vec![genesis, block]).unwrap());
+    std::fs::write(legacy_dir.path().join("blockchain.dat"), legacy).unwrap();
+
+    let chain = BlockChain::new(&legacy_dir.path().to_string_lossy(), None, SealingPolicy::default()).unwrap();
+    assert!(!legacy_dir.path().join("blockchain.dat").exists());
+    assert_eq!(chain.get_chain_length(), 2);
+    assert_eq!(chain.get_block(1).unwrap().unwrap().records.len(), 2);
+    assert!(chain.verify_chain().unwrap());
+}
+
+#[test]
+fn test_unsealed_records_recovered_from_wal() {
+    use blockdb::BlockDB;
+    use blockdb::storage::blockchain::{BlockChain, SealingPolicy};
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        blockchain_max_block_age_ms: 0,
+        ..Default::default()
+    };
+    let sealed_keys = || {
+        let chain = BlockChain::new(&config.data_dir, None, SealingPolicy::default()).unwrap();
+        let keys: Vec<Vec<u8>> = chain.blocks()
+            .flat_map(|block| block.unwrap().records)
+            .map(|record| record.key)
+            .collect();
+        (chain.sealed_sequence(), keys)
+    };
+
+    // Test 1: Records still queued for a block at shutdown are sealed on open
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for i in 0..5 {
+            db.put(format!("key_{}", i).as_bytes(), b"value").unwrap();
+        }
+    }
+    assert_eq!(sealed_keys(), (0, Vec::new()));
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        assert!(db.verify_integrity().unwrap());
+        assert_eq!(db.get(b"key_4").unwrap(), Some(b"value".to_vec()));
+    }
+    let (sealed_sequence, keys) = sealed_keys();
+    assert_eq!(sealed_sequence, 5);
+    assert_eq!(keys, (0..5).map(|i| format!("key_{}", i).into_bytes()).collect::<Vec<_>>());
+
+    // Test 2: Reopening again seals nothing twice
+    drop(BlockDB::new(config.clone()).unwrap());
+    assert_eq!(sealed_keys().1.len(), 5);
+
+    // Test 3: A flush seals queued records before it releases their WAL segments
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        db.put(b"flushed", b"value").unwrap();
+        db.force_flush_memtable().unwrap();
+    }
+    let (sealed_sequence, keys) = sealed_keys();
+    assert_eq!(sealed_sequence, 6);
+    assert_eq!(keys.last().unwrap(), b"flushed");
+
+    // Test 4: Sequence numbers already sealed are not reused when the WAL
+    // tail holding them is lost
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        blockchain_batch_size: 2,
+        blockchain_max_block_age_ms: 0,
+        wal_durability: blockdb::storage::wal::WalDurability::Flush,
+        ..Default::default()
+    };
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        db.put(b"sealed_1", b"value").unwrap();
+        db.put(b"sealed_2", b"value").unwrap();
+    }
+    for entry in std::fs::read_dir(temp_dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.file_name().unwrap().to_string_lossy().starts_with("wal_") {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    let db = BlockDB::new(config).unwrap();
+    db.put(b"after_loss_1", b"value").unwrap();
+    db.put(b"after_loss_2", b"value").unwrap();
+    let report = db.verify_deep().unwrap();
+    assert!(report.is_intact(), "{:?}", report.tamper);
+    assert_eq!(db.prove(b"after_loss_2").unwrap().unwrap().record.sequence_number, 4);
+}
+
+#[test]
+fn test_block_sealing_policy() {
+    use blockdb::BlockDB;
+    use blockdb::storage::blockchain::{BlockChain, SealingPolicy};
+
+    let sealed_blocks = |config: &BlockDBConfig| {
+        let chain = BlockChain::new(&config.data_dir, None, SealingPolicy::default()).unwrap();
+        chain.blocks()
+            .map(|block| block.unwrap().records.len())
+            .collect::<Vec<_>>()
+    };
+
+    // Test 1: blockchain_batch_size is honored
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        blockchain_batch_size: 3,
+        blockchain_max_block_age_ms: 0,
+        ..Default::default()
+    };
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for i in 0..7 {
+            db.put(format!("key_{}", i).as_bytes(), b"value").unwrap();
+        }
+    }
+    assert_eq!(sealed_blocks(&config), vec![0, 3, 3]);
+
+    // Test 2: A block is sealed once its records reach the byte limit
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        blockchain_max_block_bytes: 1024,
+        blockchain_max_block_age_ms: 0,
+        ..Default::default()
+    };
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for i in 0..5 {
+            db.put(format!("key_{}", i).as_bytes(), &[b'x'; 300]).unwrap();
+        }
+    }
+    assert_eq!(sealed_blocks(&config), vec![0, 4]);
+
+    // Test 3: The timer seals a partial block once it reaches its maximum age
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        blockchain_max_block_age_ms: 50,
+        ..Default::default()
+    };
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        db.put(b"quiet", b"value").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(db.verify_integrity().unwrap());
+    }
+    assert_eq!(sealed_blocks(&config), vec![0, 1]);
+}
+
+#[test]
+fn test_deep_verification() {
+    use blockdb::BlockDB;
+    use blockdb::storage::TamperKind;
+    use blockdb::storage::blockchain::Block;
+    use blockdb::storage::sstable::{SSTable, SSTableOptions};
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        blockchain_batch_size: 2,
+        blockchain_max_block_age_ms: 0,
+        blob_threshold: Some(128),
+        ..Default::default()
+    };
+    let sstable_path = || {
+        std::fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|extension| extension == "sst"))
+            .unwrap()
+    };
+    let rewrite_sstable = |tamper: &dyn Fn(&mut blockdb::storage::Record)| {
+        let path = sstable_path().to_string_lossy().to_string();
+        let mut records = SSTable::open(&path).unwrap().records().unwrap();
+        let record = records.iter_mut().find(|record| record.key == b"key_1").unwrap();
+        tamper(record);
+        SSTable::write(&path, &records, &SSTableOptions::default()).unwrap();
+    };
+
+    // Test 1: Untouched data verifies, including separated values and
+    // records not yet sealed
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for i in 0..4 {
+            db.put(format!("key_{}", i).as_bytes(), b"value").unwrap();
+        }
+        db.put(b"large", &[b'x'; 512]).unwrap();
+        db.delete(b"key_3").unwrap();
+        db.force_flush_memtable().unwrap();
+        db.put(b"pending", b"value").unwrap();
+
+        let report = db.verify_deep().unwrap();
+        assert!(report.is_intact(), "{:?}", report.tamper);
+        assert_eq!(report.chain_records_checked, 6);
+        assert_eq!(report.stored_records_checked, 6);
+        assert_eq!(report.pending_records, 1);
+    }
+
+    // Test 2: A value changed in an SSTable is caught, naming the file and
+    // the block the record was sealed in
+    let original = SSTable::open(&sstable_path().to_string_lossy()).unwrap().records().unwrap();
+    rewrite_sstable(&|record| record.value = b"forged".to_vec());
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        assert!(db.verify_integrity().unwrap());
+        let tamper = db.verify_deep().unwrap().tamper.unwrap();
+        assert_eq!(tamper.kind, TamperKind::RecordHash);
+        assert_eq!(tamper.key, Some(b"key_1".to_vec()));
+        assert_eq!(tamper.block, Some(1));
+        assert_eq!(tamper.file.as_deref(), sstable_path().file_name().unwrap().to_str());
+    }
+
+    // Test 3: Rehashing the forged record does not help, as the chain
+    // never sealed it
+    rewrite_sstable(&|record| record.hash = record.compute_hash());
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        let tamper = db.verify_deep().unwrap().tamper.unwrap();
+        assert_eq!(tamper.kind, TamperKind::NotInChain);
+        assert_eq!(tamper.key, Some(b"key_1".to_vec()));
+    }
+    SSTable::write(&sstable_path().to_string_lossy(), &original, &SSTableOptions::default()).unwrap();
+
+    // Test 4: A separated value whose blob file is gone fails verification
+    let blob_path = temp_dir.path().join("blob_000001.blob");
+    let blob = std::fs::read(&blob_path).unwrap();
+    std::fs::remove_file(&blob_path).unwrap();
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        let tamper = db.verify_deep().unwrap().tamper.unwrap();
+        assert_eq!(tamper.kind, TamperKind::UnreadableBlob);
+        assert_eq!(tamper.key, Some(b"large".to_vec()));
+    }
+    std::fs::write(&blob_path, blob).unwrap();
+
+    // Test 5: A value changed inside a sealed block is caught, though the
+    // block hash still matches
+    let segment_path = temp_dir.path().join("chain_000001.log");
+    let segment = std::fs::read(&segment_path).unwrap();
+    let mut rewritten = segment[..12].to_vec();
+    let mut offset = 12;
+    while offset < segment.len() {
+        let size = u32::from_be_bytes(segment[offset..offset + 4].try_into().unwrap()) as usize;
+        let mut block: Block = bincode::deserialize(&segment[offset + 8..offset + 8 + size]).unwrap();
+        if block.index == 2 {
+            block.records[0].value = b"forged".to_vec();
+        }
+        let payload = bincode::serialize(&block).unwrap();
+        rewritten.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        rewritten.extend_from_slice(&crc32c::crc32c(&payload).to_be_bytes());
+        rewritten.extend_from_slice(&payload);
+        offset += 8 + size;
+    }
+    std::fs::write(&segment_path, rewritten).unwrap();
+    std::fs::remove_file(temp_dir.path().join("chain.idx")).unwrap();
+
+    let db = BlockDB::new(config).unwrap();
+    assert!(db.verify_integrity().unwrap());
+    let tamper = db.verify_deep().unwrap().tamper.unwrap();
+    assert_eq!(tamper.kind, TamperKind::RecordHash);
+    assert_eq!(tamper.block, Some(2));
+    assert_eq!(tamper.key, Some(b"key_2".to_vec()));
+    assert_eq!(tamper.file.as_deref(), Some("chain_000001.log"));
+}
+
+#[test]
+fn test_inclusion_proofs() {
+    use blockdb::BlockDB;
+    use blockdb::storage::{verify_proof, InclusionProof, RecordKind};
+    use blockdb::storage::blob::ValueDigest;
+    use blockdb::storage::proof::Side;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config = BlockDBConfig {
+        data_dir: temp_dir.path().to_string_lossy().to_string(),
+        blockchain_batch_size: 3,
+        blockchain_max_block_age_ms: 0,
+        blob_threshold: Some(128),
+        ..Default::default()
+    };
+    let keys: Vec<Vec<u8>> = (0..7).map(|i| format!("key_{}", i).into_bytes()).collect();
+
+    // Test 1: Every key's newest record, including tombstones, separated
+    // values and records not yet sealed, proves against the chain
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for key in &keys[..6] {
+            db.put(key, b"value").unwrap();
+        }
+        db.put(&keys[6], &[b'x'; 512]).unwrap();
+        db.delete(&keys[0]).unwrap();
+
+        for key in &keys {
+            let proof = db.prove(key).unwrap().unwrap();
+            assert!(verify_proof(&proof));
+            assert_eq!(&proof.record.key, key);
+        }
+        // A separated value is proved by the digest the chain sealed
+        let record = db.prove(&keys[6]).unwrap().unwrap().record;
+        assert_eq!(record.kind, RecordKind::BlobDigest);
+        assert_eq!(record.value, ValueDigest::of(&[b'x'; 512], RecordKind::Value).to_bytes());
+        assert!(db.prove(&keys[0]).unwrap().unwrap().record.is_tombstone());
+        assert!(db.prove(b"missing").unwrap().is_none());
+
+        // The last of three records is paired with itself
+        let proof = db.prove(&keys[5]).unwrap().unwrap();
+        assert_eq!(proof.block.index, 2);
+        let sides: Vec<Side> = proof.path.iter().map(|step| step.side).collect();
+        assert_eq!(sides, vec![Side::Right, Side::Left]);
+        assert_eq!(proof.path[0].sibling, proof.record.hash);
+    }
+
+    // Test 2: Proofs survive serialization, and any change to them fails
+    let db = BlockDB::new(config.clone()).unwrap();
+    let proof = db.prove(&keys[4]).unwrap().unwrap();
+    let proof: InclusionProof = serde_json::from_str(&serde_json::to_string(&proof).unwrap()).unwrap();
+    assert!(verify_proof(&proof));
+
+    let mut forged = proof.clone();
+    forged.record.value = b"forged".to_vec();
+    assert!(!verify_proof(&forged));
+    let mut forged = proof.clone();
+    forged.path[0].side = Side::Right;
+    assert!(!verify_proof(&forged));
+    let mut forged = proof.clone();
+    forged.block.record_hashes.pop();
+    assert!(!verify_proof(&forged));
+    let mut forged = proof.clone();
+    forged.head.index = forged.block.index - 1;
+    assert!(!verify_proof(&forged));
+    drop(db);
+
+    // Test 3: A torn or lost record index is rebuilt from the chain
+    let index_path = temp_dir.path().join("chain_records.idx");
+    let index_len = std::fs::metadata(&index_path).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&index_path).unwrap().set_len(index_len - 5).unwrap();
+    {
+        let db = BlockDB::new(config.clone()).unwrap();
+        for key in &keys {
+            assert!(verify_proof(&db.prove(key).unwrap().unwrap()));
+        }
+    }
+    std::fs::remove_file(&index_path).unwrap();
+    let db = BlockDB::new(config).unwrap();
+    for key in &keys {
+        assert!(verify_proof(&db.prove(key).unwrap().unwrap()));
+    }
+    assert_eq!(std::fs::metadata(&index_path).unwrap().len(), index_len);
+}
+
+#[test]
+fn test_record_index_runs() {
+    use blockdb::storage::{Record, RecordKind};
+    use blockdb::storage::blockchain::{BlockChain, SealingPolicy};
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_string_lossy().to_string();
+    // Distinct hashes in no particular order
+    let hash = |i: u64| {
+        let mut hash = vec![0u8; 32];
+        hash[..8].copy_from_slice(&i.wrapping_mul(0x9E37_79B9_7F4A_7C15).to_be_bytes());
+        hash
+    };
+    let record = |i: u64| Record {
+        key: format!("key_{}", i).into_bytes(),
+        value: Vec::new(),
+        timestamp: i,
+        sequence_number: i,
+        hash: hash(i),
+        kind: RecordKind::Value,
+    };
+    let runs = || -> Vec<String> {
+        let mut runs: Vec<String> = std::fs::read_dir(&data_dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|file_name| file_name.ends_with(".run"))
+            .collect();
+        runs.sort();
+        runs
+    };
+    let check = |chain: &BlockChain| {
+        for i in (0..60_000).step_by(997) {
+            assert_eq!(chain.find_record(&hash(i)).unwrap(), Some(i / 1000 + 1));
+        }
+        assert_eq!(chain.find_record(&hash(60_000)).unwrap(), None);
+    };
+
+    // Test 1: Entries are written out as sorted runs, and equal runs merged
+    {
+        let mut chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+        for block in 0..60 {
+            chain.add_records((block * 1000..(block + 1) * 1000).map(record).collect()).unwrap();
+            chain.force_create_block().unwrap();
+        }
+        assert_eq!(runs().len(), 2);
+        check(&chain);
+        let proof = chain.get_record_proof(&hash(12_345)).unwrap().unwrap();
+        assert_eq!(proof.0.index, 13);
+    }
+
+    // Test 2: Runs survive a reopen, and a lost log is rebuilt after them
+    let chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+    check(&chain);
+    drop(chain);
+    std::fs::remove_file(temp_dir.path().join("chain_records.idx")).unwrap();
+    let chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+    check(&chain);
+    drop(chain);
+
+    // Test 3: Inputs left by an interrupted merge are dropped
+    let merged = runs()[0].clone();
+    std::fs::write(temp_dir.path().join("chain_records_0000000000_0000000005.run"), b"").unwrap();
+    let chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+    check(&chain);
+    assert_eq!(runs()[0], merged);
+    assert_eq!(runs().len(), 2);
+    drop(chain);
+
+    // Test 4: Blocks of a lost run are indexed again
+    std::fs::remove_file(temp_dir.path().join(runs().pop().unwrap())).unwrap();
+    let chain = BlockChain::new(&data_dir, None, SealingPolicy::default()).unwrap();
+    check(&chain);
+}